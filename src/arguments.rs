@@ -2,6 +2,8 @@ use std::path::PathBuf;
 
 use argh::FromArgs;
 
+use crate::gerber_job::Process;
+
 #[derive(FromArgs, PartialEq, Debug)]
 /// A tool to generate GCode for machines that manufacture Printed Circuit Boards.
 pub struct Arguments {
@@ -13,6 +15,10 @@ pub struct Arguments {
 #[argh(subcommand)]
 pub enum CommandEnum {
     Build(BuildCommand),
+    Check(CheckCommand),
+    Estimate(EstimateCommand),
+    Layers(LayersCommand),
+    New(NewCommand),
 }
 
 #[derive(FromArgs, PartialEq, Debug)]
@@ -30,4 +36,56 @@ pub struct BuildCommand {
     #[argh(switch)]
     /// enable debug output files
     pub debug: bool,
+
+    #[argh(switch)]
+    /// render an SVG and PNG preview of the toolpaths of each gcode file
+    pub preview: bool,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Check a forge file for problems without generating any output.
+#[argh(subcommand, name = "check")]
+pub struct CheckCommand {
+    #[argh(option, default = "PathBuf::from(\"forge.yaml\")")]
+    /// path to the project forge file.
+    pub forge_file_path: PathBuf,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Estimate how long each gcode file of a project takes to run, without writing any output.
+#[argh(subcommand, name = "estimate")]
+pub struct EstimateCommand {
+    #[argh(option, default = "PathBuf::from(\"forge.yaml\")")]
+    /// path to the project forge file.
+    pub forge_file_path: PathBuf,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// List which layer of the board each gerber and drill file holds.
+#[argh(subcommand, name = "layers")]
+pub struct LayersCommand {
+    #[argh(option, default = "PathBuf::from(\"forge.yaml\")")]
+    /// path to the project forge file, whose layer directory is listed.
+    pub forge_file_path: PathBuf,
+
+    #[argh(option)]
+    /// directory to list instead of the layer directory of the forge file.
+    pub directory: Option<PathBuf>,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Start a new forge file from the gerber job file of a board.
+#[argh(subcommand, name = "new")]
+pub struct NewCommand {
+    #[argh(option)]
+    /// path to the gerber job file (.gbrjob) written by the CAD tool.
+    pub gerber_job: PathBuf,
+
+    #[argh(option, default = "Process::LaserEtch")]
+    /// the fabrication process to set up, either laser_etch or mill. Defaults to laser_etch.
+    pub process: Process,
+
+    #[argh(option, default = "PathBuf::from(\"forge.yaml\")")]
+    /// path to write the forge file to.
+    pub forge_file_path: PathBuf,
 }
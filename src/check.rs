@@ -0,0 +1,278 @@
+//! Validates a forge file and everything it references without generating any output.
+
+use std::{fs, path::Path};
+
+use anyhow::{anyhow, bail, Context, Result};
+use itertools::Itertools;
+
+use crate::{
+    arguments::CheckCommand,
+    config::{machine::ToolConfig, Config},
+    drill_file::{self, DrillFile},
+    forge_file::{CutBoardFile, ForgeFile, GerberSource, Stage, StageLines},
+    gcode_generation::ToolSelection,
+    gerber_file::{self, GerberFile},
+    get_config_directory, get_tool_selection,
+    height_map::HeightMap,
+    layers::{LayerKind, ProjectLayers},
+    select_dialect, select_machine, MachineSelection, ProfileKind,
+};
+
+struct Problem {
+    line: Option<usize>,
+    error: anyhow::Error,
+}
+
+pub fn check(check_configuration: CheckCommand, global_config: Config) -> Result<()> {
+    let forge_file_path = &check_configuration.forge_file_path;
+    log::info!("Check Forge File: {:?}", forge_file_path);
+
+    let source = fs::read_to_string(forge_file_path).context("Failed to read forge file.")?;
+    let forge_file = ForgeFile::load_from_str(&source)?;
+    let stage_lines = StageLines::locate(&source);
+
+    let forge_file_directory = forge_file_path
+        .parent()
+        .context("Could not get parent directory of forge file.")?;
+    let config_directory = get_config_directory()?;
+
+    let mut problems = Vec::new();
+    let mut layers = ProjectLayers::new(&forge_file, forge_file_directory);
+
+    for (gcode_file_path, stages) in forge_file
+        .gcode_files
+        .iter()
+        .sorted_by(|a, b| Ord::cmp(&a.0, &b.0))
+    {
+        let mut dialect = None;
+
+        for (stage_index, stage) in stages.iter().enumerate() {
+            let key_line = |key| stage_lines.key(gcode_file_path, stage_index, key);
+            let mut report = |line, error: anyhow::Error| {
+                problems.push(Problem {
+                    line,
+                    error: error.context(format!("Stage {} of {:?}", stage_index, gcode_file_path)),
+                })
+            };
+
+            // Files are given with the key they were named by, so that problems point at the right line.
+            let gerber_source = |file: &GerberSource| match file {
+                GerberSource::File { .. } => "gerber_file",
+                GerberSource::Layer { .. } => "layer",
+            };
+
+            let (machine_config, profile_kind, file, height_map) = match stage {
+                Stage::EngraveMask {
+                    machine_config,
+                    file,
+                    backside: _,
+                    invert: _,
+                    select: _,
+                    exclude: _,
+                    height_map,
+                } => (
+                    machine_config,
+                    ProfileKind::Engraving,
+                    Some((
+                        gerber_source(file),
+                        layers
+                            .gerber_file(file)
+                            .map(|path| (LayerKind::Gerber, path)),
+                    )),
+                    height_map.as_ref(),
+                ),
+                Stage::IsolationMill {
+                    machine_config,
+                    file,
+                    backside: _,
+                    select: _,
+                    exclude: _,
+                    height_map,
+                } => (
+                    machine_config,
+                    ProfileKind::Engraving,
+                    Some((
+                        gerber_source(file),
+                        layers
+                            .gerber_file(file)
+                            .map(|path| (LayerKind::Gerber, path)),
+                    )),
+                    height_map.as_ref(),
+                ),
+                Stage::CutBoard {
+                    machine_config,
+                    file,
+                    backside: _,
+                    height_map,
+                } => (
+                    machine_config,
+                    ProfileKind::Cutting,
+                    Some((
+                        match file {
+                            CutBoardFile::Gerber { .. } => "gerber_file",
+                            CutBoardFile::Drill { .. } => "drill_file",
+                            CutBoardFile::Layer { .. } => "layer",
+                        },
+                        layers.cut_board_file(file),
+                    )),
+                    height_map.as_ref(),
+                ),
+                Stage::ProbeHeightMap {
+                    machine_config,
+                    file,
+                    grid_spacing: _,
+                    probe_log: _,
+                    backside: _,
+                } => (
+                    machine_config,
+                    ProfileKind::Cutting,
+                    Some((
+                        gerber_source(file),
+                        layers
+                            .gerber_file(file)
+                            .map(|path| (LayerKind::Gerber, path)),
+                    )),
+                    None,
+                ),
+                Stage::RegistrationPins { machine_config } => {
+                    if forge_file.registration.is_none() {
+                        report(
+                            key_line("machine_config"),
+                            anyhow!("Registration pin stages need a `registration` section in the forge file."),
+                        );
+                    }
+
+                    (machine_config, ProfileKind::Cutting, None, None)
+                }
+            };
+
+            if let Some(height_map) = height_map {
+                if let Err(error) = HeightMap::load(&forge_file_directory.join(height_map)) {
+                    report(key_line("height_map"), error);
+                }
+            }
+
+            // The referenced file is checked even if the machine can't be, so that all problems are found at once.
+            if let Some((file_key, file)) = file {
+                let load_result = file.and_then(|(kind, file_path)| {
+                    let full_file_path = forge_file_directory.join(file_path);
+
+                    match kind {
+                        LayerKind::Drill => {
+                            drill_file::load(&mut DrillFile::default(), &full_file_path)
+                                .context("Failed to load drill file.")
+                        }
+                        LayerKind::Gerber => {
+                            gerber_file::load(&mut GerberFile::default(), &full_file_path)
+                                .context("Failed to load gerber file.")
+                        }
+                    }
+                });
+                if let Err(error) = load_result {
+                    report(key_line(file_key), error);
+                }
+            }
+
+            let MachineSelection {
+                machine_name,
+                machine_config,
+                job_config,
+                include_file_search_directory: _,
+            } = match select_machine(
+                machine_config.as_ref(),
+                profile_kind,
+                &forge_file,
+                &global_config,
+                forge_file_directory,
+                &config_directory,
+            ) {
+                Ok(selection) => selection,
+                Err(error) => {
+                    report(key_line("machine_config"), error);
+                    continue;
+                }
+            };
+
+            if let Err(error) = select_dialect(&mut dialect, &machine_name, machine_config) {
+                report(key_line("machine_config"), error);
+            }
+
+            if matches!(stage, Stage::ProbeHeightMap { .. }) && machine_config.probe.is_none() {
+                report(
+                    key_line("machine_config"),
+                    anyhow!("Machine {} does not have a probe configured.", machine_name),
+                );
+            }
+
+            let tool_selection = match get_tool_selection(machine_config, &job_config.tool) {
+                Ok(tool_selection) => tool_selection,
+                Err(error) => {
+                    report(
+                        key_line("machine_config"),
+                        error.context("Failed to select the tool of the machine profile."),
+                    );
+                    continue;
+                }
+            };
+
+            match (&job_config.tool_power, &tool_selection) {
+                (ToolConfig::Laser { .. }, ToolSelection::Laser { .. })
+                | (ToolConfig::EndMill { .. }, ToolSelection::Spindle { .. }) => {}
+                (ToolConfig::Laser { .. }, ToolSelection::Spindle { .. }) => report(
+                    key_line("machine_config"),
+                    anyhow!(
+                        "Machine profile is configured for a laser but its tool `{}` is a spindle.",
+                        job_config.tool
+                    ),
+                ),
+                (ToolConfig::EndMill { .. }, ToolSelection::Laser { .. }) => report(
+                    key_line("machine_config"),
+                    anyhow!(
+                        "Machine profile is configured for a spindle but its tool `{}` is a laser.",
+                        job_config.tool
+                    ),
+                ),
+            }
+        }
+    }
+
+    // Other boards on the panel are checked on their own, so they only need to load here.
+    for board in forge_file
+        .panel
+        .iter()
+        .flat_map(|panel| panel.boards.iter())
+    {
+        if let Some(path) = &board.forge_file {
+            if let Err(error) = ForgeFile::load_from_path(&forge_file_directory.join(path)) {
+                problems.push(Problem {
+                    line: None,
+                    error: error.context(format!("Failed to load panel board {:?}.", path)),
+                });
+            }
+        }
+    }
+
+    report_problems(forge_file_path, &problems);
+
+    if !problems.is_empty() {
+        bail!("Found {} problems in forge file.", problems.len());
+    }
+
+    log::info!("No problems found.");
+
+    Ok(())
+}
+
+fn report_problems(forge_file_path: &Path, problems: &[Problem]) {
+    for problem in problems {
+        match problem.line {
+            Some(line) => log::error!(
+                "{}:{}: {:#}",
+                forge_file_path.to_string_lossy(),
+                line,
+                problem.error
+            ),
+            None => log::error!("{}: {:#}", forge_file_path.to_string_lossy(), problem.error),
+        }
+    }
+}
@@ -1,16 +1,21 @@
 use camino::Utf8PathBuf;
 use std::{collections::HashMap, path::PathBuf};
 use uom::si::{
+    acceleration::Acceleration,
     angular_velocity::{revolution_per_second, AngularVelocity},
     length::{millimeter, Length},
     power::{watt, Power},
+    time::Time,
     velocity::{millimeter_per_second, Velocity},
 };
 
 use nalgebra::Vector2;
 use serde::Deserialize;
 
-use crate::parsing::{parse_optional_quantity, parse_quantity};
+use crate::{
+    gcode_dialect::GCodeDialect,
+    parsing::{parse_optional_quantity, parse_quantity},
+};
 
 #[derive(Debug, Deserialize)]
 pub struct Machine {
@@ -28,6 +33,39 @@ pub struct Machine {
 
     /// The safe working area of the machine.
     pub workspace_area: WorkspaceSize,
+
+    /// The flavor of GCode understood by the machine's firmware.
+    #[serde(default)]
+    pub dialect: GCodeDialect,
+
+    /// How quickly the machine can speed up and slow down. Only used to estimate how long jobs take.
+    /// Moves are assumed to happen at full speed if not set.
+    #[serde(
+        default = "Machine::default_acceleration",
+        deserialize_with = "parse_optional_quantity"
+    )]
+    pub acceleration: Option<Acceleration<uom::si::SI<f64>, f64>>,
+
+    /// How the machine probes the height of a board. Required by height map probing stages.
+    #[serde(default)]
+    pub probe: Option<ProbeConfig>,
+}
+
+impl Machine {
+    fn default_acceleration() -> Option<Acceleration<uom::si::SI<f64>, f64>> {
+        None
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ProbeConfig {
+    /// How far below the Z zero the probe searches for the board before giving up.
+    #[serde(deserialize_with = "parse_quantity")]
+    pub search_depth: Length<uom::si::SI<f64>, f64>,
+
+    /// How fast the probe is lowered onto the board.
+    #[serde(deserialize_with = "parse_quantity")]
+    pub speed: Velocity<uom::si::SI<f64>, f64>,
 }
 
 #[derive(Debug, Deserialize, Clone, Copy)]
@@ -36,6 +74,18 @@ pub struct WorkspaceSize {
     pub width: Length<uom::si::SI<f64>, f64>,
     #[serde(deserialize_with = "parse_quantity")]
     pub height: Length<uom::si::SI<f64>, f64>,
+
+    /// The heights a spindle can safely move between. Heights are not checked if this isn't set.
+    #[serde(default)]
+    pub z_range: Option<ZRange>,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy)]
+pub struct ZRange {
+    #[serde(deserialize_with = "parse_quantity")]
+    pub min: Length<uom::si::SI<f64>, f64>,
+    #[serde(deserialize_with = "parse_quantity")]
+    pub max: Length<uom::si::SI<f64>, f64>,
 }
 
 impl From<WorkspaceSize> for Vector2<Length<uom::si::SI<f64>, f64>> {
@@ -53,15 +103,87 @@ pub struct JobConfig {
     #[serde(default = "distance_per_step_default")]
     pub distance_per_step: Length<uom::si::SI<f64>, f64>,
 
+    /// Curves are output as arcs when the points approximating them stay within this distance of the arc.
+    /// Set this to zero to only output straight lines.
+    #[serde(default = "arc_tolerance_default", deserialize_with = "parse_quantity")]
+    pub arc_tolerance: Length<uom::si::SI<f64>, f64>,
+
+    /// Holes within this distance of a drill bit's diameter are drilled with it rather than milled.
+    #[serde(
+        default = "drill_tolerance_default",
+        deserialize_with = "parse_quantity"
+    )]
+    pub drill_tolerance: Length<uom::si::SI<f64>, f64>,
+
+    /// How the inside of engraved shapes is cleared.
+    #[serde(default)]
+    pub fill: FillStrategy,
+
+    /// How many outlines isolation milling cuts around each shape, each one further out than the last.
+    #[serde(default = "isolation_passes_default")]
+    pub isolation_passes: usize,
+
+    /// The width of copper isolation milling clears around each shape. The passes are spread evenly across it.
+    /// Defaults to the passes overlapping by half the diameter of the tool.
+    #[serde(
+        default = "JobConfig::default_isolation_width",
+        deserialize_with = "parse_optional_quantity"
+    )]
+    pub isolation_width: Option<Length<uom::si::SI<f64>, f64>>,
+
     /// The power of the tool. The unit depends on the tool.
     #[serde(flatten)]
     pub tool_power: ToolConfig,
 }
 
+impl JobConfig {
+    fn default_isolation_width() -> Option<Length<uom::si::SI<f64>, f64>> {
+        None
+    }
+}
+
+#[derive(Debug, Deserialize, Default)]
+pub enum FillStrategy {
+    /// Parallel lines, alternating between horizontal and vertical with each pass.
+    #[default]
+    #[serde(rename = "raster")]
+    Raster,
+
+    /// Rings following the outline of the shape, shrinking inwards until it is cleared.
+    /// Each ring is linked to the next one inside it so that the tool doesn't need to be lifted between them.
+    #[serde(rename = "contour")]
+    Contour {
+        /// The distance between rings. Defaults to half the diameter of the tool.
+        #[serde(
+            default = "FillStrategy::default_stepover",
+            deserialize_with = "parse_optional_quantity"
+        )]
+        stepover: Option<Length<uom::si::SI<f64>, f64>>,
+    },
+}
+
+impl FillStrategy {
+    fn default_stepover() -> Option<Length<uom::si::SI<f64>, f64>> {
+        None
+    }
+}
+
+fn isolation_passes_default() -> usize {
+    1
+}
+
 fn distance_per_step_default() -> Length<uom::si::SI<f64>, f64> {
     Length::new::<millimeter>(0.1)
 }
 
+fn arc_tolerance_default() -> Length<uom::si::SI<f64>, f64> {
+    Length::new::<millimeter>(0.01)
+}
+
+fn drill_tolerance_default() -> Length<uom::si::SI<f64>, f64> {
+    Length::new::<millimeter>(0.05)
+}
+
 #[derive(Debug, Deserialize)]
 #[serde(untagged)]
 pub enum ToolConfig {
@@ -168,12 +290,30 @@ pub struct SpindleConfig {
 
     pub bits: HashMap<String, SpindleBit>,
 
+    /// How long to wait after starting the spindle for it to come up to speed.
+    #[serde(
+        default = "SpindleConfig::default_spin_up_time",
+        deserialize_with = "parse_optional_quantity"
+    )]
+    pub spin_up_time: Option<Time<uom::si::SI<f64>, f64>>,
+
+    /// How bits are changed when a job needs more than one of them.
+    /// Drill files use every bit in `bits` when this is set, otherwise only the bit of the job is used.
+    #[serde(default)]
+    pub tool_change: Option<ToolChangeConfig>,
+
     #[serde(default)]
     pub init_gcode: Option<PathBuf>,
     #[serde(default)]
     pub shutdown_gcode: Option<PathBuf>,
 }
 
+impl SpindleConfig {
+    fn default_spin_up_time() -> Option<Time<uom::si::SI<f64>, f64>> {
+        None
+    }
+}
+
 #[derive(Debug, Deserialize)]
 pub enum SpindleBit {
     #[serde(rename = "end_mill")]
@@ -181,4 +321,61 @@ pub enum SpindleBit {
         #[serde(deserialize_with = "parse_quantity")]
         diameter: Length<uom::si::SI<f64>, f64>,
     },
+
+    /// A drill bit can only plunge straight down, but is much faster and cleaner at making holes its own size.
+    #[serde(rename = "drill")]
+    Drill {
+        #[serde(deserialize_with = "parse_quantity")]
+        diameter: Length<uom::si::SI<f64>, f64>,
+
+        /// How deep to drill before retracting to clear chips. Holes are drilled in one plunge if not set.
+        #[serde(
+            default = "SpindleBit::default_optional_length",
+            deserialize_with = "parse_optional_quantity"
+        )]
+        peck_depth: Option<Length<uom::si::SI<f64>, f64>>,
+
+        /// The height to retract to between pecks. Defaults to the travel height of the job.
+        #[serde(
+            default = "SpindleBit::default_optional_length",
+            deserialize_with = "parse_optional_quantity"
+        )]
+        retract_height: Option<Length<uom::si::SI<f64>, f64>>,
+    },
+}
+
+impl SpindleBit {
+    fn default_optional_length() -> Option<Length<uom::si::SI<f64>, f64>> {
+        None
+    }
+
+    pub fn diameter(&self) -> Length<uom::si::SI<f64>, f64> {
+        match self {
+            SpindleBit::EndMill { diameter } => *diameter,
+            SpindleBit::Drill { diameter, .. } => *diameter,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ToolChangeConfig {
+    pub method: ToolChangeMethod,
+
+    /// Pause after changing bits so that the Z axis can be zeroed to the tip of the new bit.
+    #[serde(default)]
+    pub rezero: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub enum ToolChangeMethod {
+    /// The machine changes bits by itself with M6.
+    #[serde(rename = "m6")]
+    M6 {
+        /// The tool number of each bit in the machine's tool table.
+        tool_numbers: HashMap<String, usize>,
+    },
+
+    /// The machine pauses with a message so the bit can be changed by hand.
+    #[serde(rename = "pause")]
+    Pause,
 }
@@ -3,6 +3,7 @@ use std::{collections::HashMap, fs, path::Path};
 use anyhow::{anyhow, bail, Context, Result};
 use geo::MultiPolygon;
 use geo_offset::Offset;
+use itertools::Itertools;
 use nalgebra::Vector2;
 use uom::si::{
     length::{inch, millimeter, Length},
@@ -10,26 +11,51 @@ use uom::si::{
 };
 
 use crate::{
+    config::machine::{SpindleBit, SpindleConfig, ToolChangeMethod, ToolConfig},
     gcode_generation::{
-        add_point_string_to_gcode_vector, GCodeConfig, GCommand, MovementType, Tool, ToolSelection,
+        add_point_string_to_gcode_vector, segment_to_cut, GCodeConfig, GCommand, MovementType,
+        Tool, ToolSelection,
     },
     geometry::{Segment, Shape},
     parsing::{
         self,
-        drill::{DrillCommand, HeaderCommand, RouteCommand},
+        drill::{
+            ArcCenter, Coordinates, DrillCommand, HeaderCommand, IncludedZeros, RouteCommand, Span,
+        },
         gerber::Polarity,
         UnitMode,
     },
 };
 
+/// Arc radii are written with only a few decimals, so the start and end of an arc can be this far, in millimeters,
+/// from where its radius puts them.
+const ARC_RADIUS_TOLERANCE: f64 = 0.01;
+
 #[derive(Debug, Default)]
 pub struct DrillFile {
     holes: Vec<DrillHole>,
+    slots: Vec<DrillSlot>,
     paths: Vec<RoutePath>,
 }
 
 impl DrillFile {
-    pub fn generate_gcode(&self, config: GCodeConfig) -> Result<()> {
+    /// Holes that all have the same diameter, in millimeters, rather than ones loaded from a file.
+    pub fn with_holes(positions: impl IntoIterator<Item = Vector2<f64>>, diameter: f64) -> Self {
+        Self {
+            holes: positions
+                .into_iter()
+                .map(|position| DrillHole {
+                    position,
+                    diameter,
+                    tool: 0,
+                })
+                .collect(),
+            slots: Vec::new(),
+            paths: Vec::new(),
+        }
+    }
+
+    pub fn generate_gcode(&self, mut config: GCodeConfig) -> Result<()> {
         let passes = match config.job_config.tool_power {
             crate::config::machine::ToolConfig::Laser {
                 laser_power,
@@ -44,6 +70,7 @@ impl DrillFile {
                             GCommand::SetWorkSpeed(work_speed),
                             GCommand::EquipTool(Tool::Laser {
                                 max_power: laser.max_power,
+                                diameter: laser.point_diameter,
                             }),
                             GCommand::SetPower(laser_power),
                         ]
@@ -72,6 +99,7 @@ impl DrillFile {
                             GCommand::SetWorkSpeed(work_speed),
                             GCommand::EquipTool(Tool::Spindle {
                                 max_spindle_speed: spindle.max_speed,
+                                diameter: config.tool_config.diameter(),
                                 plunge_speed,
                                 travel_height,
                                 pass_depth,
@@ -83,6 +111,10 @@ impl DrillFile {
                         .cloned(),
                     );
 
+                    if let Some(spin_up_time) = spindle.spin_up_time {
+                        config.commands.push(GCommand::Dwell(spin_up_time));
+                    }
+
                     // The number of passes we are to do.
                     // This will have a tendency to undercut but that should be fine for most use cases.
                     pass_depth.map_or(1, |pass_depth| {
@@ -102,7 +134,243 @@ impl DrillFile {
 
         let distance_per_step = config.job_config.distance_per_step.get::<millimeter>();
 
-        let mut holes = self.holes.clone();
+        let drill_tolerance = config.job_config.drill_tolerance.get::<millimeter>();
+
+        match config.tool_config {
+            ToolSelection::Spindle { spindle, bit } if spindle.tool_change.is_some() => {
+                let groups = self.group_holes_by_bit(spindle, drill_tolerance)?;
+
+                // Start with the bit that is already installed so we can save a tool change.
+                let (installed_group, other_groups): (Vec<_>, Vec<_>) = groups
+                    .into_iter()
+                    .partition(|(_name, group_bit, _holes)| std::ptr::eq(*group_bit, *bit));
+
+                if let Some((bit_name, group_bit, holes)) = installed_group.into_iter().next() {
+                    log::info!("Cutting {} holes with bit {}.", holes.len(), bit_name);
+                    Self::cut_holes(
+                        holes,
+                        config.commands,
+                        passes,
+                        group_bit.diameter().get::<millimeter>(),
+                        drill_tolerance,
+                        Some(Drilling::new(group_bit, drill_tolerance)),
+                    )?;
+                }
+
+                self.cut_paths(&mut config, passes, distance_per_step, drill_tolerance)?;
+                self.cut_slots(config.commands, passes, config.tool_config, drill_tolerance)?;
+
+                let mut installed_bit = *bit;
+                for (bit_name, group_bit, holes) in other_groups {
+                    log::info!("Cutting {} holes with bit {}.", holes.len(), bit_name);
+                    Self::change_bit(&mut config, spindle, bit_name, group_bit)?;
+                    installed_bit = group_bit;
+                    Self::cut_holes(
+                        holes,
+                        config.commands,
+                        passes,
+                        group_bit.diameter().get::<millimeter>(),
+                        drill_tolerance,
+                        Some(Drilling::new(group_bit, drill_tolerance)),
+                    )?;
+                }
+
+                // Later stages of the gcode file expect the profile's bit to be installed.
+                if !std::ptr::eq(installed_bit, *bit) {
+                    let bit_name = spindle
+                        .bits
+                        .iter()
+                        .find(|(_name, spindle_bit)| std::ptr::eq(*spindle_bit, *bit))
+                        .map(|(name, _bit)| name.as_str())
+                        .context("Profile's bit is not installable in the spindle.")?;
+
+                    log::info!("Changing back to bit {}.", bit_name);
+                    Self::change_bit(&mut config, spindle, bit_name, bit)?;
+                }
+            }
+            ToolSelection::Spindle { spindle: _, bit } => {
+                // End mills only plunge through holes when tools are changed automatically, since they would
+                // otherwise be expected to mill out holes of every size.
+                let drilling = match bit {
+                    SpindleBit::Drill { .. } => Some(Drilling::new(bit, drill_tolerance)),
+                    SpindleBit::EndMill { .. } => None,
+                };
+
+                Self::cut_holes(
+                    self.holes.clone(),
+                    config.commands,
+                    passes,
+                    bit.diameter().get::<millimeter>(),
+                    drill_tolerance,
+                    drilling,
+                )?;
+                self.cut_paths(&mut config, passes, distance_per_step, drill_tolerance)?;
+                self.cut_slots(config.commands, passes, config.tool_config, drill_tolerance)?;
+            }
+            ToolSelection::Laser { laser: _ } => {
+                Self::cut_holes(
+                    self.holes.clone(),
+                    config.commands,
+                    passes,
+                    config.tool_config.diameter().get::<millimeter>(),
+                    drill_tolerance,
+                    None,
+                )?;
+                self.cut_paths(&mut config, passes, distance_per_step, drill_tolerance)?;
+                self.cut_slots(config.commands, passes, config.tool_config, drill_tolerance)?;
+            }
+        }
+
+        if let Some(shutdown_gcode) = config.tool_config.shutdown_gcode() {
+            config.commands.push(GCommand::IncludeFile(
+                config.include_file_search_directory.join(shutdown_gcode),
+            ));
+        }
+
+        config.commands.push(GCommand::EquipTool(Tool::None));
+
+        Ok(())
+    }
+
+    /// Assigns every hole to the bit best suited to make it.
+    /// A bit within `drill_tolerance` of the hole's size plunges straight through it, otherwise the largest end mill
+    /// that fits in the hole mills it out.
+    fn group_holes_by_bit<'a>(
+        &self,
+        spindle: &'a SpindleConfig,
+        drill_tolerance: f64,
+    ) -> Result<Vec<(&'a str, &'a SpindleBit, Vec<DrillHole>)>> {
+        // Sort the bits so that the selection doesn't depend on the order of the hash map.
+        let bits: Vec<(&String, &SpindleBit)> = spindle
+            .bits
+            .iter()
+            .sorted_by(|a, b| Ord::cmp(a.0, b.0))
+            .collect();
+
+        let mut groups: Vec<(&'a str, &'a SpindleBit, Vec<DrillHole>)> = Vec::new();
+        let mut unmakeable_holes = Vec::new();
+
+        for hole in self.holes.iter() {
+            let drilling_bit = bits
+                .iter()
+                .map(|(name, bit)| {
+                    (
+                        name,
+                        bit,
+                        (bit.diameter().get::<millimeter>() - hole.diameter).abs(),
+                    )
+                })
+                .filter(|(_name, _bit, difference)| *difference <= drill_tolerance)
+                .min_by(|a, b| a.2.total_cmp(&b.2))
+                .map(|(name, bit, _difference)| (*name, *bit));
+
+            // Drill bits can only plunge, so only end mills can mill out larger holes.
+            let milling_bit = || {
+                bits.iter()
+                    .filter(|(_name, bit)| matches!(bit, SpindleBit::EndMill { .. }))
+                    .filter(|(_name, bit)| {
+                        bit.diameter().get::<millimeter>() < hole.diameter - drill_tolerance
+                    })
+                    .max_by(|a, b| {
+                        a.1.diameter()
+                            .get::<millimeter>()
+                            .total_cmp(&b.1.diameter().get::<millimeter>())
+                    })
+                    .copied()
+            };
+
+            match drilling_bit.or_else(milling_bit) {
+                Some((name, bit)) => {
+                    if let Some((_name, _bit, holes)) = groups
+                        .iter_mut()
+                        .find(|(_name, group_bit, _holes)| std::ptr::eq(*group_bit, bit))
+                    {
+                        holes.push(hole.clone());
+                    } else {
+                        groups.push((name.as_str(), bit, vec![hole.clone()]));
+                    }
+                }
+                None => unmakeable_holes.push(hole),
+            }
+        }
+
+        if !unmakeable_holes.is_empty() {
+            let mut message = String::from(
+                "No bit installable in the spindle can make these holes. Drill bits can't mill out larger holes:",
+            );
+            for hole in unmakeable_holes {
+                message += &format!(
+                    "\n\tT{} ({} mm) at ({}, {})",
+                    hole.tool, hole.diameter, hole.position.x, hole.position.y
+                );
+            }
+
+            bail!(message);
+        }
+
+        Ok(groups)
+    }
+
+    /// Stops the spindle and has the bit replaced, then starts the spindle again.
+    fn change_bit(
+        config: &mut GCodeConfig,
+        spindle: &SpindleConfig,
+        bit_name: &str,
+        bit: &SpindleBit,
+    ) -> Result<()> {
+        let tool_change = spindle
+            .tool_change
+            .as_ref()
+            .context("Spindle does not support tool changes.")?;
+
+        let tool_number = match &tool_change.method {
+            ToolChangeMethod::M6 { tool_numbers } => Some(
+                *tool_numbers
+                    .get(bit_name)
+                    .with_context(|| format!("Bit {} has no tool number.", bit_name))?,
+            ),
+            ToolChangeMethod::Pause => None,
+        };
+
+        let spindle_speed = match config.job_config.tool_power {
+            ToolConfig::EndMill { spindle_speed, .. } => spindle_speed,
+            ToolConfig::Laser { .. } => {
+                bail!("Job was configured for a laser but selected tool is a spindle.")
+            }
+        };
+
+        config.commands.push(GCommand::ToolChange {
+            tool_number,
+            diameter: bit.diameter(),
+            message: format!(
+                "Install bit {} ({} mm)",
+                bit_name,
+                bit.diameter().get::<millimeter>()
+            ),
+            rezero: tool_change.rezero,
+        });
+        config
+            .commands
+            .push(GCommand::SetSpindleSpeed(spindle_speed));
+
+        if let Some(spin_up_time) = spindle.spin_up_time {
+            config.commands.push(GCommand::Dwell(spin_up_time));
+        }
+
+        Ok(())
+    }
+
+    /// Cuts the holes with a single tool, visiting them in nearest neighbor order.
+    /// Holes are drilled if drilling is possible and the bit is within tolerance of the hole's size, otherwise they
+    /// are milled out.
+    fn cut_holes(
+        mut holes: Vec<DrillHole>,
+        commands: &mut Vec<GCommand>,
+        passes: usize,
+        tool_diameter: f64,
+        tolerance: f64,
+        drilling: Option<Drilling>,
+    ) -> Result<()> {
         let mut last_position = Vector2::new(0.0, 0.0);
 
         while !holes.is_empty() {
@@ -119,19 +387,125 @@ impl DrillFile {
 
             let hole = holes.remove(hole_selection);
 
-            for pass_index in 0..passes {
-                hole.generate_gcode(
-                    pass_index,
-                    distance_per_step,
-                    config.commands,
-                    config.tool_config.diameter().get::<millimeter>(),
-                );
+            match &drilling {
+                Some(drilling) if (hole.diameter - tool_diameter).abs() <= drilling.tolerance => {
+                    hole.drill(commands, drilling.peck_depth, drilling.retract_height);
+                }
+                Some(drilling) if !drilling.can_mill => {
+                    bail!(
+                        "Hole at ({}, {}) with a diameter of {} mm has to be milled out, but the installed bit is a {} mm drill.",
+                        hole.position.x,
+                        hole.position.y,
+                        hole.diameter,
+                        tool_diameter
+                    );
+                }
+                Some(_) if hole.diameter < tool_diameter => {
+                    bail!(
+                        "Hole at ({}, {}) with a diameter of {} mm is smaller than the {} mm bit.",
+                        hole.position.x,
+                        hole.position.y,
+                        hole.diameter,
+                        tool_diameter
+                    );
+                }
+                _ => {
+                    // Holes larger than the bit are milled out.
+                    for pass_index in 0..passes {
+                        hole.generate_gcode(pass_index, commands, tool_diameter, tolerance)?;
+                    }
+                }
             }
 
             last_position = hole.position;
         }
 
+        Ok(())
+    }
+
+    /// Mills out the slots with the installed tool, the same as routes.
+    fn cut_slots(
+        &self,
+        commands: &mut Vec<GCommand>,
+        passes: usize,
+        tool: &ToolSelection,
+        tolerance: f64,
+    ) -> Result<()> {
+        // Slots are milled out from the side, which drill bits can't do.
+        if let (
+            Some(slot),
+            ToolSelection::Spindle {
+                bit: SpindleBit::Drill { .. },
+                ..
+            },
+        ) = (self.slots.first(), tool)
+        {
+            bail!(
+                "Slot from ({}, {}) to ({}, {}) has to be milled, but the installed bit is a drill.",
+                slot.start.x,
+                slot.start.y,
+                slot.end.x,
+                slot.end.y
+            );
+        }
+
+        let tool_diameter = tool.diameter().get::<millimeter>();
+        for slot in self.slots.iter() {
+            if slot.diameter < tool_diameter - tolerance {
+                bail!(
+                    "Slot from ({}, {}) to ({}, {}) with a width of {} mm is narrower than the {} mm tool.",
+                    slot.start.x,
+                    slot.start.y,
+                    slot.end.x,
+                    slot.end.y,
+                    slot.diameter,
+                    tool_diameter
+                );
+            }
+
+            for pass_index in 0..passes {
+                slot.generate_gcode(pass_index, commands, tool_diameter, tolerance)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn cut_paths(
+        &self,
+        config: &mut GCodeConfig,
+        passes: usize,
+        distance_per_step: f64,
+        tolerance: f64,
+    ) -> Result<()> {
+        // Routes are milled along their path, which drill bits can't do.
+        if let (
+            Some(path),
+            ToolSelection::Spindle {
+                bit: SpindleBit::Drill { .. },
+                ..
+            },
+        ) = (self.paths.first(), config.tool_config)
+        {
+            bail!(
+                "Route starting at ({}, {}) has to be milled, but the installed bit is a drill.",
+                path.shape.starting_point.x,
+                path.shape.starting_point.y
+            );
+        }
+
+        let tool_diameter = config.tool_config.diameter().get::<millimeter>();
+
         for path in self.paths.iter() {
+            // Routes made with a tool as wide as the installed one are cut along the route itself, arcs and all.
+            if (path.diameter - tool_diameter).abs() <= tolerance {
+                for pass_index in 0..passes {
+                    path.generate_gcode(pass_index, config.commands);
+                }
+
+                continue;
+            }
+
             let polygon = path
                 .convert_to_geo_polygon(distance_per_step)
                 .context("Failed to convert route path to polygon.")?;
@@ -148,6 +522,7 @@ impl DrillFile {
                         config.commands,
                         polygon.exterior().0.iter(),
                         pass_index,
+                        config.job_config.arc_tolerance,
                     );
 
                     for interior in polygon.interiors() {
@@ -155,21 +530,48 @@ impl DrillFile {
                             config.commands,
                             interior.0.iter(),
                             pass_index,
+                            config.job_config.arc_tolerance,
                         );
                     }
                 }
             }
         }
 
-        if let Some(shutdown_gcode) = config.tool_config.shutdown_gcode() {
-            config.commands.push(GCommand::IncludeFile(
-                config.include_file_search_directory.join(shutdown_gcode),
-            ));
-        }
+        Ok(())
+    }
+}
 
-        config.commands.push(GCommand::EquipTool(Tool::None));
+/// How to plunge a bit straight through holes of its own size.
+struct Drilling {
+    /// Holes within this distance of the bit's diameter are drilled.
+    tolerance: f64,
+    peck_depth: Option<Length<uom::si::SI<f64>, f64>>,
+    retract_height: Option<Length<uom::si::SI<f64>, f64>>,
 
-        Ok(())
+    /// Drill bits can only plunge, so holes of other sizes can't be milled out with them.
+    can_mill: bool,
+}
+
+impl Drilling {
+    fn new(bit: &SpindleBit, tolerance: f64) -> Self {
+        match bit {
+            SpindleBit::EndMill { diameter: _ } => Self {
+                tolerance,
+                peck_depth: None,
+                retract_height: None,
+                can_mill: true,
+            },
+            SpindleBit::Drill {
+                diameter: _,
+                peck_depth,
+                retract_height,
+            } => Self {
+                tolerance,
+                peck_depth: *peck_depth,
+                retract_height: *retract_height,
+                can_mill: false,
+            },
+        }
     }
 }
 
@@ -177,23 +579,54 @@ impl DrillFile {
 pub struct DrillHole {
     position: Vector2<f64>,
     diameter: f64,
+
+    /// The tool the drill file selected for this hole.
+    tool: usize,
 }
 
 impl DrillHole {
     /// Create the hole using a laser or router bit.
+    /// Holes within `tolerance` of the tool's size are made at their center, since circling it would be a full circle
+    /// of no radius.
     fn generate_gcode(
         &self,
         pass_index: usize,
-        distance_per_step: f64,
         commands: &mut Vec<GCommand>,
         tool_diameter: f64,
+        tolerance: f64,
         // TODO allow limiting tool selections
-    ) {
-        let tool_radius = tool_diameter / 2.0;
-        let inner_diameter = self.diameter - tool_radius;
-        let inner_radius = inner_diameter / 2.0;
+    ) -> Result<()> {
+        let inner_radius = (self.diameter - tool_diameter) / 2.0;
+
+        let center = (
+            Length::new::<millimeter>(self.position.x),
+            Length::new::<millimeter>(self.position.y),
+        );
+
+        if inner_radius < -tolerance / 2.0 {
+            bail!(
+                "Hole at ({}, {}) with a diameter of {} mm is smaller than the {} mm tool.",
+                self.position.x,
+                self.position.y,
+                self.diameter,
+                tool_diameter
+            );
+        }
+
+        if inner_radius <= tolerance / 2.0 {
+            // Spindles plunge at the center, while lasers are switched on there.
+            commands.push(GCommand::MoveTo { target: center });
+            commands.push(GCommand::Cut {
+                pass_index,
+                movement: MovementType::Linear,
+                target: center,
+            });
+
+            return Ok(());
+        }
 
         let starting_point = self.position + Vector2::new(inner_radius, 0.0);
+        let opposite_point = self.position - Vector2::new(inner_radius, 0.0);
 
         commands.push(GCommand::MoveTo {
             target: (
@@ -202,38 +635,134 @@ impl DrillHole {
             ),
         });
 
-        let arch_length = std::f64::consts::PI * 2.0 * inner_radius;
-        let steps = (arch_length / distance_per_step).ceil();
+        // The circle is cut as two halves, since a full circle arc is ambiguous to some machines.
+        commands.push(GCommand::Cut {
+            pass_index,
+            movement: MovementType::CounterClockwiseCurve { center },
+            target: (
+                Length::new::<millimeter>(opposite_point.x),
+                Length::new::<millimeter>(opposite_point.y),
+            ),
+        });
+
+        commands.push(GCommand::Cut {
+            pass_index,
+            movement: MovementType::CounterClockwiseCurve { center },
+            target: (
+                Length::new::<millimeter>(starting_point.x),
+                Length::new::<millimeter>(starting_point.y),
+            ),
+        });
+
+        Ok(())
+    }
+}
+
+impl DrillHole {
+    /// Create the hole by plunging a drill bit of the same size straight through it.
+    fn drill(
+        &self,
+        commands: &mut Vec<GCommand>,
+        peck_depth: Option<Length<uom::si::SI<f64>, f64>>,
+        retract_height: Option<Length<uom::si::SI<f64>, f64>>,
+    ) {
+        commands.push(GCommand::Drill {
+            target: (
+                Length::new::<millimeter>(self.position.x),
+                Length::new::<millimeter>(self.position.y),
+            ),
+            peck_depth,
+            retract_height,
+        });
+    }
+}
+
+/// A hole stretched from one point to another, such as the plated slots of connectors.
+#[derive(Debug, Clone)]
+pub struct DrillSlot {
+    start: Vector2<f64>,
+    end: Vector2<f64>,
 
-        let angle_step = std::f64::consts::PI * 2.0 / steps;
+    /// The width of the slot.
+    diameter: f64,
 
-        let steps = steps as usize;
+    /// The tool the drill file selected for this slot.
+    tool: usize,
+}
 
-        for step_index in 0..steps {
-            let angle = angle_step * step_index as f64;
+impl DrillSlot {
+    /// Mills the slot along its outline, a stadium inset by the radius of the tool.
+    /// Slots that are as wide as the tool are cut along their center line instead.
+    fn generate_gcode(
+        &self,
+        pass_index: usize,
+        commands: &mut Vec<GCommand>,
+        tool_diameter: f64,
+        tolerance: f64,
+    ) -> Result<()> {
+        let to_target = |point: Vector2<f64>| {
+            (
+                Length::new::<millimeter>(point.x),
+                Length::new::<millimeter>(point.y),
+            )
+        };
 
-            let (sin, cos) = angle.sin_cos();
-            let offset = Vector2::new(cos, sin) * inner_radius;
+        let direction = self.end - self.start;
+        let length = direction.norm();
+        let inset_radius = (self.diameter - tool_diameter) / 2.0;
 
-            let new_position = self.position + offset;
+        if length == 0.0 {
+            let hole = DrillHole {
+                position: self.start,
+                diameter: self.diameter,
+                tool: self.tool,
+            };
+            hole.generate_gcode(pass_index, commands, tool_diameter, tolerance)?;
+        } else if inset_radius <= tolerance / 2.0 {
+            commands.push(GCommand::MoveTo {
+                target: to_target(self.start),
+            });
             commands.push(GCommand::Cut {
                 pass_index,
                 movement: MovementType::Linear,
-                target: (
-                    Length::new::<millimeter>(new_position.x),
-                    Length::new::<millimeter>(new_position.y),
-                ),
+                target: to_target(self.end),
             });
+        } else {
+            // The outline goes along the right side of the slot, around its end, and back along the left side.
+            let side = Vector2::new(-direction.y, direction.x) / length * inset_radius;
+
+            commands.push(GCommand::MoveTo {
+                target: to_target(self.start - side),
+            });
+            commands.extend([
+                GCommand::Cut {
+                    pass_index,
+                    movement: MovementType::Linear,
+                    target: to_target(self.end - side),
+                },
+                GCommand::Cut {
+                    pass_index,
+                    movement: MovementType::CounterClockwiseCurve {
+                        center: to_target(self.end),
+                    },
+                    target: to_target(self.end + side),
+                },
+                GCommand::Cut {
+                    pass_index,
+                    movement: MovementType::Linear,
+                    target: to_target(self.start + side),
+                },
+                GCommand::Cut {
+                    pass_index,
+                    movement: MovementType::CounterClockwiseCurve {
+                        center: to_target(self.start),
+                    },
+                    target: to_target(self.start - side),
+                },
+            ]);
         }
 
-        commands.push(GCommand::Cut {
-            pass_index,
-            movement: MovementType::Linear,
-            target: (
-                Length::new::<millimeter>(starting_point.x),
-                Length::new::<millimeter>(starting_point.y),
-            ),
-        });
+        Ok(())
     }
 }
 
@@ -244,6 +773,22 @@ pub struct RoutePath {
 }
 
 impl RoutePath {
+    /// Cuts along the route, with its arcs as arc movements.
+    fn generate_gcode(&self, pass_index: usize, commands: &mut Vec<GCommand>) {
+        commands.push(GCommand::MoveTo {
+            target: (
+                Length::new::<millimeter>(self.shape.starting_point.x),
+                Length::new::<millimeter>(self.shape.starting_point.y),
+            ),
+        });
+        commands.extend(
+            self.shape
+                .segments
+                .iter()
+                .map(|segment| segment_to_cut(segment, pass_index)),
+        );
+    }
+
     pub fn convert_to_geo_polygon(&self, distance_per_step: f64) -> Result<MultiPolygon<f64>> {
         let line_string = self.shape.convert_to_geo_line_string(distance_per_step);
 
@@ -267,12 +812,38 @@ enum CutMode {
     Route,
 }
 
+#[derive(Debug, Clone, Copy)]
+enum RouteMode {
+    Linear,
+    Clockwise,
+    CounterClockwise,
+}
+
+/// The settings from the header of a drill file.
+#[derive(Default)]
+struct Header {
+    tools: HashMap<usize, f64>,
+    unit_mode: Option<UnitMode>,
+    included_zeros: Option<IncludedZeros>,
+    digits: Option<(u32, u32)>,
+    incremental: bool,
+}
+
 struct DrillingContext {
     unit_mode: UnitMode,
+
+    /// Tool diameters in millimeters.
     tools: HashMap<usize, f64>,
+
+    /// Numbers without a decimal point are read as plain integers unless the header says which zeros were kept.
+    included_zeros: Option<IncludedZeros>,
+    digits: (u32, u32),
+
     coordinate_mode: CoordinateMode,
     cut_mode: CutMode,
+    route_mode: RouteMode,
     position: Vector2<f64>,
+    tool_index: usize,
     tool_diameter: Option<f64>,
 }
 
@@ -286,55 +857,150 @@ impl DrillingContext {
         .get::<millimeter>()
     }
 
-    fn internalize_coordinate(&self, coordinate: Vector2<f64>) -> Vector2<f64> {
-        Vector2::new(
-            self.internalize_axis(coordinate.x),
-            self.internalize_axis(coordinate.y),
-        )
+    /// Reads a number as it was written in the file, placing the implied decimal point if it doesn't have one.
+    fn internalize_number(&self, number: &Span) -> Result<f64> {
+        let text = number.fragment();
+
+        let value = match self.included_zeros {
+            Some(included_zeros) if !text.contains('.') => {
+                let (sign, digits) = match text.strip_prefix('-') {
+                    Some(digits) => (-1.0, digits),
+                    None => (1.0, text.trim_start_matches('+')),
+                };
+                let (integer_digits, decimal_digits) = self.digits;
+
+                let decimal_places = match included_zeros {
+                    IncludedZeros::Leading => digits.len() as i32 - integer_digits as i32,
+                    IncludedZeros::Trailing => decimal_digits as i32,
+                };
+
+                sign * digits
+                    .parse::<f64>()
+                    .with_context(|| format!("Invalid number {}.", text))?
+                    / 10.0f64.powi(decimal_places)
+            }
+            _ => text
+                .parse::<f64>()
+                .with_context(|| format!("Invalid number {}.", text))?,
+        };
+
+        Ok(self.internalize_axis(value))
+    }
+
+    /// The position that coordinates move to. An axis that was left out keeps its current position.
+    fn resolve(&self, coordinates: &Coordinates) -> Result<Vector2<f64>> {
+        let axis = |number: &Option<Span>, current: f64| -> Result<f64> {
+            match (number, &self.coordinate_mode) {
+                (Some(number), CoordinateMode::Absolute) => self.internalize_number(number),
+                (Some(number), CoordinateMode::Incremental) => {
+                    Ok(current + self.internalize_number(number)?)
+                }
+                (None, _) => Ok(current),
+            }
+        };
+
+        Ok(Vector2::new(
+            axis(&coordinates.x, self.position.x)?,
+            axis(&coordinates.y, self.position.y)?,
+        ))
+    }
+
+    /// A hole at the current position, made with the selected tool.
+    fn hole(&self) -> Result<DrillHole> {
+        Ok(DrillHole {
+            position: self.position,
+            diameter: self.tool_diameter.context("No tool equipped.")?,
+            tool: self.tool_index,
+        })
+    }
+
+    /// A slot from `start` to the current position, made with the selected tool.
+    fn slot(&self, start: Vector2<f64>) -> Result<DrillSlot> {
+        Ok(DrillSlot {
+            start,
+            end: self.position,
+            diameter: self.tool_diameter.context("No tool equipped.")?,
+            tool: self.tool_index,
+        })
     }
 }
 
 pub fn load(drill_file: &mut DrillFile, path: &Path) -> Result<()> {
     let drill_file_content =
         fs::read_to_string(path).context("Failed to read drill file from disk.")?;
-    match parsing::drill::parse_drill_file(parsing::drill::Span::new(&drill_file_content)) {
-        Ok((_remainder, (header, commands))) => {
-            let mut tools = HashMap::new();
-            let mut unit_mode = None;
+    load_from_str(drill_file, &drill_file_content, path)
+}
+
+/// Reads the source of a drill file. The path is only used to point out where problems are.
+fn load_from_str(drill_file: &mut DrillFile, drill_file_content: &str, path: &Path) -> Result<()> {
+    match parsing::drill::parse_drill_file(parsing::drill::Span::new(drill_file_content)) {
+        Ok((_remainder, (header_commands, commands))) => {
+            let mut header = Header::default();
 
-            for command in header.iter() {
+            for command in header_commands.iter() {
                 let location_info = command.location_info();
 
-                process_header_command(&command.command, &mut tools, &mut unit_mode).with_context(
-                    move || {
-                        format!(
-                            "error processing header command: {}:{}",
-                            path.to_string_lossy(),
-                            location_info
-                        )
-                    },
-                )?;
+                if let Some(ignored) = command.command.ignored() {
+                    log::warn!("{}:{}: {}", path.to_string_lossy(), location_info, ignored);
+                }
+
+                process_header_command(&command.command, &mut header).with_context(move || {
+                    format!(
+                        "error processing header command: {}:{}",
+                        path.to_string_lossy(),
+                        location_info
+                    )
+                })?;
             }
 
-            let unit_mode = unit_mode.context("Unit mode is missing from file header.")?;
+            let unit_mode = header
+                .unit_mode
+                .context("Unit mode is missing from file header.")?;
+
+            // Excellon's default formats, for files that leave out the decimal point without saying how many digits there are.
+            let digits = header.digits.unwrap_or(match unit_mode {
+                UnitMode::Metric => (3, 3),
+                UnitMode::Imperial => (2, 4),
+            });
 
             let mut drilling_context = DrillingContext {
                 unit_mode,
-                tools,
-                coordinate_mode: CoordinateMode::Absolute,
+                tools: HashMap::new(),
+                included_zeros: header.included_zeros,
+                digits,
+                coordinate_mode: if header.incremental {
+                    CoordinateMode::Incremental
+                } else {
+                    CoordinateMode::Absolute
+                },
                 cut_mode: CutMode::Drill,
+                route_mode: RouteMode::Linear,
                 position: Vector2::zeros(),
+                tool_index: 0,
                 tool_diameter: None,
             };
 
+            // Tools are sized in the unit of the header, even if the body switches to another one.
+            drilling_context.tools = header
+                .tools
+                .into_iter()
+                .map(|(index, diameter)| (index, drilling_context.internalize_axis(diameter)))
+                .collect();
+
             for command in commands.iter() {
                 let location_info = command.location_info();
 
+                if let Some(ignored) = command.command.ignored() {
+                    log::warn!("{}:{}: {}", path.to_string_lossy(), location_info, ignored);
+                }
+
                 process_drill_command(
                     &command.command,
                     &mut drilling_context,
                     &mut drill_file.holes,
+                    &mut drill_file.slots,
                     &mut drill_file.paths,
+                    path,
                 )
                 .with_context(move || {
                     format!(
@@ -369,144 +1035,542 @@ fn process_drill_command(
     command: &DrillCommand,
     drilling_context: &mut DrillingContext,
     holes: &mut Vec<DrillHole>,
+    slots: &mut Vec<DrillSlot>,
     paths: &mut Vec<RoutePath>,
+    path: &Path,
 ) -> Result<()> {
     match command {
         DrillCommand::Comment(_comment) => {}
+        DrillCommand::Attribute(_attribute) => {
+            // Attributes describe the file, they don't change what is cut.
+        }
         DrillCommand::AbsoluteMode => drilling_context.coordinate_mode = CoordinateMode::Absolute,
         DrillCommand::IncrementalMode => {
             drilling_context.coordinate_mode = CoordinateMode::Incremental
         }
+        DrillCommand::UnitMode(unit_mode) => drilling_context.unit_mode = *unit_mode,
         DrillCommand::DrillMode => drilling_context.cut_mode = CutMode::Drill,
         DrillCommand::RouteMode => drilling_context.cut_mode = CutMode::Route,
         DrillCommand::SelectTool(index) => {
+            drilling_context.tool_index = *index;
+
             if *index != 0 {
                 let diameter = drilling_context
                     .tools
                     .get(index)
                     .context("Command referenced undefined tool.")?;
-                drilling_context.tool_diameter = Some(drilling_context.internalize_axis(*diameter));
+                drilling_context.tool_diameter = Some(*diameter);
             } else {
                 drilling_context.tool_diameter = None;
             }
         }
         DrillCommand::DrillHit { target } => {
-            let target = drilling_context.internalize_coordinate(*target);
-
-            let new_position = match drilling_context.coordinate_mode {
-                CoordinateMode::Absolute => target,
-                CoordinateMode::Incremental => drilling_context.position + target,
-            };
+            drilling_context.position = drilling_context.resolve(target)?;
 
             // We only add a hole if we're in drill mode.
             if drilling_context.cut_mode == CutMode::Drill {
-                holes.push(DrillHole {
-                    position: new_position,
-                    diameter: drilling_context
-                        .tool_diameter
-                        .context("No tool equipped.")?,
-                });
+                holes.push(drilling_context.hole()?);
             }
+        }
+        DrillCommand::Repeat { count, step } => {
+            // The step is always relative to the last hole.
+            let step = Vector2::new(
+                step.x
+                    .as_ref()
+                    .map_or(Ok(0.0), |x| drilling_context.internalize_number(x))?,
+                step.y
+                    .as_ref()
+                    .map_or(Ok(0.0), |y| drilling_context.internalize_number(y))?,
+            );
+
+            for _ in 0..*count {
+                drilling_context.position += step;
+
+                if drilling_context.cut_mode == CutMode::Drill {
+                    holes.push(drilling_context.hole()?);
+                }
+            }
+        }
+        DrillCommand::Slot { start, end } => {
+            let start = drilling_context.resolve(start)?;
+            drilling_context.position = start;
+            drilling_context.position = drilling_context.resolve(end)?;
 
-            drilling_context.position = new_position;
+            // Like holes, slots are only made in drill mode.
+            if drilling_context.cut_mode == CutMode::Drill {
+                slots.push(drilling_context.slot(start)?);
+            }
         }
+        DrillCommand::CutterCompensation(_compensation) => {}
         DrillCommand::Route(route) => {
             if drilling_context.cut_mode == CutMode::Route {
                 let starting_point = drilling_context.position;
-                let mut last_point = starting_point;
-                let segments = route
-                    .iter()
-                    .map(|route_command| match route_command {
-                        RouteCommand::LinearMove { target } => {
-                            last_point = *target;
-
-                            Segment::Line { end: *target }
-                        }
-                        RouteCommand::ClockwiseCurve { target, diameter } => {
-                            let cord_length = (target - last_point).norm();
-                            let chord_center = (target + last_point) / 2.0;
-                            let radius = diameter / 2.0;
-
-                            let center_offset_x = chord_center.x
-                                + ((radius.powi(2) - (cord_length / 2.0).powi(2))
-                                    * (target.x - last_point.x))
-                                    .sqrt();
-                            let center_offset_y = chord_center.x
-                                + ((radius.powi(2) - (cord_length / 2.0).powi(2))
-                                    * (last_point.y - target.y))
-                                    .sqrt();
-
-                            last_point = *target;
-                            Segment::ClockwiseCurve {
-                                end: *target,
-                                center: chord_center
-                                    - Vector2::new(center_offset_x, center_offset_y),
-                            }
-                        }
-                        RouteCommand::CounterClockwiseCurve { target, diameter } => {
-                            let cord_length = (target - last_point).norm();
-                            let chord_center = (target + last_point) / 2.0;
-                            let radius = diameter / 2.0;
-
-                            let center_offset_x = chord_center.x
-                                + ((radius.powi(2) - (cord_length / 2.0).powi(2))
-                                    * (target.x - last_point.x))
-                                    .sqrt();
-                            let center_offset_y = chord_center.x
-                                + ((radius.powi(2) - (cord_length / 2.0).powi(2))
-                                    * (last_point.y - target.y))
-                                    .sqrt();
-
-                            last_point = *target;
-                            Segment::CounterClockwiseCurve {
-                                end: *target,
-                                center: chord_center
-                                    + Vector2::new(center_offset_x, center_offset_y),
-                            }
-                        }
-                    })
-                    .collect();
+                let mut segments = Vec::new();
 
-                paths.push(RoutePath {
-                    shape: Shape {
-                        polarity: Polarity::Dark,
-                        starting_point,
-                        segments,
-                    },
-                    diameter: drilling_context.tool_diameter.context("No tool equipped")?,
-                });
+                for route_command in route.iter() {
+                    let location_info = route_command.location_info();
+
+                    let segment = process_route_command(&route_command.command, drilling_context)
+                        .with_context(move || {
+                        format!(
+                            "error processing route command: {}:{}",
+                            path.to_string_lossy(),
+                            location_info
+                        )
+                    })?;
+
+                    drilling_context.position = match segment {
+                        Segment::Line { end }
+                        | Segment::ClockwiseCurve { end, .. }
+                        | Segment::CounterClockwiseCurve { end, .. } => end,
+                    };
+                    segments.push(segment);
+                }
+
+                // A single straight move is a routed slot, which is how KiCad writes oval holes.
+                if let [Segment::Line { end: _ }] = segments[..] {
+                    slots.push(drilling_context.slot(starting_point)?);
+                } else {
+                    paths.push(RoutePath {
+                        shape: Shape {
+                            polarity: Polarity::Dark,
+                            starting_point,
+                            segments,
+                        },
+                        diameter: drilling_context.tool_diameter.context("No tool equipped")?,
+                    });
+                }
             } else {
                 bail!("Tool down command specified while in drilling mode.");
             }
         }
+        DrillCommand::Circle {
+            clockwise,
+            center,
+            radius,
+        } => {
+            let center = drilling_context.resolve(center)?;
+            let radius = drilling_context.internalize_number(radius)?;
+            if radius <= 0.0 {
+                bail!("Circle radius of {} mm must be larger than zero.", radius);
+            }
+
+            // The circle is cut as two halves, since a full circle arc is ambiguous to some machines.
+            let starting_point = center + Vector2::new(radius, 0.0);
+            let opposite_point = center - Vector2::new(radius, 0.0);
+            let half = |end| match clockwise {
+                true => Segment::ClockwiseCurve { end, center },
+                false => Segment::CounterClockwiseCurve { end, center },
+            };
+
+            paths.push(RoutePath {
+                shape: Shape {
+                    polarity: Polarity::Dark,
+                    starting_point,
+                    segments: vec![half(opposite_point), half(starting_point)],
+                },
+                diameter: drilling_context.tool_diameter.context("No tool equipped")?,
+            });
+
+            // The tool returns to the center once the circle is done.
+            drilling_context.position = center;
+        }
+        DrillCommand::Unsupported(_line) => {}
     }
 
     Ok(())
 }
 
-fn process_header_command(
-    command: &HeaderCommand,
-    tools: &mut HashMap<usize, f64>,
-    unit_mode: &mut Option<UnitMode>,
-) -> Result<()> {
+/// Converts a move of a route into a segment, starting from the current position.
+fn process_route_command(
+    command: &RouteCommand,
+    drilling_context: &mut DrillingContext,
+) -> Result<Segment> {
+    let (mode, target, center) = match command {
+        RouteCommand::LinearMove { target } => (RouteMode::Linear, target, None),
+        RouteCommand::ClockwiseCurve { target, center } => {
+            (RouteMode::Clockwise, target, Some(center))
+        }
+        RouteCommand::CounterClockwiseCurve { target, center } => {
+            (RouteMode::CounterClockwise, target, Some(center))
+        }
+        RouteCommand::Move { target, center } => {
+            (drilling_context.route_mode, target, center.as_ref())
+        }
+    };
+    drilling_context.route_mode = mode;
+
+    let start = drilling_context.position;
+    let end = drilling_context.resolve(target)?;
+
+    let center = match (mode, center) {
+        (RouteMode::Linear, None) => return Ok(Segment::Line { end }),
+        (RouteMode::Linear, Some(_center)) => {
+            bail!("An arc center was given for a linear move.")
+        }
+        (RouteMode::Clockwise | RouteMode::CounterClockwise, None) => {
+            bail!("Arcs need an A radius or I and J center offset.")
+        }
+        (RouteMode::Clockwise | RouteMode::CounterClockwise, Some(center)) => center,
+    };
+    let clockwise = matches!(mode, RouteMode::Clockwise);
+
+    let center = match center {
+        ArcCenter::Radius(radius) => {
+            let radius = drilling_context.internalize_number(radius)?;
+            arc_center_from_radius(start, end, radius, clockwise)?
+        }
+        ArcCenter::Offset { i, j } => {
+            // The offset is always relative to the start of the arc, even in absolute mode.
+            let offset = |number: &Option<Span>| {
+                number.as_ref().map_or(Ok(0.0), |number| {
+                    drilling_context.internalize_number(number)
+                })
+            };
+            let center = start + Vector2::new(offset(i)?, offset(j)?);
+
+            let start_radius = (start - center).norm();
+            let end_radius = (end - center).norm();
+            if (start_radius - end_radius).abs() > ARC_RADIUS_TOLERANCE {
+                bail!(
+                    "Arc starts {} mm from its center at ({}, {}) but ends {} mm from it.",
+                    start_radius,
+                    center.x,
+                    center.y,
+                    end_radius
+                );
+            }
+
+            center
+        }
+    };
+
+    Ok(match clockwise {
+        true => Segment::ClockwiseCurve { end, center },
+        false => Segment::CounterClockwiseCurve { end, center },
+    })
+}
+
+/// Finds the center of an arc from its radius, in millimeters. Two arcs with that radius join the start and end, so
+/// the one that turns through less than half of a circle is used.
+fn arc_center_from_radius(
+    start: Vector2<f64>,
+    end: Vector2<f64>,
+    radius: f64,
+    clockwise: bool,
+) -> Result<Vector2<f64>> {
+    let chord = end - start;
+    let chord_length = chord.norm();
+
+    if radius <= 0.0 {
+        bail!("Arc radius of {} mm must be larger than zero.", radius);
+    }
+    if chord_length == 0.0 {
+        bail!(
+            "Arc starts and ends at the same point, so its center can't be found from its radius."
+        );
+    }
+    if chord_length / 2.0 > radius + ARC_RADIUS_TOLERANCE {
+        bail!(
+            "Arc radius of {} mm can't reach from ({}, {}) to ({}, {}), which are {} mm apart.",
+            radius,
+            start.x,
+            start.y,
+            end.x,
+            end.y,
+            chord_length
+        );
+    }
+
+    // Radii that only fall short by rounding make a half circle.
+    let distance_to_center = (radius.powi(2) - (chord_length / 2.0).powi(2))
+        .max(0.0)
+        .sqrt();
+
+    // Clockwise arcs that turn less than half a circle have their center to the right of the chord.
+    let left = Vector2::new(-chord.y, chord.x) / chord_length;
+    let chord_center = (start + end) / 2.0;
+
+    Ok(match clockwise {
+        true => chord_center - left * distance_to_center,
+        false => chord_center + left * distance_to_center,
+    })
+}
+
+fn process_header_command(command: &HeaderCommand, header: &mut Header) -> Result<()> {
     match command {
         HeaderCommand::Comment(_comment) => {}
-        HeaderCommand::UnitMode(new_unit_mode) => {
-            if unit_mode.is_some() {
+        HeaderCommand::Attribute(_attribute) => {
+            // Attributes describe the file, they don't change what is cut.
+        }
+        HeaderCommand::UnitMode {
+            unit_mode,
+            included_zeros,
+            digits,
+        } => {
+            if header.unit_mode.is_some() {
                 log::warn!("Unit mode for drill file was set more than once.");
             }
 
-            *unit_mode = Some(*new_unit_mode);
+            header.unit_mode = Some(*unit_mode);
+            header.included_zeros = included_zeros.or(header.included_zeros);
+            header.digits = digits.or(header.digits);
         }
         HeaderCommand::Format(_version) => {
             // Unique to KiCad, not something we pay attention to.
         }
-        HeaderCommand::ToolDeclaration { index, diameter } => {
-            if tools.insert(*index, *diameter).is_some() {
+        HeaderCommand::IncrementalInput(enabled) => header.incremental = *enabled,
+        HeaderCommand::ToolDeclaration {
+            index,
+            diameter,
+            ignored_parameters: _,
+        } => {
+            if header.tools.insert(*index, *diameter).is_some() {
                 log::warn!("Tool {} has been defined multiple times.", index);
             }
         }
+        HeaderCommand::Unsupported(_line) => {}
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn try_load_source(source: &str) -> Result<DrillFile> {
+        let mut drill_file = DrillFile::default();
+        load_from_str(&mut drill_file, source, Path::new("test.drl"))?;
+        Ok(drill_file)
+    }
+
+    fn load_source(source: &str) -> DrillFile {
+        try_load_source(source).unwrap()
+    }
+
+    fn hole_positions(drill_file: &DrillFile) -> Vec<(f64, f64)> {
+        drill_file
+            .holes
+            .iter()
+            .map(|hole| (hole.position.x, hole.position.y))
+            .collect()
+    }
+
+    fn assert_near(actual: (f64, f64), expected: (f64, f64)) {
+        assert!(
+            (actual.0 - expected.0).abs() < 1e-9 && (actual.1 - expected.1).abs() < 1e-9,
+            "{:?} is not {:?}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn imperial_leading_zeros_without_decimal_point() {
+        // Inches default to two integer digits and four decimal ones.
+        let drill_file = load_source("M48\nINCH,LZ\nT1C0.0394\n%\nT1\nX01Y0005\nX-0025\nM30\n");
+
+        let positions = hole_positions(&drill_file);
+        assert_near(positions[0], (25.4, 1.27));
+        assert_near(positions[1], (-6.35, 1.27));
+        assert!((drill_file.holes[0].diameter - 1.00076).abs() < 1e-9);
+    }
+
+    #[test]
+    fn metric_trailing_zeros_without_decimal_point() {
+        let drill_file =
+            load_source("M48\nMETRIC,TZ,000.000\nT1C0.8\n%\nT1\nX1500Y-250\nX1.5Y2.0\nM30\n");
+
+        let positions = hole_positions(&drill_file);
+        assert_near(positions[0], (1.5, -0.25));
+
+        // Numbers with a decimal point are read as they are.
+        assert_near(positions[1], (1.5, 2.0));
+    }
+
+    #[test]
+    fn repeats_step_from_the_last_hole() {
+        let drill_file =
+            load_source("M48\nMETRIC\nT1C0.8\n%\nT1\nX1.0Y1.0\nR3X2.0\nR1Y-0.5\nM30\n");
+
+        let positions = hole_positions(&drill_file);
+        assert_eq!(positions.len(), 5);
+        assert_near(positions[3], (7.0, 1.0));
+        assert_near(positions[4], (7.0, 0.5));
+    }
+
+    #[test]
+    fn unit_switches() {
+        // Tools keep the unit of the header, while coordinates follow the switches.
+        let drill_file = load_source(
+            "M48\nMETRIC\nT1C1.0\nM95\nT1\nX1.0Y0.0\nM72\nX1.0Y0.0\nM71\nX1.0Y0.0\nM30\n",
+        );
+
+        let positions = hole_positions(&drill_file);
+        assert_near(positions[0], (1.0, 0.0));
+        assert_near(positions[1], (25.4, 0.0));
+        assert_near(positions[2], (1.0, 0.0));
+        assert!(drill_file.holes.iter().all(|hole| hole.diameter == 1.0));
+    }
+
+    #[test]
+    fn unknown_commands_are_skipped() {
+        let drill_file = load_source("M48\nMETRIC\nVER,1\nT1C0.8\n%\nT1\nG93X1Y1\nX1.0Y2.0\nM30\n");
+
+        assert_eq!(hole_positions(&drill_file).len(), 1);
+        assert_near(hole_positions(&drill_file)[0], (1.0, 2.0));
+    }
+
+    fn arc_center(start: (f64, f64), end: (f64, f64), radius: f64, clockwise: bool) -> (f64, f64) {
+        let center = arc_center_from_radius(
+            Vector2::new(start.0, start.1),
+            Vector2::new(end.0, end.1),
+            radius,
+            clockwise,
+        )
+        .unwrap();
+
+        (center.x, center.y)
+    }
+
+    #[test]
+    fn quarter_arcs_from_radius() {
+        // The shorter arc from (1, 0) to (0, 1) turns counter clockwise around the origin.
+        assert_near(arc_center((1.0, 0.0), (0.0, 1.0), 1.0, false), (0.0, 0.0));
+        assert_near(arc_center((1.0, 0.0), (0.0, 1.0), 1.0, true), (1.0, 1.0));
+
+        // Running the chord the other way swaps which center each direction uses.
+        assert_near(arc_center((0.0, 1.0), (1.0, 0.0), 1.0, true), (0.0, 0.0));
+        assert_near(arc_center((0.0, 1.0), (1.0, 0.0), 1.0, false), (1.0, 1.0));
+    }
+
+    #[test]
+    fn half_circle_radius_rounding() {
+        // A radius rounded down by less than the tolerance still makes a half circle.
+        assert_near(arc_center((0.0, 0.0), (2.0, 0.0), 0.995, true), (1.0, 0.0));
+        assert_near(arc_center((0.0, 0.0), (2.0, 0.0), 0.995, false), (1.0, 0.0));
+
+        assert!(
+            arc_center_from_radius(Vector2::new(0.0, 0.0), Vector2::new(2.0, 0.0), 0.98, true)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn arcs_from_center_offset() {
+        let drill_file = load_source(
+            "M48\nMETRIC\nT1C1.0\n%\nT1\nG00X1.0Y0.0\nM15\nG03X0.0Y1.0I-1.0J0.0\nG01X0.0Y2.0\nM16\nM30\n",
+        );
+
+        match drill_file.paths[0].shape.segments[..] {
+            [Segment::CounterClockwiseCurve { end, center }, Segment::Line { .. }] => {
+                assert_near((end.x, end.y), (0.0, 1.0));
+                assert_near((center.x, center.y), (0.0, 0.0));
+            }
+            ref segments => panic!("Unexpected segments {:?}", segments),
+        }
+    }
+
+    #[test]
+    fn holes_the_size_of_the_tool_are_cut_at_their_center() {
+        let hole = DrillHole {
+            position: Vector2::new(2.0, 3.0),
+            diameter: 1.0,
+            tool: 1,
+        };
+
+        let mut commands = Vec::new();
+        hole.generate_gcode(0, &mut commands, 1.02, 0.05).unwrap();
+
+        match &commands[..] {
+            [GCommand::MoveTo { target }, GCommand::Cut {
+                movement: MovementType::Linear,
+                target: cut_target,
+                ..
+            }] => {
+                assert_near(
+                    (target.0.get::<millimeter>(), target.1.get::<millimeter>()),
+                    (2.0, 3.0),
+                );
+                assert_near(
+                    (
+                        cut_target.0.get::<millimeter>(),
+                        cut_target.1.get::<millimeter>(),
+                    ),
+                    (2.0, 3.0),
+                );
+            }
+            commands => panic!("Unexpected commands {:?}", commands),
+        }
+
+        assert!(hole.generate_gcode(0, &mut commands, 1.2, 0.05).is_err());
+    }
+
+    #[test]
+    fn routes_are_cut_with_arc_movements() {
+        let drill_file = load_source(
+            "M48\nMETRIC\nT1C1.0\n%\nT1\nG00X1.0Y0.0\nM15\nG03X0.0Y1.0I-1.0J0.0\nG01X0.0Y2.0\nM16\nM30\n",
+        );
+
+        let mut commands = Vec::new();
+        drill_file.paths[0].generate_gcode(0, &mut commands);
+
+        match &commands[..] {
+            [GCommand::MoveTo { .. }, GCommand::Cut {
+                movement: MovementType::CounterClockwiseCurve { center },
+                ..
+            }, GCommand::Cut {
+                movement: MovementType::Linear,
+                ..
+            }] => {
+                assert_near(
+                    (center.0.get::<millimeter>(), center.1.get::<millimeter>()),
+                    (0.0, 0.0),
+                );
+            }
+            commands => panic!("Unexpected commands {:?}", commands),
+        }
+    }
+
+    #[test]
+    fn arcs_from_mismatched_center_offset_are_rejected() {
+        let result = try_load_source(
+            "M48\nMETRIC\nT1C1.0\n%\nT1\nG00X1.0Y0.0\nM15\nG03X0.0Y1.0I-0.5J0.0\nG01X0.0Y2.0\nM16\nM30\n",
+        );
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn circles() {
+        for (command, clockwise) in [("G32", true), ("G33", false)] {
+            let drill_file = load_source(&format!(
+                "M48\nMETRIC\nT1C1.0\n%\nT1\nG00\n{}X5.0Y5.0A2.0\nX6.0Y5.0\nM30\n",
+                command
+            ));
+
+            let shape = &drill_file.paths[0].shape;
+            assert_near((shape.starting_point.x, shape.starting_point.y), (7.0, 5.0));
+
+            let ends: Vec<(f64, f64)> = shape
+                .segments
+                .iter()
+                .map(|segment| match (segment, clockwise) {
+                    (Segment::ClockwiseCurve { end, center }, true)
+                    | (Segment::CounterClockwiseCurve { end, center }, false) => {
+                        assert_near((center.x, center.y), (5.0, 5.0));
+                        (end.x, end.y)
+                    }
+                    (segment, _) => panic!("Unexpected segment {:?}", segment),
+                })
+                .collect();
+            assert_eq!(ends.len(), 2);
+            assert_near(ends[0], (3.0, 5.0));
+            assert_near(ends[1], (7.0, 5.0));
+
+            // Nothing is drilled in route mode, but moves start from the center of the circle.
+            assert!(drill_file.holes.is_empty());
+        }
+    }
+}
@@ -0,0 +1,40 @@
+//! Estimates how long the GCode files of a forge file take to run, without writing any output.
+
+use anyhow::{Context, Result};
+
+use crate::{
+    arguments::EstimateCommand, config::Config, forge_file::ForgeFile,
+    gcode_simulation::JobStatistics, generate_gcode_files,
+};
+
+pub fn estimate(estimate_configuration: EstimateCommand, global_config: Config) -> Result<()> {
+    let forge_file_path = &estimate_configuration.forge_file_path;
+    log::info!("Read Forge File: {:?}", forge_file_path);
+
+    let forge_file =
+        ForgeFile::load_from_path(forge_file_path).context("Failed to load forge file.")?;
+
+    let generated_files = generate_gcode_files(&forge_file, forge_file_path, None, &global_config)?;
+
+    let mut total = JobStatistics::default();
+
+    for generated_file in generated_files {
+        let stage_statistics = generated_file.estimate();
+        let file_total = stage_statistics.iter().sum::<JobStatistics>();
+
+        println!("{}: {}", generated_file.path.display(), file_total);
+        for (span, statistics) in generated_file
+            .stage_spans
+            .iter()
+            .zip(stage_statistics.iter())
+        {
+            println!("\t{}: {}", span.description, statistics);
+        }
+
+        total += file_total;
+    }
+
+    println!("Total: {}", total);
+
+    Ok(())
+}
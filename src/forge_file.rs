@@ -8,7 +8,13 @@ use std::{
     path::{Path, PathBuf},
 };
 
-use crate::config::machine::Machine;
+use uom::si::length::{millimeter, Length};
+
+use crate::{
+    config::machine::Machine,
+    layers::LayerRole,
+    parsing::{parse_optional_quantity, parse_quantity},
+};
 
 #[derive(Debug, Deserialize)]
 pub struct ForgeFile {
@@ -23,12 +29,39 @@ pub struct ForgeFile {
     pub machines: HashMap<String, Machine>,
 
     pub gcode_files: HashMap<PathBuf, Vec<Stage>>,
+
+    /// Where the CAD tool wrote the board's gerber and drill files, relative to the forge file. Stages that name a
+    /// `layer` instead of a file search it. Defaults to the forge file's directory.
+    #[serde(default)]
+    pub layer_directory: Option<PathBuf>,
+
+    /// A gerber job file written by the CAD tool, relative to the forge file. Stages that name a `layer` find its file
+    /// through the job instead of the `layer_directory`, and cuts are checked against the thickness of the board.
+    #[serde(default)]
+    pub gerber_job: Option<PathBuf>,
+
+    /// Places several boards on one sheet of stock. Every stage is repeated for each board on the panel.
+    #[serde(default)]
+    pub panel: Option<Panel>,
+
+    /// Where the board, or the whole panel, sits on the bed of the machine.
+    #[serde(default)]
+    pub placement: Placement,
+
+    /// Pins that line up the back side of the board once it has been flipped over.
+    #[serde(default)]
+    pub registration: Option<Registration>,
 }
 
 impl ForgeFile {
     pub fn load_from_path(path: &Path) -> Result<Self> {
         let forge = std::fs::read_to_string(path).context("Failed to read forge file.")?;
-        let forge: Self = serde_yaml::from_str(&forge).context("Failed to decode forge file.")?;
+
+        Self::load_from_str(&forge)
+    }
+
+    pub fn load_from_str(forge: &str) -> Result<Self> {
+        let forge: Self = serde_yaml::from_str(forge).context("Failed to decode forge file.")?;
 
         Ok(forge)
     }
@@ -38,18 +71,187 @@ impl ForgeFile {
     }
 }
 
+/// Moves every stage of the board from gerber coordinates to machine coordinates. The board is rotated about the
+/// gerber origin first, and then the chosen origin of the rotated board is moved to the given position.
+#[derive(Debug, Deserialize)]
+pub struct Placement {
+    #[serde(default)]
+    pub origin: PlacementOrigin,
+
+    #[serde(
+        default = "Placement::default_offset",
+        deserialize_with = "parse_quantity"
+    )]
+    pub x: Length<uom::si::SI<f64>, f64>,
+
+    #[serde(
+        default = "Placement::default_offset",
+        deserialize_with = "parse_quantity"
+    )]
+    pub y: Length<uom::si::SI<f64>, f64>,
+
+    /// Counterclockwise, in degrees.
+    #[serde(default)]
+    pub rotation: f64,
+}
+
+impl Default for Placement {
+    fn default() -> Self {
+        Self {
+            origin: PlacementOrigin::default(),
+            x: Self::default_offset(),
+            y: Self::default_offset(),
+            rotation: 0.0,
+        }
+    }
+}
+
+impl Placement {
+    fn default_offset() -> Length<uom::si::SI<f64>, f64> {
+        Length::new::<millimeter>(0.0)
+    }
+}
+
+/// Two pins on the axis the board is flipped over, one below the board and one above it. Holes for the pins are made
+/// by a `registration_pins` stage. The stock is flipped over the pins, so the back side is mirrored about their axis.
+#[derive(Debug, Deserialize)]
+pub struct Registration {
+    #[serde(deserialize_with = "parse_quantity")]
+    pub diameter: Length<uom::si::SI<f64>, f64>,
+
+    /// The gap left between the board and the edges of the pin holes.
+    #[serde(
+        default = "Registration::default_clearance",
+        deserialize_with = "parse_quantity"
+    )]
+    pub clearance: Length<uom::si::SI<f64>, f64>,
+
+    /// The X position of the pins on the bed of the machine. Defaults to the middle of the board, or of the panel.
+    #[serde(
+        default = "Registration::default_axis",
+        deserialize_with = "parse_optional_quantity"
+    )]
+    pub axis: Option<Length<uom::si::SI<f64>, f64>>,
+}
+
+impl Registration {
+    fn default_clearance() -> Length<uom::si::SI<f64>, f64> {
+        Length::new::<millimeter>(3.0)
+    }
+
+    fn default_axis() -> Option<Length<uom::si::SI<f64>, f64>> {
+        None
+    }
+}
+
+/// The point of the board that is moved to the position of its placement.
+#[derive(Debug, Deserialize, Default, Clone, Copy)]
+pub enum PlacementOrigin {
+    /// The origin of the gerber files, so the board stays where its gerber files put it.
+    #[default]
+    #[serde(rename = "gerber")]
+    Gerber,
+
+    /// The lower left corner of the board. For panels, this is the corner of the stock, margin included.
+    #[serde(rename = "lower_left")]
+    LowerLeft,
+
+    /// The center of the board. For panels, this is the center of the stock.
+    #[serde(rename = "center")]
+    Center,
+}
+
+impl Display for PlacementOrigin {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Gerber => write!(f, "gerber origin"),
+            Self::LowerLeft => write!(f, "lower left corner"),
+            Self::Center => write!(f, "center"),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Panel {
+    /// The gap left between boards laid out in a grid.
+    #[serde(default = "Panel::default_gap", deserialize_with = "parse_quantity")]
+    pub spacing: Length<uom::si::SI<f64>, f64>,
+
+    /// The gap left between the edges of the stock and the boards. Grids start this far from the origin.
+    #[serde(default = "Panel::default_gap", deserialize_with = "parse_quantity")]
+    pub margin: Length<uom::si::SI<f64>, f64>,
+
+    pub boards: Vec<PanelBoard>,
+}
+
+impl Panel {
+    fn default_gap() -> Length<uom::si::SI<f64>, f64> {
+        Length::new::<millimeter>(0.0)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PanelBoard {
+    /// Another forge project to place on the panel. Its stages are added to the GCode files of the same name.
+    /// Defaults to the board of this forge file.
+    #[serde(default)]
+    pub forge_file: Option<PathBuf>,
+
+    #[serde(flatten)]
+    pub layout: PanelLayout,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum PanelLayout {
+    /// Copies in rows and columns. Each grid of the panel is placed above the one before it.
+    Grid { columns: usize, rows: usize },
+
+    /// Copies at the given positions.
+    Positions { positions: Vec<PanelPosition> },
+}
+
+/// Where the lower left corner of a board goes, after it has been rotated.
+#[derive(Debug, Deserialize)]
+pub struct PanelPosition {
+    #[serde(deserialize_with = "parse_quantity")]
+    pub x: Length<uom::si::SI<f64>, f64>,
+
+    #[serde(deserialize_with = "parse_quantity")]
+    pub y: Length<uom::si::SI<f64>, f64>,
+
+    /// Counterclockwise, in degrees.
+    #[serde(default)]
+    pub rotation: f64,
+}
+
 #[derive(Debug, Deserialize)]
 pub enum Stage {
     #[serde(rename = "engrave_mask")]
     EngraveMask {
         machine_config: Option<Utf8PathBuf>,
-        gerber_file: PathBuf,
 
+        #[serde(flatten)]
+        file: GerberSource,
+
+        /// Defaults to whether the stage's layer is on the bottom of the board.
         #[serde(default)]
-        backside: bool,
+        backside: Option<bool>,
 
         #[serde(default)]
         invert: bool,
+
+        /// Only the objects of the gerber file with these attributes are engraved.
+        #[serde(default)]
+        select: Option<ObjectSelection>,
+
+        /// Objects of the gerber file with any of these attributes are left out.
+        #[serde(default)]
+        exclude: Option<ObjectSelection>,
+
+        /// A probe log of the board. The cuts of spindles follow the surface of the board it describes.
+        #[serde(default)]
+        height_map: Option<PathBuf>,
     },
     #[serde(rename = "cut_board")]
     CutBoard {
@@ -58,9 +260,105 @@ pub enum Stage {
         #[serde(flatten)]
         file: CutBoardFile,
 
+        /// Defaults to whether the stage's layer is on the bottom of the board.
         #[serde(default)]
-        backside: bool,
+        backside: Option<bool>,
+
+        /// A probe log of the board. The cuts of spindles follow the surface of the board it describes.
+        #[serde(default)]
+        height_map: Option<PathBuf>,
     },
+    /// Mills around the shapes of a gerber file to isolate them from the rest of the copper, leaving it in place.
+    /// The engraving profile's `isolation_passes` and `isolation_width` set how much copper is cleared around them.
+    #[serde(rename = "isolation_mill")]
+    IsolationMill {
+        machine_config: Option<Utf8PathBuf>,
+
+        #[serde(flatten)]
+        file: GerberSource,
+
+        /// Defaults to whether the stage's layer is on the bottom of the board.
+        #[serde(default)]
+        backside: Option<bool>,
+
+        /// Only the objects of the gerber file with these attributes are isolated.
+        #[serde(default)]
+        select: Option<ObjectSelection>,
+
+        /// Objects of the gerber file with any of these attributes are left out.
+        #[serde(default)]
+        exclude: Option<ObjectSelection>,
+
+        /// A probe log of the board. The cuts of spindles follow the surface of the board it describes.
+        #[serde(default)]
+        height_map: Option<PathBuf>,
+    },
+    /// Probes the height of the board on a grid so that later stages can follow its surface.
+    #[serde(rename = "probe_height_map")]
+    ProbeHeightMap {
+        machine_config: Option<Utf8PathBuf>,
+
+        /// The grid covers the bounds of this file, which is usually the board outline.
+        #[serde(flatten)]
+        file: GerberSource,
+
+        /// The largest distance between probed points.
+        #[serde(deserialize_with = "parse_quantity")]
+        grid_spacing: Length<uom::si::SI<f64>, f64>,
+
+        /// Where the machine should save the probe results, if it can.
+        #[serde(default = "Stage::default_probe_log")]
+        probe_log: String,
+
+        /// Defaults to whether the stage's layer is on the bottom of the board.
+        #[serde(default)]
+        backside: Option<bool>,
+    },
+    /// Drills the holes of the forge file's registration pins, or marks them with a laser. The pins are placed around
+    /// the whole panel, so this stage is only done once no matter how many boards are on it.
+    #[serde(rename = "registration_pins")]
+    RegistrationPins { machine_config: Option<Utf8PathBuf> },
+}
+
+impl Stage {
+    fn default_probe_log() -> String {
+        String::from("probe_log.txt")
+    }
+
+    /// Whether the stage works on the back of the board. Stages that name a bottom layer are done from the back unless
+    /// they say otherwise.
+    pub fn backside(&self) -> bool {
+        let (backside, layer) = match self {
+            Stage::EngraveMask { backside, file, .. }
+            | Stage::IsolationMill { backside, file, .. }
+            | Stage::ProbeHeightMap { backside, file, .. } => (*backside, file.layer()),
+            Stage::CutBoard { backside, file, .. } => (*backside, file.layer()),
+            Stage::RegistrationPins { .. } => (None, None),
+        };
+
+        backside.unwrap_or(layer.is_some_and(|layer| layer.is_bottom()))
+    }
+}
+
+/// Picks objects of a gerber file by the X2 attributes its CAD tool attached to them. An object is picked if it matches
+/// every category that lists anything, by matching any of the entries of that category.
+#[derive(Debug, Deserialize, Default)]
+pub struct ObjectSelection {
+    /// Aperture functions such as `ComponentPad`, `ViaPad`, `Conductor` or `NonConductor`.
+    #[serde(default)]
+    pub aperture_functions: Vec<String>,
+
+    /// Net names such as `GND`.
+    #[serde(default)]
+    pub nets: Vec<String>,
+
+    /// Reference designators of components such as `U1`.
+    #[serde(default)]
+    pub components: Vec<String>,
+
+    /// Component pins, given as the reference designator and pin number, such as `U1-3`.
+    #[serde(default)]
+    pub pins: Vec<String>,
 }
 
 #[derive(Debug, Deserialize, Clone, Copy)]
@@ -87,10 +385,33 @@ pub enum CutBoardFile {
 
         #[serde(default)]
         select_lines: LineSelection,
+
+        /// Bridges of material left standing along the cut so that the board doesn't come loose.
+        #[serde(default)]
+        tabs: Option<HoldingTabs>,
     },
     Drill {
         drill_file: PathBuf,
     },
+    /// The file of a layer found in the forge file's `layer_directory`, which can be a gerber or drill file.
+    Layer {
+        layer: LayerRole,
+
+        #[serde(default)]
+        select_lines: LineSelection,
+
+        #[serde(default)]
+        tabs: Option<HoldingTabs>,
+    },
+}
+
+impl CutBoardFile {
+    pub fn layer(&self) -> Option<LayerRole> {
+        match self {
+            CutBoardFile::Gerber { .. } | CutBoardFile::Drill { .. } => None,
+            CutBoardFile::Layer { layer, .. } => Some(*layer),
+        }
+    }
 }
 
 impl Display for CutBoardFile {
@@ -99,8 +420,161 @@ impl Display for CutBoardFile {
             CutBoardFile::Gerber {
                 gerber_file,
                 select_lines: _,
+                tabs: _,
             } => write!(f, "gerber file: {:?}", gerber_file),
             CutBoardFile::Drill { drill_file } => write!(f, "drill file: {:?}", drill_file),
+            CutBoardFile::Layer { layer, .. } => write!(f, "{} layer", layer),
         }
     }
 }
+
+/// A gerber file, given by its path or by the layer it holds.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum GerberSource {
+    File {
+        gerber_file: PathBuf,
+    },
+    /// Found in the forge file's `layer_directory` by what it holds, such as `bottom_copper`.
+    Layer {
+        layer: LayerRole,
+    },
+}
+
+impl GerberSource {
+    pub fn layer(&self) -> Option<LayerRole> {
+        match self {
+            GerberSource::File { .. } => None,
+            GerberSource::Layer { layer } => Some(*layer),
+        }
+    }
+}
+
+impl Display for GerberSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GerberSource::File { gerber_file } => write!(f, "gerber file: {:?}", gerber_file),
+            GerberSource::Layer { layer } => write!(f, "{} layer", layer),
+        }
+    }
+}
+
+/// Holding tabs are spread evenly along each outline that is cut, moved aside where they would land on a corner.
+/// Either a number of tabs per outline or the distance between them can be given. Defaults to four tabs per outline.
+#[derive(Debug, Deserialize)]
+pub struct HoldingTabs {
+    #[serde(default)]
+    pub count: Option<usize>,
+
+    #[serde(
+        default = "HoldingTabs::default_spacing",
+        deserialize_with = "parse_optional_quantity"
+    )]
+    pub spacing: Option<Length<uom::si::SI<f64>, f64>>,
+
+    /// The width of the material left standing.
+    #[serde(deserialize_with = "parse_quantity")]
+    pub width: Length<uom::si::SI<f64>, f64>,
+
+    /// How far the tabs reach up from the bottom of the cut. Lasers leave tabs standing at full height.
+    #[serde(deserialize_with = "parse_quantity")]
+    pub height: Length<uom::si::SI<f64>, f64>,
+}
+
+impl HoldingTabs {
+    fn default_spacing() -> Option<Length<uom::si::SI<f64>, f64>> {
+        None
+    }
+}
+
+/// The line numbers of stages within the source of a forge file, so that problems can be pointed out.
+/// Only block style YAML is understood, which is how forge files are normally written.
+#[derive(Debug, Default)]
+pub struct StageLines {
+    stages: HashMap<(PathBuf, usize), StageLine>,
+}
+
+#[derive(Debug)]
+struct StageLine {
+    line: usize,
+    keys: HashMap<String, usize>,
+}
+
+impl StageLines {
+    pub fn locate(source: &str) -> Self {
+        let mut stages = HashMap::new();
+
+        let mut in_gcode_files = false;
+        let mut file_indent = None;
+        let mut current_file: Option<PathBuf> = None;
+        let mut current_stage: Option<usize> = None;
+
+        for (line_index, line) in source.lines().enumerate() {
+            let line_number = line_index + 1;
+
+            // Strip comments.
+            let content = match line.find('#') {
+                Some(0) => "",
+                Some(comment_start) if line[..comment_start].ends_with(char::is_whitespace) => {
+                    &line[..comment_start]
+                }
+                _ => line,
+            };
+
+            let trimmed = content.trim();
+            if trimmed.is_empty() {
+                continue;
+            }
+
+            let indent = content.len() - content.trim_start().len();
+
+            if indent == 0 {
+                in_gcode_files = trimmed == "gcode_files:";
+                file_indent = None;
+                current_file = None;
+                continue;
+            }
+
+            if !in_gcode_files {
+                continue;
+            }
+
+            // Sequences are allowed to have the same indentation as their key.
+            let is_stage = trimmed == "-" || trimmed.starts_with("- ");
+
+            if let (true, Some(file)) = (is_stage, current_file.as_ref()) {
+                let stage_index = current_stage.map_or(0, |stage_index| stage_index + 1);
+                current_stage = Some(stage_index);
+
+                stages.insert(
+                    (file.clone(), stage_index),
+                    StageLine {
+                        line: line_number,
+                        keys: HashMap::new(),
+                    },
+                );
+            } else if indent <= *file_indent.get_or_insert(indent) {
+                current_file = trimmed
+                    .strip_suffix(':')
+                    .map(|key| PathBuf::from(key.trim_matches(|c| c == '"' || c == '\'')));
+                current_stage = None;
+            } else if let (Some(file), Some(stage_index)) = (current_file.as_ref(), current_stage) {
+                if let (Some((key, _value)), Some(stage)) = (
+                    trimmed.split_once(':'),
+                    stages.get_mut(&(file.clone(), stage_index)),
+                ) {
+                    stage.keys.entry(key.to_string()).or_insert(line_number);
+                }
+            }
+        }
+
+        Self { stages }
+    }
+
+    /// The line a key of a stage is on, or the line the stage starts on if the key isn't written out.
+    pub fn key(&self, gcode_file: &Path, stage_index: usize, key: &str) -> Option<usize> {
+        self.stages
+            .get(&(gcode_file.to_path_buf(), stage_index))
+            .map(|stage| stage.keys.get(key).copied().unwrap_or(stage.line))
+    }
+}
@@ -0,0 +1,381 @@
+//! Different machine firmwares understand slightly different flavors of GCode.
+//! Everything that differs between them is provided by a [Dialect], selected by the `dialect` field of a machine's config.
+
+use std::fmt::{Result, Write};
+
+use serde::Deserialize;
+use uom::si::{
+    angular_velocity::{revolution_per_minute, AngularVelocity},
+    ratio::ratio,
+    time::{millisecond, second, Time},
+    velocity::{inch_per_second, millimeter_per_minute, millimeter_per_second, Velocity},
+};
+
+use crate::parsing::UnitMode;
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum GCodeDialect {
+    /// The flavor PCB Forge has always produced, as understood by Snapmaker machines.
+    /// Feed rates are written as the numeric value of the configured speed per second, so existing configs keep producing the same output.
+    #[default]
+    #[serde(rename = "snapmaker")]
+    Snapmaker,
+
+    #[serde(rename = "marlin")]
+    Marlin,
+
+    #[serde(rename = "grbl")]
+    Grbl,
+
+    #[serde(rename = "linuxcnc")]
+    LinuxCnc,
+
+    #[serde(rename = "smoothieware")]
+    Smoothieware,
+}
+
+impl GCodeDialect {
+    pub fn backend(&self) -> &'static dyn Dialect {
+        match self {
+            GCodeDialect::Snapmaker => &Snapmaker,
+            GCodeDialect::Marlin => &Marlin,
+            GCodeDialect::Grbl => &Grbl,
+            GCodeDialect::LinuxCnc => &LinuxCnc,
+            GCodeDialect::Smoothieware => &Smoothieware,
+        }
+    }
+}
+
+impl std::fmt::Display for GCodeDialect {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GCodeDialect::Snapmaker => write!(f, "snapmaker"),
+            GCodeDialect::Marlin => write!(f, "marlin"),
+            GCodeDialect::Grbl => write!(f, "grbl"),
+            GCodeDialect::LinuxCnc => write!(f, "linuxcnc"),
+            GCodeDialect::Smoothieware => write!(f, "smoothieware"),
+        }
+    }
+}
+
+/// Writes the parts of GCode that differ between firmwares.
+/// Anything not covered here (G0, G1, G2, G3, G90) is common to all of them.
+pub trait Dialect {
+    /// Written at the very start of a program.
+    fn program_start(&self, output: &mut String) -> Result {
+        writeln!(output, "G90")
+    }
+
+    /// Written at the very end of a program.
+    fn program_end(&self, _output: &mut String) -> Result {
+        Ok(())
+    }
+
+    fn comment(&self, output: &mut String, comment: &str) -> Result {
+        for line in comment.lines() {
+            writeln!(output, "; {}", line)?;
+        }
+
+        Ok(())
+    }
+
+    fn unit_mode(&self, output: &mut String, unit_mode: UnitMode) -> Result {
+        match unit_mode {
+            UnitMode::Metric => writeln!(output, "G21"),
+            UnitMode::Imperial => writeln!(output, "G20"),
+        }
+    }
+
+    /// The value to use for an F word.
+    fn feed_rate(&self, speed: Velocity<uom::si::SI<f64>, f64>, unit_mode: UnitMode) -> f64 {
+        match unit_mode {
+            UnitMode::Metric => speed.get::<millimeter_per_minute>(),
+            UnitMode::Imperial => speed.get::<inch_per_second>() * 60.0,
+        }
+    }
+
+    /// The speed the machine actually moves at when asked to move at `speed`.
+    /// Firmwares read F words as a distance per minute, which isn't what every dialect writes.
+    fn machine_speed(
+        &self,
+        speed: Velocity<uom::si::SI<f64>, f64>,
+    ) -> Velocity<uom::si::SI<f64>, f64> {
+        Velocity::new::<millimeter_per_minute>(self.feed_rate(speed, UnitMode::Metric))
+    }
+
+    /// Sets the speed used by rapid (G0) moves.
+    /// Most firmwares always make rapid moves at the machine's maximum speed, so this does nothing by default.
+    fn rapid_speed(
+        &self,
+        _output: &mut String,
+        _speed: Velocity<uom::si::SI<f64>, f64>,
+        _unit_mode: UnitMode,
+    ) -> Result {
+        Ok(())
+    }
+
+    /// Called when the laser power is changed. The laser must be left off.
+    fn laser_power(&self, _output: &mut String, _power_ratio: f64) -> Result {
+        Ok(())
+    }
+
+    /// Turns the laser on. The power is a ratio of the laser's maximum power.
+    fn laser_on(&self, output: &mut String, power_ratio: f64) -> Result;
+
+    fn laser_off(&self, output: &mut String) -> Result {
+        writeln!(output, "M5")
+    }
+
+    /// Starts the spindle. Negative speeds spin counter clockwise.
+    fn spindle_speed(
+        &self,
+        output: &mut String,
+        speed: AngularVelocity<uom::si::SI<f64>, f64>,
+        _max_speed: AngularVelocity<uom::si::SI<f64>, f64>,
+    ) -> Result {
+        let rpm = speed.get::<revolution_per_minute>();
+
+        if rpm.is_sign_positive() {
+            writeln!(output, "M3 S{}", rpm)
+        } else {
+            writeln!(output, "M4 S{}", rpm.abs())
+        }
+    }
+
+    fn spindle_stop(&self, output: &mut String) -> Result {
+        writeln!(output, "M5")
+    }
+
+    /// Stops the program until the operator resumes it.
+    fn pause(&self, output: &mut String, message: &str) -> Result {
+        self.comment(output, message)?;
+        writeln!(output, "M0")
+    }
+
+    /// Has the machine change to a tool from its tool table.
+    fn tool_change(&self, output: &mut String, tool_number: usize, message: &str) -> Result {
+        self.comment(output, message)?;
+        writeln!(output, "T{} M6", tool_number)
+    }
+
+    fn dwell(&self, output: &mut String, duration: Time<uom::si::SI<f64>, f64>) -> Result {
+        writeln!(output, "G4 P{}", duration.get::<second>())
+    }
+
+    /// Called before probing the height of the board.
+    /// Most firmwares only report probe results to the host, so the operator is told where to save them by default.
+    fn probe_log_start(&self, output: &mut String, file_name: &str) -> Result {
+        self.comment(
+            output,
+            &format!(
+                "Save the results of the following probes to {} as X Y Z lines.",
+                file_name
+            ),
+        )
+    }
+
+    fn probe_log_end(&self, _output: &mut String) -> Result {
+        Ok(())
+    }
+
+    /// If the firmware has the G81 and G83 drilling cycles. Drilling is written out as plain Z moves otherwise.
+    fn supports_canned_drilling(&self) -> bool {
+        false
+    }
+}
+
+/// Marlin and Snapmaker firmware take their power as a PWM value.
+fn write_pwm(output: &mut String, command: &str, power_ratio: f64) -> Result {
+    writeln!(
+        output,
+        "{} S{}",
+        command,
+        (255.0 * power_ratio.abs()) as usize
+    )
+}
+
+pub struct Snapmaker;
+
+impl Dialect for Snapmaker {
+    fn pause(&self, output: &mut String, message: &str) -> Result {
+        // The message is shown on the machine's display.
+        writeln!(output, "M0 {}", message)
+    }
+
+    fn feed_rate(&self, speed: Velocity<uom::si::SI<f64>, f64>, unit_mode: UnitMode) -> f64 {
+        match unit_mode {
+            UnitMode::Metric => speed.get::<millimeter_per_second>(),
+            UnitMode::Imperial => speed.get::<inch_per_second>(),
+        }
+    }
+
+    fn rapid_speed(
+        &self,
+        output: &mut String,
+        speed: Velocity<uom::si::SI<f64>, f64>,
+        unit_mode: UnitMode,
+    ) -> Result {
+        writeln!(output, "G0 F{}", self.feed_rate(speed, unit_mode))
+    }
+
+    fn laser_power(&self, output: &mut String, power_ratio: f64) -> Result {
+        let percentage = (100.0 * power_ratio) as usize;
+        let pwm_scale = (255.0 * power_ratio) as usize;
+
+        writeln!(output, "M3 P{} S{}", percentage, pwm_scale)?;
+        writeln!(output, "M5") // Don't power on the laser just yet.
+    }
+
+    fn laser_on(&self, output: &mut String, _power_ratio: f64) -> Result {
+        // The power was already set, so the laser just needs to be turned back on.
+        writeln!(output, "M3")
+    }
+
+    fn spindle_speed(
+        &self,
+        output: &mut String,
+        speed: AngularVelocity<uom::si::SI<f64>, f64>,
+        max_speed: AngularVelocity<uom::si::SI<f64>, f64>,
+    ) -> Result {
+        let power_ratio = (speed / max_speed).get::<ratio>();
+        let percentage = (100.0 * power_ratio.abs()) as usize;
+        let pwm_scale = (255.0 * power_ratio.abs()) as usize;
+
+        if power_ratio.is_sign_positive() {
+            writeln!(output, "M3 P{} S{}", percentage, pwm_scale)
+        } else {
+            writeln!(output, "M4 P{} S{}", percentage, pwm_scale)
+        }
+    }
+
+    fn dwell(&self, output: &mut String, duration: Time<uom::si::SI<f64>, f64>) -> Result {
+        writeln!(output, "G4 P{}", duration.get::<millisecond>())
+    }
+}
+
+pub struct Marlin;
+
+impl Dialect for Marlin {
+    fn pause(&self, output: &mut String, message: &str) -> Result {
+        // The message is shown on the machine's display.
+        writeln!(output, "M0 {}", message)
+    }
+
+    fn rapid_speed(
+        &self,
+        output: &mut String,
+        speed: Velocity<uom::si::SI<f64>, f64>,
+        unit_mode: UnitMode,
+    ) -> Result {
+        writeln!(output, "G0 F{}", self.feed_rate(speed, unit_mode))
+    }
+
+    fn laser_on(&self, output: &mut String, power_ratio: f64) -> Result {
+        // Marlin uses full power if no power is given.
+        write_pwm(output, "M3", power_ratio)
+    }
+
+    fn spindle_speed(
+        &self,
+        output: &mut String,
+        speed: AngularVelocity<uom::si::SI<f64>, f64>,
+        max_speed: AngularVelocity<uom::si::SI<f64>, f64>,
+    ) -> Result {
+        let power_ratio = (speed / max_speed).get::<ratio>();
+
+        if power_ratio.is_sign_positive() {
+            write_pwm(output, "M3", power_ratio)
+        } else {
+            write_pwm(output, "M4", power_ratio)
+        }
+    }
+
+    fn dwell(&self, output: &mut String, duration: Time<uom::si::SI<f64>, f64>) -> Result {
+        writeln!(output, "G4 P{}", duration.get::<millisecond>())
+    }
+}
+
+pub struct Grbl;
+
+impl Dialect for Grbl {
+    fn program_start(&self, output: &mut String) -> Result {
+        writeln!(output, "G17 G90 G94")
+    }
+
+    fn program_end(&self, output: &mut String) -> Result {
+        writeln!(output, "M2")
+    }
+
+    fn laser_on(&self, output: &mut String, power_ratio: f64) -> Result {
+        // GRBL's default maximum spindle value ($30) is 1000.
+        // M4 is used so the power scales with the speed, which avoids burning corners.
+        writeln!(output, "M4 S{}", (1000.0 * power_ratio) as usize)
+    }
+}
+
+pub struct LinuxCnc;
+
+impl Dialect for LinuxCnc {
+    fn program_start(&self, output: &mut String) -> Result {
+        writeln!(output, "G17 G90 G94")
+    }
+
+    fn program_end(&self, output: &mut String) -> Result {
+        writeln!(output, "M2")
+    }
+
+    fn comment(&self, output: &mut String, comment: &str) -> Result {
+        // Comments can't contain parentheses.
+        for line in comment.lines() {
+            writeln!(output, "({})", line.replace(['(', ')'], ""))?;
+        }
+
+        Ok(())
+    }
+
+    fn supports_canned_drilling(&self) -> bool {
+        true
+    }
+
+    fn probe_log_start(&self, output: &mut String, file_name: &str) -> Result {
+        // Every probe result is written to the file, one point per line.
+        writeln!(output, "(PROBEOPEN {})", file_name.replace(['(', ')'], ""))
+    }
+
+    fn probe_log_end(&self, output: &mut String) -> Result {
+        writeln!(output, "(PROBECLOSE)")
+    }
+
+    fn pause(&self, output: &mut String, message: &str) -> Result {
+        // MSG comments are shown to the operator.
+        writeln!(output, "(MSG, {})", message.replace(['(', ')'], ""))?;
+        writeln!(output, "M0")
+    }
+
+    fn laser_on(&self, output: &mut String, power_ratio: f64) -> Result {
+        // Lasers are usually driven through the spindle output, with the power given as a percentage.
+        writeln!(output, "M3 S{}", 100.0 * power_ratio)
+    }
+}
+
+pub struct Smoothieware;
+
+impl Dialect for Smoothieware {
+    fn rapid_speed(
+        &self,
+        output: &mut String,
+        speed: Velocity<uom::si::SI<f64>, f64>,
+        unit_mode: UnitMode,
+    ) -> Result {
+        writeln!(output, "G0 F{}", self.feed_rate(speed, unit_mode))
+    }
+
+    fn laser_on(&self, output: &mut String, power_ratio: f64) -> Result {
+        // The laser module takes its power as a fraction of the maximum power.
+        writeln!(output, "M3 S{}", power_ratio)
+    }
+
+    fn dwell(&self, output: &mut String, duration: Time<uom::si::SI<f64>, f64>) -> Result {
+        writeln!(output, "G4 P{}", duration.get::<millisecond>())
+    }
+}
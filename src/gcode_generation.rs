@@ -1,23 +1,28 @@
 //! Tools to generate GCode.
 //! Fantastic documentation of GCode commands can be found [here](https://marlinfw.org/meta/gcode/).
 
-use std::{fmt::Write, fs, path::PathBuf};
+use std::{fmt::Write, fs, path::PathBuf, rc::Rc};
 
 use anyhow::{bail, Context, Result};
 use geo::Coord;
+use nalgebra::{Isometry2, Point2, Vector2};
 use uom::{
     num_traits::Zero,
     si::{
         angular_velocity::AngularVelocity,
-        length::{mil, millimeter, Length},
+        length::{inch, millimeter, Length},
         power::Power,
         ratio::ratio,
-        velocity::{inch_per_second, millimeter_per_second, Velocity},
+        time::Time,
+        velocity::Velocity,
     },
 };
 
 use crate::{
-    config::machine::{JobConfig, LaserConfig, Machine, SpindleBit, SpindleConfig},
+    config::machine::{JobConfig, LaserConfig, Machine, SpindleBit, SpindleConfig, WorkspaceSize},
+    gcode_dialect::Dialect,
+    geometry::{arc_sweep, fit_arcs, Segment},
+    height_map::HeightMap,
     parsing::UnitMode,
 };
 
@@ -26,9 +31,11 @@ pub enum Tool {
     None,
     Laser {
         max_power: Power<uom::si::SI<f64>, f64>,
+        diameter: Length<uom::si::SI<f64>, f64>,
     },
     Spindle {
         max_spindle_speed: AngularVelocity<uom::si::SI<f64>, f64>,
+        diameter: Length<uom::si::SI<f64>, f64>,
         plunge_speed: Velocity<uom::si::SI<f64>, f64>,
         travel_height: Length<uom::si::SI<f64>, f64>,
         cut_depth: Length<uom::si::SI<f64>, f64>,
@@ -51,9 +58,45 @@ pub enum GCommand {
     MoveTo {
         target: (Length<uom::si::SI<f64>, f64>, Length<uom::si::SI<f64>, f64>),
     },
+    /// Plunge straight down to the cut depth and back up again.
+    Drill {
+        target: (Length<uom::si::SI<f64>, f64>, Length<uom::si::SI<f64>, f64>),
+
+        /// How deep to plunge before retracting to clear chips. The hole is drilled in one plunge if not set.
+        peck_depth: Option<Length<uom::si::SI<f64>, f64>>,
+
+        /// The height to retract to between pecks. Defaults to the travel height.
+        retract_height: Option<Length<uom::si::SI<f64>, f64>>,
+    },
+    /// Stops the spindle and has its bit replaced, either by the machine or by hand.
+    ToolChange {
+        /// The tool number for the machine to change to. The machine pauses for a manual change if not set.
+        tool_number: Option<usize>,
+        diameter: Length<uom::si::SI<f64>, f64>,
+        message: String,
+
+        /// Pause again after the change so the Z axis can be zeroed to the new bit.
+        rezero: bool,
+    },
+    /// Lowers the tool until it touches the board so that the machine records the height of the board there.
+    Probe {
+        target: (Length<uom::si::SI<f64>, f64>, Length<uom::si::SI<f64>, f64>),
+        search_depth: Length<uom::si::SI<f64>, f64>,
+        speed: Velocity<uom::si::SI<f64>, f64>,
+    },
+    /// Has the machine record the results of the following probes to a file, if it can.
+    StartProbeLog(String),
+    EndProbeLog,
+    /// Moves the cuts of the spindle up and down to follow the surface of the board. Cleared by passing none.
+    SetHeightMap(Option<Rc<HeightMap>>),
+    /// The cuts that follow cross a holding tab, which reaches up to the given height. Spindles rise to the top of the
+    /// tab if they are cutting below it, while lasers move over it without cutting. Cleared by passing none.
+    SetHoldingTab(Option<Length<uom::si::SI<f64>, f64>>),
     UnitMode(UnitMode),
     IncludeFile(PathBuf),
     SetSide(BoardSide),
+    Comment(String),
+    Dwell(Time<uom::si::SI<f64>, f64>),
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -65,6 +108,12 @@ pub enum BoardSide {
 #[derive(Debug, Clone)]
 pub enum MovementType {
     Linear,
+    ClockwiseCurve {
+        center: (Length<uom::si::SI<f64>, f64>, Length<uom::si::SI<f64>, f64>),
+    },
+    CounterClockwiseCurve {
+        center: (Length<uom::si::SI<f64>, f64>, Length<uom::si::SI<f64>, f64>),
+    },
 }
 
 pub struct GCodeFile {
@@ -72,23 +121,28 @@ pub struct GCodeFile {
 }
 
 impl GCodeFile {
-    pub fn to_string(&self, x_offset: Length<uom::si::SI<f64>, f64>) -> Result<String> {
+    pub fn to_string(
+        &self,
+        x_offset: Length<uom::si::SI<f64>, f64>,
+        dialect: &dyn Dialect,
+    ) -> Result<String> {
         let mut unit_mode = UnitMode::Metric;
         let mut board_side = BoardSide::Front;
         let mut tool_is_ready_to_cut = false;
         let mut work_speed = Velocity::zero();
+        let mut laser_power_ratio = 0.0;
+        let mut height_map: Option<Rc<HeightMap>> = None;
+        let mut holding_tab: Option<Length<uom::si::SI<f64>, f64>> = None;
 
-        let x_offset = match unit_mode {
-            UnitMode::Metric => x_offset.get::<millimeter>(),
-            UnitMode::Imperial => x_offset.get::<mil>(),
-        };
+        // The depth the spindle is cutting at, before any height map is applied.
+        let mut cut_height = Length::<uom::si::SI<f64>, f64>::zero();
 
         let mut tool = Tool::None;
 
         let mut output = String::default();
 
         // Put the machine into absolute mode.
-        writeln!(&mut output, "G90")?;
+        dialect.program_start(&mut output)?;
 
         // Move the X-Y axis to the origin so we can lower with minimized risk of hitting a clamp
         // and be confident of our starting position.
@@ -105,14 +159,18 @@ impl GCodeFile {
                     // Disengage the tool.
                     match tool {
                         Tool::None => {} // Nothing needs to be done.
-                        Tool::Laser { max_power: _ } => {
+                        Tool::Laser {
+                            max_power: _,
+                            diameter: _,
+                        } => {
                             if tool_is_ready_to_cut {
-                                writeln!(&mut output, "M5")?;
+                                dialect.laser_off(&mut output)?;
                                 tool_is_ready_to_cut = false;
                             }
                         }
                         Tool::Spindle {
                             max_spindle_speed: _,
+                            diameter: _,
                             travel_height,
                             cut_depth: _,
                             pass_depth: _,
@@ -122,10 +180,7 @@ impl GCodeFile {
                                 writeln!(
                                     &mut output,
                                     "G0 Z{}",
-                                    match unit_mode {
-                                        UnitMode::Metric => travel_height.get::<millimeter>(),
-                                        UnitMode::Imperial => travel_height.get::<mil>(),
-                                    }
+                                    length_value(travel_height, unit_mode)
                                 )?;
                                 tool_is_ready_to_cut = false;
                             }
@@ -137,12 +192,16 @@ impl GCodeFile {
                     // Make sure that tool is still disengaged.
                     match tool {
                         Tool::None => {} // Nothing needs to be done.
-                        Tool::Laser { max_power: _ } => {
-                            writeln!(&mut output, "M5")?;
+                        Tool::Laser {
+                            max_power: _,
+                            diameter: _,
+                        } => {
+                            dialect.laser_off(&mut output)?;
                             tool_is_ready_to_cut = false;
                         }
                         Tool::Spindle {
                             max_spindle_speed: _,
+                            diameter: _,
                             travel_height,
                             cut_depth: _,
                             pass_depth: _,
@@ -151,10 +210,7 @@ impl GCodeFile {
                             writeln!(
                                 &mut output,
                                 "G0 Z{}",
-                                match unit_mode {
-                                    UnitMode::Metric => travel_height.get::<millimeter>(),
-                                    UnitMode::Imperial => travel_height.get::<mil>(),
-                                }
+                                length_value(travel_height, unit_mode)
                             )?;
                             tool_is_ready_to_cut = false;
                         }
@@ -162,34 +218,23 @@ impl GCodeFile {
 
                     Ok(())
                 }
-                GCommand::SetRapidTransverseSpeed(speed) => writeln!(
-                    &mut output,
-                    "G0 F{}",
-                    match unit_mode {
-                        UnitMode::Metric => speed.get::<millimeter_per_second>(),
-                        UnitMode::Imperial => speed.get::<inch_per_second>(),
-                    }
-                ),
+                GCommand::SetRapidTransverseSpeed(speed) => {
+                    dialect.rapid_speed(&mut output, *speed, unit_mode)
+                }
                 GCommand::SetWorkSpeed(speed) => {
                     work_speed = *speed;
-                    writeln!(
-                        &mut output,
-                        "G1 F{}",
-                        match unit_mode {
-                            UnitMode::Metric => speed.get::<millimeter_per_second>(),
-                            UnitMode::Imperial => speed.get::<inch_per_second>(),
-                        }
-                    )
+                    writeln!(&mut output, "G1 F{}", dialect.feed_rate(*speed, unit_mode))
                 }
                 GCommand::SetPower(power) => {
-                    if let Tool::Laser { max_power } = &tool {
-                        let power_ratio = *power / *max_power;
-                        let percentage = (100.0 * power_ratio.get::<ratio>()) as usize;
-                        let pwm_scale = (255.0 * power_ratio.get::<ratio>()) as usize;
+                    if let Tool::Laser {
+                        max_power,
+                        diameter: _,
+                    } = &tool
+                    {
+                        laser_power_ratio = (*power / *max_power).get::<ratio>();
 
                         tool_is_ready_to_cut = false;
-                        writeln!(&mut output, "M3 P{} S{}", percentage, pwm_scale)?;
-                        writeln!(&mut output, "M5") // Don't power on the laser just yet.
+                        dialect.laser_power(&mut output, laser_power_ratio)
                     } else {
                         bail!("Attempt to set power of non-laser tool.");
                     }
@@ -197,23 +242,16 @@ impl GCodeFile {
                 GCommand::SetSpindleSpeed(speed) => {
                     if let Tool::Spindle {
                         max_spindle_speed,
+                        diameter: _,
                         travel_height: _,
                         cut_depth: _,
                         pass_depth: _,
                         plunge_speed: _,
                     } = &tool
                     {
-                        let power_ratio = *speed / *max_spindle_speed;
-                        let percentage = (100.0 * power_ratio.get::<ratio>().abs()) as usize;
-                        let pwm_scale = (255.0 * power_ratio.get::<ratio>().abs()) as usize;
-
                         // Note that we let the tool start spinning immediately.
                         tool_is_ready_to_cut = false;
-                        if power_ratio.is_sign_positive() {
-                            writeln!(&mut output, "M3 P{} S{}", percentage, pwm_scale)
-                        } else {
-                            writeln!(&mut output, "M4 P{} S{}", percentage, pwm_scale)
-                        }
+                        dialect.spindle_speed(&mut output, *speed, *max_spindle_speed)
                     } else {
                         bail!("Attempt to set speed of non-spindle tool.");
                     }
@@ -225,14 +263,35 @@ impl GCodeFile {
                 } => {
                     match tool {
                         Tool::None => bail!("No tool is equipped."),
-                        Tool::Laser { max_power: _ } => {
+                        Tool::Laser {
+                            max_power: _,
+                            diameter: _,
+                        } if holding_tab.is_some() => {
+                            // The laser was turned off when the tab started, so it just moves over it.
+                            position = (*x, *y);
+
+                            let (x, y) = (length_value(*x, unit_mode), length_value(*y, unit_mode));
+
+                            let x = match board_side {
+                                BoardSide::Front => x,
+                                BoardSide::Back => -x + length_value(x_offset, unit_mode),
+                            };
+
+                            writeln!(&mut output, "G0 X{} Y{}", x, y)?;
+                            continue;
+                        }
+                        Tool::Laser {
+                            max_power: _,
+                            diameter: _,
+                        } => {
                             if !tool_is_ready_to_cut {
-                                writeln!(&mut output, "M3")?;
+                                dialect.laser_on(&mut output, laser_power_ratio)?;
                                 tool_is_ready_to_cut = true;
                             }
                         }
                         Tool::Spindle {
                             max_spindle_speed: _,
+                            diameter: _,
                             travel_height,
                             cut_depth,
                             pass_depth,
@@ -243,61 +302,130 @@ impl GCodeFile {
                                     travel_height - pass_depth * *pass_index as f64
                                 });
 
+                                cut_height = target_depth;
+
                                 writeln!(
                                     &mut output,
                                     "G1 Z{} F{}",
-                                    match unit_mode {
-                                        UnitMode::Metric => target_depth.get::<millimeter>(),
-                                        UnitMode::Imperial => target_depth.get::<mil>(),
-                                    },
-                                    match unit_mode {
-                                        UnitMode::Metric =>
-                                            plunge_speed.get::<millimeter_per_second>(),
-                                        UnitMode::Imperial => plunge_speed.get::<inch_per_second>(),
-                                    }
+                                    length_value(
+                                        tool_height(target_depth, holding_tab)
+                                            + height_compensation(
+                                                height_map.as_deref(),
+                                                board_side,
+                                                x_offset,
+                                                position.0,
+                                                position.1
+                                            ),
+                                        unit_mode
+                                    ),
+                                    dialect.feed_rate(plunge_speed, unit_mode)
                                 )?;
                                 writeln!(
                                     &mut output,
                                     "G1 F{}",
-                                    match unit_mode {
-                                        UnitMode::Metric =>
-                                            work_speed.get::<millimeter_per_second>(),
-                                        UnitMode::Imperial => work_speed.get::<inch_per_second>(),
-                                    }
+                                    dialect.feed_rate(work_speed, unit_mode)
                                 )?;
                                 tool_is_ready_to_cut = true;
                             }
                         }
                     }
 
+                    let start = position;
                     position = (*x, *y);
 
-                    let (x, y) = match unit_mode {
-                        UnitMode::Metric => (x.get::<millimeter>(), y.get::<millimeter>()),
-                        UnitMode::Imperial => (x.get::<mil>(), y.get::<mil>()),
+                    // Cuts follow the height map, if there is one.
+                    let z_word = match (&height_map, tool) {
+                        (Some(height_map), Tool::Spindle { .. }) => format!(
+                            " Z{}",
+                            length_value(
+                                tool_height(cut_height, holding_tab)
+                                    + height_compensation(
+                                        Some(height_map),
+                                        board_side,
+                                        x_offset,
+                                        *x,
+                                        *y
+                                    ),
+                                unit_mode
+                            )
+                        ),
+                        _ => String::new(),
                     };
 
+                    let (x, y) = (length_value(*x, unit_mode), length_value(*y, unit_mode));
+
                     let x = match board_side {
                         BoardSide::Front => x,
-                        BoardSide::Back => -x + x_offset,
+                        BoardSide::Back => -x + length_value(x_offset, unit_mode),
+                    };
+
+                    // Arc centers are given relative to the start of the arc.
+                    let center_offset =
+                        |center_x: Length<uom::si::SI<f64>, f64>,
+                         center_y: Length<uom::si::SI<f64>, f64>| {
+                            let (i, j) = (
+                                length_value(center_x - start.0, unit_mode),
+                                length_value(center_y - start.1, unit_mode),
+                            );
+
+                            match board_side {
+                                BoardSide::Front => (i, j),
+                                BoardSide::Back => (-i, j),
+                            }
+                        };
+
+                    // Mirroring the board also reverses the direction of arcs.
+                    let clockwise_code = match board_side {
+                        BoardSide::Front => "G2",
+                        BoardSide::Back => "G3",
+                    };
+                    let counter_clockwise_code = match board_side {
+                        BoardSide::Front => "G3",
+                        BoardSide::Back => "G2",
                     };
 
                     match movement {
-                        MovementType::Linear => writeln!(&mut output, "G1 X{} Y{}", x, y),
+                        MovementType::Linear => {
+                            writeln!(&mut output, "G1 X{} Y{}{}", x, y, z_word)
+                        }
+                        MovementType::ClockwiseCurve {
+                            center: (center_x, center_y),
+                        } => {
+                            let (i, j) = center_offset(*center_x, *center_y);
+                            writeln!(
+                                &mut output,
+                                "{} X{} Y{}{} I{} J{}",
+                                clockwise_code, x, y, z_word, i, j
+                            )
+                        }
+                        MovementType::CounterClockwiseCurve {
+                            center: (center_x, center_y),
+                        } => {
+                            let (i, j) = center_offset(*center_x, *center_y);
+                            writeln!(
+                                &mut output,
+                                "{} X{} Y{}{} I{} J{}",
+                                counter_clockwise_code, x, y, z_word, i, j
+                            )
+                        }
                     }
                 }
                 GCommand::MoveTo { target: (x, y) } => {
                     if position != (*x, *y) {
                         match tool {
                             Tool::None => bail!("No tool is equipped."),
-                            Tool::Laser { max_power: _ } => {
+                            Tool::Laser {
+                                max_power: _,
+                                diameter: _,
+                            } => {
                                 if tool_is_ready_to_cut {
-                                    writeln!(&mut output, "M5")?;
+                                    dialect.laser_off(&mut output)?;
                                     tool_is_ready_to_cut = false;
                                 }
                             }
                             Tool::Spindle {
                                 max_spindle_speed: _,
+                                diameter: _,
                                 travel_height,
                                 cut_depth: _,
                                 pass_depth: _,
@@ -307,10 +435,7 @@ impl GCodeFile {
                                     writeln!(
                                         &mut output,
                                         "G0 Z{}",
-                                        match unit_mode {
-                                            UnitMode::Metric => travel_height.get::<millimeter>(),
-                                            UnitMode::Imperial => travel_height.get::<mil>(),
-                                        }
+                                        length_value(travel_height, unit_mode)
                                     )?;
                                     tool_is_ready_to_cut = false;
                                 }
@@ -319,14 +444,11 @@ impl GCodeFile {
 
                         position = (*x, *y);
 
-                        let (x, y) = match unit_mode {
-                            UnitMode::Metric => (x.get::<millimeter>(), y.get::<millimeter>()),
-                            UnitMode::Imperial => (x.get::<mil>(), y.get::<mil>()),
-                        };
+                        let (x, y) = (length_value(*x, unit_mode), length_value(*y, unit_mode));
 
                         let x = match board_side {
                             BoardSide::Front => x,
-                            BoardSide::Back => -x + x_offset,
+                            BoardSide::Back => -x + length_value(x_offset, unit_mode),
                         };
 
                         writeln!(&mut output, "G0 X{} Y{}", x, y)
@@ -336,12 +458,274 @@ impl GCodeFile {
                         Ok(())
                     }
                 }
+                GCommand::Drill {
+                    target: (x, y),
+                    peck_depth,
+                    retract_height,
+                } => {
+                    if let Tool::Spindle {
+                        max_spindle_speed: _,
+                        diameter: _,
+                        travel_height,
+                        cut_depth,
+                        pass_depth: _,
+                        plunge_speed,
+                    } = tool
+                    {
+                        if tool_is_ready_to_cut {
+                            writeln!(
+                                &mut output,
+                                "G0 Z{}",
+                                length_value(travel_height, unit_mode)
+                            )?;
+                            tool_is_ready_to_cut = false;
+                        }
+
+                        position = (*x, *y);
+
+                        let cut_depth = cut_depth
+                            + height_compensation(
+                                height_map.as_deref(),
+                                board_side,
+                                x_offset,
+                                *x,
+                                *y,
+                            );
+
+                        let (x, y) = (length_value(*x, unit_mode), length_value(*y, unit_mode));
+
+                        let x = match board_side {
+                            BoardSide::Front => x,
+                            BoardSide::Back => -x + length_value(x_offset, unit_mode),
+                        };
+
+                        let retract_height = retract_height.unwrap_or(travel_height);
+
+                        writeln!(&mut output, "G0 X{} Y{}", x, y)?;
+
+                        if dialect.supports_canned_drilling() {
+                            // G98 returns the tool to the travel height once the hole is done.
+                            match peck_depth {
+                                Some(peck_depth) => writeln!(
+                                    &mut output,
+                                    "G98 G83 X{} Y{} Z{} R{} Q{} F{}",
+                                    x,
+                                    y,
+                                    length_value(cut_depth, unit_mode),
+                                    length_value(retract_height, unit_mode),
+                                    length_value(*peck_depth, unit_mode),
+                                    dialect.feed_rate(plunge_speed, unit_mode)
+                                )?,
+                                None => writeln!(
+                                    &mut output,
+                                    "G98 G81 X{} Y{} Z{} R{} F{}",
+                                    x,
+                                    y,
+                                    length_value(cut_depth, unit_mode),
+                                    length_value(retract_height, unit_mode),
+                                    dialect.feed_rate(plunge_speed, unit_mode)
+                                )?,
+                            }
+                            writeln!(&mut output, "G80")?;
+                        } else {
+                            writeln!(
+                                &mut output,
+                                "G0 Z{}",
+                                length_value(retract_height, unit_mode)
+                            )?;
+
+                            let mut depth = retract_height;
+                            while depth > cut_depth {
+                                depth = peck_depth
+                                    .map_or(cut_depth, |peck_depth| depth - peck_depth)
+                                    .max(cut_depth);
+
+                                writeln!(
+                                    &mut output,
+                                    "G1 Z{} F{}",
+                                    length_value(depth, unit_mode),
+                                    dialect.feed_rate(plunge_speed, unit_mode)
+                                )?;
+                                writeln!(
+                                    &mut output,
+                                    "G0 Z{}",
+                                    length_value(retract_height, unit_mode)
+                                )?;
+                            }
+
+                            writeln!(
+                                &mut output,
+                                "G0 Z{}",
+                                length_value(travel_height, unit_mode)
+                            )?;
+                        }
+
+                        // The plunges changed the feed rate.
+                        writeln!(
+                            &mut output,
+                            "G1 F{}",
+                            dialect.feed_rate(work_speed, unit_mode)
+                        )
+                    } else {
+                        bail!("Attempt to drill with non-spindle tool.");
+                    }
+                }
+                GCommand::ToolChange {
+                    tool_number,
+                    diameter: _,
+                    message,
+                    rezero,
+                } => {
+                    if let Tool::Spindle {
+                        max_spindle_speed: _,
+                        diameter: _,
+                        travel_height,
+                        cut_depth: _,
+                        pass_depth: _,
+                        plunge_speed: _,
+                    } = tool
+                    {
+                        if tool_is_ready_to_cut {
+                            writeln!(
+                                &mut output,
+                                "G0 Z{}",
+                                length_value(travel_height, unit_mode)
+                            )?;
+                            tool_is_ready_to_cut = false;
+                        }
+
+                        dialect.spindle_stop(&mut output)?;
+
+                        match tool_number {
+                            Some(tool_number) => {
+                                dialect.tool_change(&mut output, *tool_number, message)?
+                            }
+                            None => dialect.pause(&mut output, message)?,
+                        }
+
+                        if *rezero {
+                            dialect
+                                .pause(&mut output, "Zero the Z axis to the tip of the new bit")?;
+                        }
+
+                        Ok(())
+                    } else {
+                        bail!("Attempt to change the bit of a non-spindle tool.");
+                    }
+                }
+                GCommand::Probe {
+                    target: (x, y),
+                    search_depth,
+                    speed,
+                } => {
+                    if let Tool::Spindle {
+                        max_spindle_speed: _,
+                        diameter: _,
+                        travel_height,
+                        cut_depth: _,
+                        pass_depth: _,
+                        plunge_speed: _,
+                    } = tool
+                    {
+                        if tool_is_ready_to_cut {
+                            writeln!(
+                                &mut output,
+                                "G0 Z{}",
+                                length_value(travel_height, unit_mode)
+                            )?;
+                            tool_is_ready_to_cut = false;
+                        }
+
+                        position = (*x, *y);
+
+                        let (x, y) = (length_value(*x, unit_mode), length_value(*y, unit_mode));
+
+                        let x = match board_side {
+                            BoardSide::Front => x,
+                            BoardSide::Back => -x + length_value(x_offset, unit_mode),
+                        };
+
+                        writeln!(&mut output, "G0 X{} Y{}", x, y)?;
+                        writeln!(
+                            &mut output,
+                            "G38.2 Z{} F{}",
+                            length_value(*search_depth, unit_mode),
+                            dialect.feed_rate(*speed, unit_mode)
+                        )?;
+                        writeln!(
+                            &mut output,
+                            "G0 Z{}",
+                            length_value(travel_height, unit_mode)
+                        )
+                    } else {
+                        bail!("Attempt to probe with non-spindle tool.");
+                    }
+                }
+                GCommand::StartProbeLog(file_name) => {
+                    dialect.probe_log_start(&mut output, file_name)
+                }
+                GCommand::EndProbeLog => dialect.probe_log_end(&mut output),
+                GCommand::SetHeightMap(new_height_map) => {
+                    height_map = new_height_map.clone();
+                    Ok(())
+                }
+                GCommand::SetHoldingTab(new_holding_tab) => {
+                    let previous_height = tool_height(cut_height, holding_tab);
+                    holding_tab = *new_holding_tab;
+
+                    match tool {
+                        Tool::Laser {
+                            max_power: _,
+                            diameter: _,
+                        } if tool_is_ready_to_cut && holding_tab.is_some() => {
+                            tool_is_ready_to_cut = false;
+                            dialect.laser_off(&mut output)
+                        }
+                        Tool::Spindle {
+                            max_spindle_speed: _,
+                            diameter: _,
+                            travel_height: _,
+                            cut_depth: _,
+                            pass_depth: _,
+                            plunge_speed,
+                        } if tool_is_ready_to_cut
+                            && tool_height(cut_height, holding_tab) > previous_height =>
+                        {
+                            writeln!(
+                                &mut output,
+                                "G1 Z{} F{}",
+                                length_value(
+                                    tool_height(cut_height, holding_tab)
+                                        + height_compensation(
+                                            height_map.as_deref(),
+                                            board_side,
+                                            x_offset,
+                                            position.0,
+                                            position.1
+                                        ),
+                                    unit_mode
+                                ),
+                                dialect.feed_rate(plunge_speed, unit_mode)
+                            )?;
+                            writeln!(
+                                &mut output,
+                                "G1 F{}",
+                                dialect.feed_rate(work_speed, unit_mode)
+                            )
+                        }
+                        Tool::Spindle { .. }
+                            if tool_height(cut_height, holding_tab) < previous_height =>
+                        {
+                            // The next cut plunges back down, unless the tool moves away first.
+                            tool_is_ready_to_cut = false;
+                            Ok(())
+                        }
+                        _ => Ok(()),
+                    }
+                }
                 GCommand::UnitMode(new_mode) => {
                     unit_mode = *new_mode;
-                    match new_mode {
-                        UnitMode::Metric => writeln!(&mut output, "G21"),
-                        UnitMode::Imperial => writeln!(&mut output, "G22"),
-                    }
+                    dialect.unit_mode(&mut output, unit_mode)
                 }
                 GCommand::IncludeFile(file_path) => {
                     let file_content = fs::read_to_string(file_path)
@@ -360,17 +744,323 @@ impl GCodeFile {
                     board_side = *new_side;
                     Ok(())
                 }
+                GCommand::Comment(comment) => dialect.comment(&mut output, comment),
+                GCommand::Dwell(duration) => dialect.dwell(&mut output, *duration),
             }?;
         }
 
+        dialect.program_end(&mut output)?;
+
         Ok(output)
     }
 }
 
+/// A move that would take the tool outside of the machine's workspace.
+#[derive(Debug)]
+pub enum BoundsViolation {
+    Travel {
+        command_index: usize,
+        x: Length<uom::si::SI<f64>, f64>,
+        y: Length<uom::si::SI<f64>, f64>,
+    },
+    Height {
+        command_index: usize,
+        z: Length<uom::si::SI<f64>, f64>,
+    },
+}
+
+impl BoundsViolation {
+    pub fn command_index(&self) -> usize {
+        match self {
+            BoundsViolation::Travel {
+                command_index,
+                x: _,
+                y: _,
+            } => *command_index,
+            BoundsViolation::Height {
+                command_index,
+                z: _,
+            } => *command_index,
+        }
+    }
+}
+
+impl std::fmt::Display for BoundsViolation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BoundsViolation::Travel {
+                command_index: _,
+                x,
+                y,
+            } => write!(
+                f,
+                "X{} Y{} mm",
+                x.get::<millimeter>(),
+                y.get::<millimeter>()
+            ),
+            BoundsViolation::Height {
+                command_index: _,
+                z,
+            } => write!(f, "Z{} mm", z.get::<millimeter>()),
+        }
+    }
+}
+
+impl GCodeFile {
+    /// Finds every move that would leave the workspace of the machine.
+    /// Positions are checked as the machine will see them, after the back side of the board has been mirrored.
+    /// Heights are checked as written to the G-code, including holding tabs and height map compensation.
+    pub fn check_bounds(
+        &self,
+        x_offset: Length<uom::si::SI<f64>, f64>,
+        workspace: &WorkspaceSize,
+    ) -> Vec<BoundsViolation> {
+        let mut violations = Vec::new();
+
+        let mut board_side = BoardSide::Front;
+        let mut tool = Tool::None;
+        let mut height_map: Option<Rc<HeightMap>> = None;
+        let mut holding_tab: Option<Length<uom::si::SI<f64>, f64>> = None;
+        let mut position = (
+            Length::<uom::si::SI<f64>, f64>::zero(),
+            Length::<uom::si::SI<f64>, f64>::zero(),
+        );
+
+        let check_travel =
+            |command_index: usize,
+             board_side: BoardSide,
+             (x, y): (Length<uom::si::SI<f64>, f64>, Length<uom::si::SI<f64>, f64>)|
+             -> Option<BoundsViolation> {
+                let x = match board_side {
+                    BoardSide::Front => x,
+                    BoardSide::Back => x_offset - x,
+                };
+
+                let inside = x >= Length::<uom::si::SI<f64>, f64>::zero()
+                    && x <= workspace.width
+                    && y >= Length::<uom::si::SI<f64>, f64>::zero()
+                    && y <= workspace.height;
+
+                (!inside).then_some(BoundsViolation::Travel {
+                    command_index,
+                    x,
+                    y,
+                })
+            };
+
+        let check_height =
+            |command_index: usize, z: Length<uom::si::SI<f64>, f64>| -> Option<BoundsViolation> {
+                let outside = workspace
+                    .z_range
+                    .as_ref()
+                    .is_some_and(|z_range| z < z_range.min || z > z_range.max);
+
+                outside.then_some(BoundsViolation::Height { command_index, z })
+            };
+
+        for (command_index, command) in self.commands.iter().enumerate() {
+            match command {
+                GCommand::EquipTool(new_tool) => {
+                    tool = *new_tool;
+
+                    if let Tool::Spindle {
+                        max_spindle_speed: _,
+                        diameter: _,
+                        travel_height,
+                        cut_depth: _,
+                        pass_depth: _,
+                        plunge_speed: _,
+                    } = tool
+                    {
+                        violations.extend(check_height(command_index, travel_height));
+                    }
+                }
+                GCommand::Cut {
+                    pass_index,
+                    movement,
+                    target,
+                } => {
+                    if let Tool::Spindle {
+                        max_spindle_speed: _,
+                        diameter: _,
+                        travel_height,
+                        cut_depth,
+                        pass_depth,
+                        plunge_speed: _,
+                    } = tool
+                    {
+                        let target_depth = pass_depth.map_or(cut_depth, |pass_depth| {
+                            travel_height - pass_depth * *pass_index as f64
+                        });
+
+                        // The tool plunges at the start of the cut and follows the height map to its end.
+                        violations.extend(
+                            [position, *target]
+                                .into_iter()
+                                .map(|(x, y)| {
+                                    tool_height(target_depth, holding_tab)
+                                        + height_compensation(
+                                            height_map.as_deref(),
+                                            board_side,
+                                            x_offset,
+                                            x,
+                                            y,
+                                        )
+                                })
+                                .find_map(|z| check_height(command_index, z)),
+                        );
+                    }
+
+                    // Arcs can bulge out past both of their ends.
+                    let extremes = match movement {
+                        MovementType::Linear => Vec::new(),
+                        MovementType::ClockwiseCurve { center } => {
+                            arc_extremes(*target, *center, position)
+                        }
+                        MovementType::CounterClockwiseCurve { center } => {
+                            arc_extremes(position, *center, *target)
+                        }
+                    };
+
+                    // Only report the first point of a move that leaves the workspace.
+                    violations.extend(
+                        extremes
+                            .into_iter()
+                            .map(|point| {
+                                (
+                                    Length::new::<millimeter>(point.x),
+                                    Length::new::<millimeter>(point.y),
+                                )
+                            })
+                            .chain([*target])
+                            .find_map(|point| check_travel(command_index, board_side, point)),
+                    );
+
+                    position = *target;
+                }
+                GCommand::MoveTo { target } => {
+                    violations.extend(check_travel(command_index, board_side, *target));
+                    position = *target;
+                }
+                GCommand::Drill {
+                    target,
+                    peck_depth: _,
+                    retract_height,
+                } => {
+                    violations.extend(check_travel(command_index, board_side, *target));
+
+                    if let Tool::Spindle {
+                        max_spindle_speed: _,
+                        diameter: _,
+                        travel_height: _,
+                        cut_depth,
+                        pass_depth: _,
+                        plunge_speed: _,
+                    } = tool
+                    {
+                        let cut_depth = cut_depth
+                            + height_compensation(
+                                height_map.as_deref(),
+                                board_side,
+                                x_offset,
+                                target.0,
+                                target.1,
+                            );
+
+                        violations.extend(check_height(command_index, cut_depth));
+                        if let Some(retract_height) = retract_height {
+                            violations.extend(check_height(command_index, *retract_height));
+                        }
+                    }
+
+                    position = *target;
+                }
+                GCommand::Probe {
+                    target,
+                    search_depth,
+                    speed: _,
+                } => {
+                    violations.extend(check_travel(command_index, board_side, *target));
+                    violations.extend(check_height(command_index, *search_depth));
+                    position = *target;
+                }
+                GCommand::SetHeightMap(new_height_map) => height_map = new_height_map.clone(),
+                GCommand::SetHoldingTab(new_holding_tab) => holding_tab = *new_holding_tab,
+                GCommand::SetSide(new_side) => board_side = *new_side,
+                _ => {}
+            }
+        }
+
+        violations
+    }
+}
+
+/// The points, in millimeters, where a counter clockwise arc reaches its furthest along either axis.
+fn arc_extremes(
+    start: (Length<uom::si::SI<f64>, f64>, Length<uom::si::SI<f64>, f64>),
+    center: (Length<uom::si::SI<f64>, f64>, Length<uom::si::SI<f64>, f64>),
+    end: (Length<uom::si::SI<f64>, f64>, Length<uom::si::SI<f64>, f64>),
+) -> Vec<Vector2<f64>> {
+    let to_vector = |(x, y): (Length<uom::si::SI<f64>, f64>, Length<uom::si::SI<f64>, f64>)| {
+        Vector2::new(x.get::<millimeter>(), y.get::<millimeter>())
+    };
+
+    let (start, center, end) = (to_vector(start), to_vector(center), to_vector(end));
+    let radius = (start - center).norm();
+
+    let start_angle = (start.y - center.y).atan2(start.x - center.x);
+    let sweep = arc_sweep(start, center, end, false);
+
+    (0..4)
+        .map(|quadrant| quadrant as f64 * std::f64::consts::FRAC_PI_2)
+        .filter(|angle| (angle - start_angle).rem_euclid(std::f64::consts::TAU) <= sweep)
+        .map(|angle| center + Vector2::new(angle.cos(), angle.sin()) * radius)
+        .collect()
+}
+
+/// The height of a spindle cutting at a depth. Spindles don't cut into holding tabs.
+fn tool_height(
+    cut_height: Length<uom::si::SI<f64>, f64>,
+    holding_tab: Option<Length<uom::si::SI<f64>, f64>>,
+) -> Length<uom::si::SI<f64>, f64> {
+    holding_tab.map_or(cut_height, |tab_height| cut_height.max(tab_height))
+}
+
+/// How far a height map moves the tool at a position on the board.
+/// Height maps are probed by the machine, so the position is mirrored the same way the machine's moves are.
+fn height_compensation(
+    height_map: Option<&HeightMap>,
+    board_side: BoardSide,
+    x_offset: Length<uom::si::SI<f64>, f64>,
+    x: Length<uom::si::SI<f64>, f64>,
+    y: Length<uom::si::SI<f64>, f64>,
+) -> Length<uom::si::SI<f64>, f64> {
+    height_map.map_or(Length::zero(), |height_map| {
+        let x = match board_side {
+            BoardSide::Front => x,
+            BoardSide::Back => x_offset - x,
+        };
+
+        height_map.height_at(x, y)
+    })
+}
+
+/// The numeric value of a length in the units of the current unit mode.
+fn length_value(length: Length<uom::si::SI<f64>, f64>, unit_mode: UnitMode) -> f64 {
+    match unit_mode {
+        UnitMode::Metric => length.get::<millimeter>(),
+        UnitMode::Imperial => length.get::<inch>(),
+    }
+}
+
 impl GCodeFile {
     pub fn new(commands: Vec<GCommand>) -> Self {
         Self { commands }
     }
+
+    pub fn commands(&self) -> &[GCommand] {
+        &self.commands
+    }
 }
 
 pub enum ToolSelection<'a> {
@@ -387,9 +1077,7 @@ impl<'a> ToolSelection<'a> {
     pub fn diameter(&self) -> Length<uom::si::SI<f64>, f64> {
         match self {
             ToolSelection::Laser { laser } => laser.point_diameter,
-            ToolSelection::Spindle { spindle: _, bit } => match bit {
-                SpindleBit::EndMill { diameter } => *diameter,
-            },
+            ToolSelection::Spindle { spindle: _, bit } => bit.diameter(),
         }
     }
 
@@ -416,12 +1104,52 @@ pub struct GCodeConfig<'a> {
     pub include_file_search_directory: PathBuf,
 }
 
+/// Moves commands to where their board sits on the machine. Rotations don't change the direction of arcs.
+pub fn transform_commands(commands: &mut [GCommand], transform: &Isometry2<f64>) {
+    let apply = |x: &mut Length<uom::si::SI<f64>, f64>, y: &mut Length<uom::si::SI<f64>, f64>| {
+        let point =
+            transform.transform_point(&Point2::new(x.get::<millimeter>(), y.get::<millimeter>()));
+
+        *x = Length::new::<millimeter>(point.x);
+        *y = Length::new::<millimeter>(point.y);
+    };
+
+    for command in commands.iter_mut() {
+        match command {
+            GCommand::Cut {
+                pass_index: _,
+                movement,
+                target,
+            } => {
+                apply(&mut target.0, &mut target.1);
+
+                match movement {
+                    MovementType::Linear => {}
+                    MovementType::ClockwiseCurve { center }
+                    | MovementType::CounterClockwiseCurve { center } => {
+                        apply(&mut center.0, &mut center.1)
+                    }
+                }
+            }
+            GCommand::MoveTo { target }
+            | GCommand::Drill { target, .. }
+            | GCommand::Probe { target, .. } => apply(&mut target.0, &mut target.1),
+            _ => {}
+        }
+    }
+}
+
+/// Adds a string of points to be cut, replacing runs of points that follow an arc with an arc movement
+/// when they are within `arc_tolerance` of it.
 pub fn add_point_string_to_gcode_vector<'a>(
     commands: &mut Vec<GCommand>,
-    mut point_iter: impl Iterator<Item = &'a Coord<f64>>,
+    point_iter: impl Iterator<Item = &'a Coord<f64>>,
     pass_index: usize,
+    arc_tolerance: Length<uom::si::SI<f64>, f64>,
 ) {
-    if let Some(first_point) = point_iter.next() {
+    let points: Vec<Coord<f64>> = point_iter.copied().collect();
+
+    if let Some(first_point) = points.first() {
         commands.push(GCommand::MoveTo {
             target: (
                 Length::new::<millimeter>(first_point.x),
@@ -430,14 +1158,39 @@ pub fn add_point_string_to_gcode_vector<'a>(
         })
     }
 
-    for point in point_iter {
-        commands.push(GCommand::Cut {
-            pass_index,
-            movement: MovementType::Linear,
-            target: (
-                Length::new::<millimeter>(point.x),
-                Length::new::<millimeter>(point.y),
-            ),
-        })
+    for segment in fit_arcs(&points, arc_tolerance.get::<millimeter>()) {
+        commands.push(segment_to_cut(&segment, pass_index));
+    }
+}
+
+/// Creates a command to cut along a segment, starting from wherever the tool currently is.
+pub fn segment_to_cut(segment: &Segment, pass_index: usize) -> GCommand {
+    let to_length = |point: Vector2<f64>| {
+        (
+            Length::new::<millimeter>(point.x),
+            Length::new::<millimeter>(point.y),
+        )
+    };
+
+    let (end, movement) = match segment {
+        Segment::Line { end } => (end, MovementType::Linear),
+        Segment::ClockwiseCurve { end, center } => (
+            end,
+            MovementType::ClockwiseCurve {
+                center: to_length(*center),
+            },
+        ),
+        Segment::CounterClockwiseCurve { end, center } => (
+            end,
+            MovementType::CounterClockwiseCurve {
+                center: to_length(*center),
+            },
+        ),
+    };
+
+    GCommand::Cut {
+        pass_index,
+        movement,
+        target: to_length(*end),
     }
 }
@@ -0,0 +1,485 @@
+//! Estimates how long a GCode file takes to run by following the movements the machine will make.
+//! Every move is assumed to start and end at rest, speeding up and slowing down at the machine's acceleration limit.
+//! Time spent waiting on the operator can't be known, so pauses are counted instead.
+
+use std::{fmt::Display, iter::Sum, ops::AddAssign};
+
+use nalgebra::Vector2;
+use uom::{
+    num_traits::Zero,
+    si::{
+        acceleration::{millimeter_per_second_squared, Acceleration},
+        length::{millimeter, Length},
+        time::{second, Time},
+        velocity::millimeter_per_second,
+    },
+};
+
+use crate::{
+    gcode_dialect::Dialect,
+    gcode_generation::{GCodeFile, GCommand, MovementType, Tool},
+    geometry::arc_sweep,
+};
+
+/// What it takes to run a part of a GCode file.
+#[derive(Debug, Clone, Copy)]
+pub struct JobStatistics {
+    pub time: Time<uom::si::SI<f64>, f64>,
+
+    /// How far the tool moved across the board while cutting. Plunges are not included.
+    pub cut_length: Length<uom::si::SI<f64>, f64>,
+
+    /// How far the tool moved in rapid moves, including lifting it out of the board.
+    pub rapid_length: Length<uom::si::SI<f64>, f64>,
+
+    /// How many times a spindle went down into the board, counting each drilled hole once.
+    pub plunges: usize,
+
+    pub laser_on_time: Time<uom::si::SI<f64>, f64>,
+
+    /// How many times the machine waits for the operator, such as for a manual bit change.
+    pub pauses: usize,
+}
+
+impl Default for JobStatistics {
+    fn default() -> Self {
+        Self {
+            time: Time::zero(),
+            cut_length: Length::zero(),
+            rapid_length: Length::zero(),
+            plunges: 0,
+            laser_on_time: Time::zero(),
+            pauses: 0,
+        }
+    }
+}
+
+impl AddAssign for JobStatistics {
+    fn add_assign(&mut self, other: Self) {
+        self.time += other.time;
+        self.cut_length += other.cut_length;
+        self.rapid_length += other.rapid_length;
+        self.plunges += other.plunges;
+        self.laser_on_time += other.laser_on_time;
+        self.pauses += other.pauses;
+    }
+}
+
+impl<'a> Sum<&'a JobStatistics> for JobStatistics {
+    fn sum<I: Iterator<Item = &'a JobStatistics>>(iter: I) -> Self {
+        iter.fold(Self::default(), |mut total, statistics| {
+            total += *statistics;
+            total
+        })
+    }
+}
+
+impl Display for JobStatistics {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}, {:.1} mm cut, {:.1} mm rapid, {} plunges",
+            format_duration(self.time),
+            self.cut_length.get::<millimeter>(),
+            self.rapid_length.get::<millimeter>(),
+            self.plunges
+        )?;
+
+        if self.laser_on_time > Time::<uom::si::SI<f64>, f64>::zero() {
+            write!(f, ", laser on for {}", format_duration(self.laser_on_time))?;
+        }
+
+        if self.pauses > 0 {
+            write!(f, ", {} pauses for the operator", self.pauses)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn format_duration(duration: Time<uom::si::SI<f64>, f64>) -> String {
+    let seconds = duration.get::<second>().round() as u64;
+    let (hours, minutes, seconds) = (seconds / 3600, seconds / 60 % 60, seconds % 60);
+
+    if hours > 0 {
+        format!("{}h {:02}m {:02}s", hours, minutes, seconds)
+    } else if minutes > 0 {
+        format!("{}m {:02}s", minutes, seconds)
+    } else {
+        format!("{}s", seconds)
+    }
+}
+
+/// A stage of a GCode file, as far as the simulation is concerned.
+pub struct SimulatedStage {
+    /// The index of the first command of the stage.
+    pub start: usize,
+
+    /// The acceleration limit of the machine running the stage.
+    pub acceleration: Option<Acceleration<uom::si::SI<f64>, f64>>,
+}
+
+/// Follows the commands of a GCode file the same way [GCodeFile::to_string] writes them out.
+/// Positions are in millimeters, speeds in millimeters per second.
+struct Simulator<'a> {
+    dialect: &'a dyn Dialect,
+    acceleration: Option<f64>,
+    rapid_speed: f64,
+    work_speed: f64,
+    tool: Tool,
+    tool_is_ready_to_cut: bool,
+    position: Vector2<f64>,
+
+    /// Nothing is known about the height of the tool until one is equipped.
+    height: Option<f64>,
+
+    /// The depth the spindle is cutting at, and the top of the holding tab it is crossing.
+    cut_height: f64,
+    holding_tab: Option<f64>,
+    statistics: JobStatistics,
+}
+
+impl<'a> Simulator<'a> {
+    /// How long it takes to move a distance, coming to a stop at the end of it.
+    fn move_time(&self, distance: f64, speed: f64) -> f64 {
+        if distance <= 0.0 || speed <= 0.0 {
+            return 0.0;
+        }
+
+        match self.acceleration {
+            Some(acceleration) if acceleration > 0.0 => {
+                // The distance covered while getting up to full speed and back down again.
+                let ramp_distance = speed * speed / acceleration;
+
+                if distance >= ramp_distance {
+                    distance / speed + speed / acceleration
+                } else {
+                    // Full speed is never reached.
+                    2.0 * (distance / acceleration).sqrt()
+                }
+            }
+            _ => distance / speed,
+        }
+    }
+
+    fn rapid(&mut self, distance: f64) {
+        self.statistics.time += Time::<uom::si::SI<f64>, f64>::new::<second>(
+            self.move_time(distance, self.rapid_speed),
+        );
+        self.statistics.rapid_length +=
+            Length::<uom::si::SI<f64>, f64>::new::<millimeter>(distance);
+    }
+
+    fn rapid_to_height(&mut self, height: f64) {
+        if let Some(current_height) = self.height {
+            self.rapid((height - current_height).abs());
+        }
+
+        self.height = Some(height);
+    }
+
+    /// Moves the tool to a height at the given speed rather than the rapid speed.
+    fn feed_to_height(&mut self, height: f64, speed: f64) {
+        if let Some(current_height) = self.height {
+            self.statistics.time += Time::<uom::si::SI<f64>, f64>::new::<second>(
+                self.move_time((height - current_height).abs(), speed),
+            );
+        }
+
+        self.height = Some(height);
+    }
+
+    fn cut(&mut self, distance: f64) {
+        let time =
+            Time::<uom::si::SI<f64>, f64>::new::<second>(self.move_time(distance, self.work_speed));
+
+        self.statistics.time += time;
+        self.statistics.cut_length += Length::<uom::si::SI<f64>, f64>::new::<millimeter>(distance);
+
+        if matches!(self.tool, Tool::Laser { .. }) {
+            self.statistics.laser_on_time += time;
+        }
+    }
+
+    fn tool_height(&self) -> f64 {
+        self.holding_tab.map_or(self.cut_height, |tab_height| {
+            self.cut_height.max(tab_height)
+        })
+    }
+
+    /// Turns off the laser or lifts the spindle out of the board.
+    fn disengage(&mut self) {
+        if self.tool_is_ready_to_cut {
+            if let Tool::Spindle { travel_height, .. } = self.tool {
+                self.rapid_to_height(travel_height.get::<millimeter>());
+            }
+
+            self.tool_is_ready_to_cut = false;
+        }
+    }
+
+    fn step(&mut self, command: &GCommand) {
+        let to_vector = |(x, y): (Length<uom::si::SI<f64>, f64>, Length<uom::si::SI<f64>, f64>)| {
+            Vector2::new(x.get::<millimeter>(), y.get::<millimeter>())
+        };
+
+        match command {
+            GCommand::EquipTool(new_tool) => {
+                self.disengage();
+                self.tool = *new_tool;
+
+                if let Tool::Spindle { travel_height, .. } = self.tool {
+                    self.rapid_to_height(travel_height.get::<millimeter>());
+                }
+                self.tool_is_ready_to_cut = false;
+            }
+            GCommand::SetRapidTransverseSpeed(speed) => {
+                self.rapid_speed = self
+                    .dialect
+                    .machine_speed(*speed)
+                    .get::<millimeter_per_second>();
+            }
+            GCommand::SetWorkSpeed(speed) => {
+                self.work_speed = self
+                    .dialect
+                    .machine_speed(*speed)
+                    .get::<millimeter_per_second>();
+            }
+            GCommand::SetPower(_) | GCommand::SetSpindleSpeed(_) => {
+                self.tool_is_ready_to_cut = false;
+            }
+            GCommand::Cut {
+                pass_index,
+                movement,
+                target,
+            } => {
+                match self.tool {
+                    Tool::None => return,
+                    Tool::Laser { .. } if self.holding_tab.is_some() => {
+                        // Lasers move over holding tabs without cutting.
+                        let target = to_vector(*target);
+                        self.rapid((target - self.position).norm());
+                        self.position = target;
+                        return;
+                    }
+                    Tool::Laser { .. } => self.tool_is_ready_to_cut = true,
+                    Tool::Spindle {
+                        max_spindle_speed: _,
+                        diameter: _,
+                        travel_height,
+                        cut_depth,
+                        pass_depth,
+                        plunge_speed,
+                    } => {
+                        if !self.tool_is_ready_to_cut {
+                            let target_depth = pass_depth.map_or(cut_depth, |pass_depth| {
+                                travel_height - pass_depth * *pass_index as f64
+                            });
+
+                            self.cut_height = target_depth.get::<millimeter>();
+                            let target_depth = self.tool_height();
+
+                            // Cutting again at the same depth, such as after changing the speed, isn't a plunge.
+                            if self.height.is_none_or(|height| target_depth < height) {
+                                self.statistics.plunges += 1;
+                            }
+
+                            self.feed_to_height(
+                                target_depth,
+                                self.dialect
+                                    .machine_speed(plunge_speed)
+                                    .get::<millimeter_per_second>(),
+                            );
+                            self.tool_is_ready_to_cut = true;
+                        }
+                    }
+                }
+
+                let target = to_vector(*target);
+                let distance = match movement {
+                    MovementType::Linear => (target - self.position).norm(),
+                    MovementType::ClockwiseCurve { center } => {
+                        let center = to_vector(*center);
+                        (self.position - center).norm()
+                            * arc_sweep(self.position, center, target, true).abs()
+                    }
+                    MovementType::CounterClockwiseCurve { center } => {
+                        let center = to_vector(*center);
+                        (self.position - center).norm()
+                            * arc_sweep(self.position, center, target, false).abs()
+                    }
+                };
+
+                self.cut(distance);
+                self.position = target;
+            }
+            GCommand::MoveTo { target } => {
+                let target = to_vector(*target);
+
+                if self.position != target {
+                    self.disengage();
+                    self.rapid((target - self.position).norm());
+                    self.position = target;
+                } else {
+                    // We're already there.
+                    self.tool_is_ready_to_cut = false;
+                }
+            }
+            GCommand::Drill {
+                target,
+                peck_depth,
+                retract_height,
+            } => {
+                if let Tool::Spindle {
+                    max_spindle_speed: _,
+                    diameter: _,
+                    travel_height,
+                    cut_depth,
+                    pass_depth: _,
+                    plunge_speed,
+                } = self.tool
+                {
+                    self.disengage();
+
+                    let target = to_vector(*target);
+                    self.rapid((target - self.position).norm());
+                    self.position = target;
+
+                    let plunge_speed = self
+                        .dialect
+                        .machine_speed(plunge_speed)
+                        .get::<millimeter_per_second>();
+                    let retract_height = retract_height.unwrap_or(travel_height);
+                    self.rapid_to_height(retract_height.get::<millimeter>());
+
+                    let mut depth = retract_height;
+                    while depth > cut_depth {
+                        depth = peck_depth
+                            .map_or(cut_depth, |peck_depth| depth - peck_depth)
+                            .max(cut_depth);
+
+                        self.feed_to_height(depth.get::<millimeter>(), plunge_speed);
+
+                        self.rapid_to_height(retract_height.get::<millimeter>());
+                    }
+
+                    self.rapid_to_height(travel_height.get::<millimeter>());
+                    self.statistics.plunges += 1;
+                }
+            }
+            GCommand::Probe {
+                target,
+                search_depth: _,
+                speed,
+            } => {
+                if let Tool::Spindle { travel_height, .. } = self.tool {
+                    self.disengage();
+
+                    let target = to_vector(*target);
+                    self.rapid((target - self.position).norm());
+                    self.position = target;
+
+                    // The probe stops when it touches the board, which should be close to the Z zero.
+                    let speed = self
+                        .dialect
+                        .machine_speed(*speed)
+                        .get::<millimeter_per_second>();
+                    self.feed_to_height(0.0, speed);
+                    self.rapid_to_height(travel_height.get::<millimeter>());
+                }
+            }
+            GCommand::ToolChange {
+                tool_number,
+                diameter: _,
+                message: _,
+                rezero,
+            } => {
+                self.disengage();
+
+                if tool_number.is_none() {
+                    self.statistics.pauses += 1;
+                }
+
+                if *rezero {
+                    self.statistics.pauses += 1;
+                }
+            }
+            GCommand::SetHoldingTab(holding_tab) => {
+                let previous_height = self.tool_height();
+                self.holding_tab = holding_tab.map(|tab_height| tab_height.get::<millimeter>());
+
+                match self.tool {
+                    Tool::Laser { .. } if self.holding_tab.is_some() => {
+                        self.tool_is_ready_to_cut = false
+                    }
+                    Tool::Spindle { .. } if self.tool_height() < previous_height => {
+                        self.tool_is_ready_to_cut = false
+                    }
+                    Tool::Spindle { plunge_speed, .. } if self.tool_is_ready_to_cut => {
+                        self.feed_to_height(
+                            self.tool_height(),
+                            self.dialect
+                                .machine_speed(plunge_speed)
+                                .get::<millimeter_per_second>(),
+                        );
+                    }
+                    _ => {}
+                }
+            }
+            GCommand::Dwell(duration) => self.statistics.time += *duration,
+            // Included files can't be simulated, so they are assumed to take no time.
+            GCommand::UnitMode(_)
+            | GCommand::StartProbeLog(_)
+            | GCommand::EndProbeLog
+            | GCommand::SetHeightMap(_)
+            | GCommand::IncludeFile(_)
+            | GCommand::SetSide(_)
+            | GCommand::Comment(_) => {}
+        }
+    }
+}
+
+/// Estimates each stage of a GCode file. The stages must be in the order they appear in the file.
+pub fn simulate(
+    gcode_file: &GCodeFile,
+    dialect: &dyn Dialect,
+    stages: &[SimulatedStage],
+) -> Vec<JobStatistics> {
+    let mut simulator = Simulator {
+        dialect,
+        acceleration: None,
+        rapid_speed: 0.0,
+        work_speed: 0.0,
+        tool: Tool::None,
+        tool_is_ready_to_cut: false,
+        position: Vector2::new(0.0, 0.0),
+        height: None,
+        cut_height: 0.0,
+        holding_tab: None,
+        statistics: JobStatistics::default(),
+    };
+
+    let commands = gcode_file.commands();
+
+    stages
+        .iter()
+        .enumerate()
+        .map(|(stage_index, stage)| {
+            // Anything before the first stage is counted as part of it.
+            let start = if stage_index == 0 { 0 } else { stage.start };
+            let end = stages
+                .get(stage_index + 1)
+                .map_or(commands.len(), |next_stage| next_stage.start);
+
+            simulator.acceleration = stage
+                .acceleration
+                .map(|acceleration| acceleration.get::<millimeter_per_second_squared>());
+
+            for command in &commands[start..end] {
+                simulator.step(command);
+            }
+
+            std::mem::take(&mut simulator.statistics)
+        })
+        .collect()
+}
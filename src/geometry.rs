@@ -1,7 +1,7 @@
 use std::collections::HashMap;
 
 use anyhow::Result;
-use geo::{Coord, LineString, Polygon};
+use geo::{ConvexHull, Coord, LineString, MultiPoint, MultiPolygon, Point, Polygon};
 use nalgebra::{Matrix2, Rotation2, Vector2};
 use ordered_float::NotNan;
 use svg_composer::element::path::{
@@ -41,6 +41,40 @@ impl Shape {
         Ok(())
     }
 
+    /// Moves the shape by an affine transform. Mirroring reverses the direction of its arcs.
+    pub fn transform(&mut self, matrix: &Matrix2<f64>, offset: Vector2<f64>) {
+        let apply = |point: Vector2<f64>| matrix * point + offset;
+        let mirrored = matrix.determinant() < 0.0;
+
+        self.starting_point = apply(self.starting_point);
+
+        for segment in self.segments.iter_mut() {
+            *segment = match *segment {
+                Segment::Line { end } => Segment::Line { end: apply(end) },
+                Segment::ClockwiseCurve { end, center } if mirrored => {
+                    Segment::CounterClockwiseCurve {
+                        end: apply(end),
+                        center: apply(center),
+                    }
+                }
+                Segment::ClockwiseCurve { end, center } => Segment::ClockwiseCurve {
+                    end: apply(end),
+                    center: apply(center),
+                },
+                Segment::CounterClockwiseCurve { end, center } if mirrored => {
+                    Segment::ClockwiseCurve {
+                        end: apply(end),
+                        center: apply(center),
+                    }
+                }
+                Segment::CounterClockwiseCurve { end, center } => Segment::CounterClockwiseCurve {
+                    end: apply(end),
+                    center: apply(center),
+                },
+            };
+        }
+    }
+
     pub fn calculate_bounds(&self) -> (f64, f64, f64, f64) {
         let mut min_x = self.starting_point.x;
         let mut min_y = self.starting_point.y;
@@ -299,6 +333,48 @@ impl Shape {
         }
     }
 
+    /// Draws made with a rectangle aperture cover everything the rectangle passes over, which is the convex hull of the
+    /// rectangle at both ends of the draw. Section 4.7.1
+    pub fn rectangle_line(
+        shape_configuration: ShapeConfiguration,
+        width: f64,
+        height: f64,
+        start: Vector2<f64>,
+        end: Vector2<f64>,
+    ) {
+        let half_width = width / 2.0;
+        let half_height = height / 2.0;
+
+        let corners: MultiPoint<f64> = [start, end]
+            .iter()
+            .flat_map(|center| {
+                [
+                    (center.x - half_width, center.y - half_height),
+                    (center.x + half_width, center.y - half_height),
+                    (center.x + half_width, center.y + half_height),
+                    (center.x - half_width, center.y + half_height),
+                ]
+            })
+            .map(|(x, y)| Point::new(x, y))
+            .collect();
+
+        // The exterior of the hull is closed, so it ends where it starts.
+        let hull = corners.convex_hull();
+        let mut points = hull
+            .exterior()
+            .0
+            .iter()
+            .map(|coord| shape_configuration.transform * Vector2::new(coord.x, coord.y));
+
+        if let Some(starting_point) = points.next() {
+            shape_configuration.shapes.push(Shape {
+                polarity: shape_configuration.polarity,
+                starting_point,
+                segments: points.map(|end| Segment::Line { end }).collect(),
+            });
+        }
+    }
+
     pub fn arch(
         shape_configuration: ShapeConfiguration,
         diameter: f64,
@@ -591,7 +667,7 @@ impl Shape {
         }
 
         shape_configuration.shapes.push(Shape {
-            polarity: Polarity::Dark,
+            polarity: shape_configuration.polarity,
             starting_point,
             segments,
         });
@@ -948,3 +1024,496 @@ pub enum ArchDirection {
     Clockwise,
     CounterClockwise,
 }
+
+/// Replaces runs of points that lie on a common circle with arc segments, recovering the arcs that were lost when
+/// curves were flattened into line strings. Points that cannot be fitted to an arc are kept as line segments.
+///
+/// An arc is only accepted if every point and every chord between the points stays within `tolerance` of it, so
+/// corners of genuine polygons are not rounded off. The returned segments start at the first point.
+pub fn fit_arcs(points: &[Coord<f64>], tolerance: f64) -> Vec<Segment> {
+    /// Fewer segments than this are not worth turning into an arc.
+    const MINIMUM_ARC_SEGMENTS: usize = 3;
+
+    let points: Vec<Vector2<f64>> = points
+        .iter()
+        .map(|point| Vector2::new(point.x, point.y))
+        .collect();
+
+    let mut segments = Vec::new();
+    let mut start = 0;
+
+    while start + 1 < points.len() {
+        let mut best_fit = None;
+
+        if tolerance > 0.0 {
+            let mut end = start + MINIMUM_ARC_SEGMENTS;
+            while end < points.len() {
+                if let Some(arc) = fit_arc(&points[start..=end], tolerance) {
+                    best_fit = Some((end, arc));
+                    end += 1;
+                } else {
+                    break;
+                }
+            }
+        }
+
+        // If everything is close enough to the chord, this is a line, not an arc.
+        let best_fit =
+            best_fit.filter(|(end, _arc)| !is_straight(&points[start..=*end], tolerance));
+
+        if let Some((end, arc)) = best_fit {
+            segments.push(arc);
+            start = end;
+        } else {
+            segments.push(Segment::Line {
+                end: points[start + 1],
+            });
+            start += 1;
+        }
+    }
+
+    segments
+}
+
+/// Attempts to fit a single arc through all of the provided points.
+fn fit_arc(points: &[Vector2<f64>], tolerance: f64) -> Option<Segment> {
+    let first = *points.first()?;
+    let middle = points[points.len() / 2];
+    let last = *points.last()?;
+
+    let center = circle_center(first, middle, last)?;
+    let radius = (first - center).norm();
+
+    // The direction we turn around the center. Positive is counter clockwise.
+    let direction = {
+        let to_middle = middle - first;
+        let to_last = last - middle;
+        (to_middle.x * to_last.y - to_middle.y * to_last.x).signum()
+    };
+
+    let mut sweep = 0.0;
+
+    for window in points.windows(2) {
+        let (start, end) = (window[0], window[1]);
+
+        if ((end - center).norm() - radius).abs() > tolerance {
+            return None;
+        }
+
+        // How far the arc bulges away from the line between the two points.
+        let half_chord = (end - start).norm() / 2.0;
+        if half_chord > radius || radius - (radius.powi(2) - half_chord.powi(2)).sqrt() > tolerance
+        {
+            return None;
+        }
+
+        let from_center = start - center;
+        let to_center = end - center;
+        let angle = (from_center.x * to_center.y - from_center.y * to_center.x)
+            .atan2(from_center.dot(&to_center));
+
+        // Every step has to turn the same way around the center.
+        if angle * direction <= 0.0 {
+            return None;
+        }
+
+        sweep += angle.abs();
+    }
+
+    // A full circle starts and ends at the same point, which G-code can't express without ambiguity.
+    if sweep >= std::f64::consts::PI * 2.0 - f64::EPSILON.sqrt() {
+        return None;
+    }
+
+    Some(if direction > 0.0 {
+        Segment::CounterClockwiseCurve { end: last, center }
+    } else {
+        Segment::ClockwiseCurve { end: last, center }
+    })
+}
+
+/// Checks if all the points are within the tolerance of the line between the first and last point.
+fn is_straight(points: &[Vector2<f64>], tolerance: f64) -> bool {
+    let first = points[0];
+    let chord = points[points.len() - 1] - first;
+    let chord_length = chord.norm();
+
+    chord_length > 0.0
+        && points.iter().all(|point| {
+            let offset = point - first;
+            (chord.x * offset.y - chord.y * offset.x).abs() / chord_length <= tolerance
+        })
+}
+
+/// Finds the center of the circle that passes through all three points.
+/// Returns none if the points are on a line.
+fn circle_center(a: Vector2<f64>, b: Vector2<f64>, c: Vector2<f64>) -> Option<Vector2<f64>> {
+    let ab = b - a;
+    let ac = c - a;
+
+    let determinant = 2.0 * (ab.x * ac.y - ab.y * ac.x);
+    if determinant.abs() < f64::EPSILON {
+        return None;
+    }
+
+    let ab_squared = ab.norm_squared();
+    let ac_squared = ac.norm_squared();
+
+    Some(
+        a + Vector2::new(
+            (ac.y * ab_squared - ab.y * ac_squared) / determinant,
+            (ab.x * ac_squared - ac.x * ab_squared) / determinant,
+        ),
+    )
+}
+
+/// The angle an arc turns through around its center, negative for clockwise arcs.
+/// An arc that ends where it starts is a full circle.
+pub fn arc_sweep(
+    start: Vector2<f64>,
+    center: Vector2<f64>,
+    end: Vector2<f64>,
+    clockwise: bool,
+) -> f64 {
+    let start_angle = (start.y - center.y).atan2(start.x - center.x);
+    let end_angle = (end.y - center.y).atan2(end.x - center.x);
+
+    let sweep = if clockwise {
+        (start_angle - end_angle).rem_euclid(std::f64::consts::TAU)
+    } else {
+        (end_angle - start_angle).rem_euclid(std::f64::consts::TAU)
+    };
+    let sweep = if sweep == 0.0 {
+        std::f64::consts::TAU
+    } else {
+        sweep
+    };
+
+    if clockwise {
+        -sweep
+    } else {
+        sweep
+    }
+}
+
+/// Which way infill lines run across a shape.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScanDirection {
+    Horizontal,
+    Vertical,
+}
+
+/// An edge of a shape, in coordinates along and across the scan lines.
+/// Edges run upwards across the scan lines so that each line can work out where it crosses them.
+struct ScanEdge {
+    across_start: f64,
+    across_end: f64,
+    along_start: f64,
+    slope: f64,
+}
+
+/// Intersects evenly spaced scan lines with the edges of a shape, returning the parts of the lines outside of it, or
+/// inside of it when inverted. Lines start at the low corner of the bounds and are clipped to them.
+///
+/// Only the edges a scan line crosses are kept in an active edge table, so each line costs only as much as the edges
+/// around it. Points where a line crosses the edges pair up into the parts inside the shape, which makes nested holes
+/// come out right.
+pub fn scanline_fill(
+    polygon: &MultiPolygon<f64>,
+    direction: ScanDirection,
+    (min_x, min_y, max_x, max_y): (f64, f64, f64, f64),
+    spacing: f64,
+    invert: bool,
+) -> Vec<[Vector2<f64>; 2]> {
+    let to_scan = |coord: Coord<f64>| match direction {
+        ScanDirection::Horizontal => (coord.x, coord.y),
+        ScanDirection::Vertical => (coord.y, coord.x),
+    };
+    let from_scan = |along: f64, across: f64| match direction {
+        ScanDirection::Horizontal => Vector2::new(along, across),
+        ScanDirection::Vertical => Vector2::new(across, along),
+    };
+    let (along_min, across_min, along_max, across_max) = match direction {
+        ScanDirection::Horizontal => (min_x, min_y, max_x, max_y),
+        ScanDirection::Vertical => (min_y, min_x, max_y, max_x),
+    };
+
+    let mut edges: Vec<ScanEdge> = polygon
+        .iter()
+        .flat_map(|polygon| std::iter::once(polygon.exterior()).chain(polygon.interiors()))
+        .flat_map(|ring| ring.lines())
+        .filter_map(|line| {
+            let (start_along, start_across) = to_scan(line.start);
+            let (end_along, end_across) = to_scan(line.end);
+
+            // Edges that run along the scan lines never cross them.
+            if start_across == end_across {
+                return None;
+            }
+
+            let ((start_along, start_across), (end_along, end_across)) =
+                if start_across < end_across {
+                    ((start_along, start_across), (end_along, end_across))
+                } else {
+                    ((end_along, end_across), (start_along, start_across))
+                };
+
+            Some(ScanEdge {
+                across_start: start_across,
+                across_end: end_across,
+                along_start: start_along,
+                slope: (end_along - start_along) / (end_across - start_across),
+            })
+        })
+        .collect();
+    edges.sort_by(|a, b| a.across_start.total_cmp(&b.across_start));
+
+    let mut lines = Vec::new();
+    let mut active_edges: Vec<&ScanEdge> = Vec::new();
+    let mut next_edge = 0;
+    let mut crossings = Vec::new();
+
+    let mut push_line = |across: f64, start: f64, end: f64| {
+        let (start, end) = (start.max(along_min), end.min(along_max));
+
+        if end > start {
+            lines.push([from_scan(start, across), from_scan(end, across)]);
+        }
+    };
+
+    let mut across = across_min;
+    while across < across_max {
+        while let Some(edge) = edges
+            .get(next_edge)
+            .filter(|edge| edge.across_start <= across)
+        {
+            active_edges.push(edge);
+            next_edge += 1;
+        }
+
+        // Edges are treated as not including their top end, so that a line through a vertex crosses it only once.
+        active_edges.retain(|edge| edge.across_end > across);
+
+        crossings.clear();
+        crossings.extend(
+            active_edges
+                .iter()
+                .map(|edge| edge.along_start + (across - edge.across_start) * edge.slope),
+        );
+        crossings.sort_by(f64::total_cmp);
+
+        if invert {
+            for pair in crossings.chunks_exact(2) {
+                push_line(across, pair[0], pair[1]);
+            }
+        } else {
+            let mut previous_end = along_min;
+
+            for pair in crossings.chunks_exact(2) {
+                push_line(across, previous_end, pair[0]);
+                previous_end = previous_end.max(pair[1]);
+            }
+
+            push_line(across, previous_end, along_max);
+        }
+
+        across += spacing;
+    }
+
+    lines
+}
+
+/// Orders lines so that each one is started from whichever end is nearest to where the previous one finished.
+/// The ends of the lines are sorted into a grid of cells so that finding the nearest one only needs to look at the
+/// cells around the tool, rather than every line.
+pub fn order_lines(
+    lines: Vec<[Vector2<f64>; 2]>,
+    start: Vector2<f64>,
+    cell_size: f64,
+) -> Vec<[Vector2<f64>; 2]> {
+    if lines.is_empty() {
+        return lines;
+    }
+
+    let origin = lines
+        .iter()
+        .flatten()
+        .fold(Vector2::repeat(f64::INFINITY), |origin, point| {
+            origin.inf(point)
+        });
+    let cell_of = |point: Vector2<f64>| {
+        (
+            ((point.x - origin.x) / cell_size).floor() as i64,
+            ((point.y - origin.y) / cell_size).floor() as i64,
+        )
+    };
+
+    // Each cell lists the line and end index of the ends within it.
+    let mut cells: HashMap<(i64, i64), Vec<(usize, usize)>> = HashMap::new();
+    for (line_index, line) in lines.iter().enumerate() {
+        for (end_index, point) in line.iter().enumerate() {
+            cells
+                .entry(cell_of(*point))
+                .or_default()
+                .push((line_index, end_index));
+        }
+    }
+
+    let (max_column, max_row) = cells
+        .keys()
+        .fold((0, 0), |(max_column, max_row), (column, row)| {
+            (max_column.max(*column), max_row.max(*row))
+        });
+
+    let mut taken = vec![false; lines.len()];
+    let mut ordered = Vec::with_capacity(lines.len());
+    let mut position = start;
+
+    while ordered.len() < lines.len() {
+        let (column, row) = cell_of(position);
+        let last_ring = [column, max_column - column, row, max_row - row]
+            .into_iter()
+            .map(i64::abs)
+            .max()
+            .unwrap_or(0);
+
+        let mut nearest: Option<(f64, usize, usize)> = None;
+
+        for ring in 0..=last_ring {
+            // Anything in this ring or further out is at least this far away.
+            let ring_distance = (ring - 1).max(0) as f64 * cell_size;
+            if nearest.is_some_and(|(distance, _, _)| distance <= ring_distance) {
+                break;
+            }
+
+            let ring_cells: Vec<(i64, i64)> = if ring == 0 {
+                vec![(column, row)]
+            } else {
+                (column - ring..=column + ring)
+                    .flat_map(|ring_column| [(ring_column, row - ring), (ring_column, row + ring)])
+                    .chain((row - ring + 1..row + ring).flat_map(|ring_row| {
+                        [(column - ring, ring_row), (column + ring, ring_row)]
+                    }))
+                    .collect()
+            };
+
+            for cell in ring_cells {
+                if let Some(ends) = cells.get_mut(&cell) {
+                    ends.retain(|(line_index, _)| !taken[*line_index]);
+
+                    for (line_index, end_index) in ends.iter() {
+                        let distance = (lines[*line_index][*end_index] - position).norm();
+
+                        if nearest
+                            .is_none_or(|(nearest_distance, _, _)| distance < nearest_distance)
+                        {
+                            nearest = Some((distance, *line_index, *end_index));
+                        }
+                    }
+                }
+            }
+        }
+
+        let (_, line_index, end_index) = nearest.expect("No line was selected.");
+        taken[line_index] = true;
+
+        let [line_start, line_end] = lines[line_index];
+        let line = if end_index == 0 {
+            [line_start, line_end]
+        } else {
+            [line_end, line_start]
+        };
+
+        position = line[1];
+        ordered.push(line);
+    }
+
+    ordered
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn square(min: f64, max: f64) -> LineString<f64> {
+        LineString::from(vec![
+            (min, min),
+            (max, min),
+            (max, max),
+            (min, max),
+            (min, min),
+        ])
+    }
+
+    fn spans(lines: &[[Vector2<f64>; 2]]) -> Vec<(f64, f64, f64)> {
+        lines
+            .iter()
+            .map(|[start, end]| {
+                assert_eq!(start.y, end.y);
+                (start.y, start.x, end.x)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn scanline_fill_of_square() {
+        let polygon = MultiPolygon::new(vec![Polygon::new(square(2.0, 8.0), vec![])]);
+        let bounds = (0.0, 1.0, 10.0, 9.0);
+
+        // Engravings fill around the shape.
+        let lines = scanline_fill(&polygon, ScanDirection::Horizontal, bounds, 2.0, false);
+        assert_eq!(
+            spans(&lines),
+            vec![
+                (1.0, 0.0, 10.0),
+                (3.0, 0.0, 2.0),
+                (3.0, 8.0, 10.0),
+                (5.0, 0.0, 2.0),
+                (5.0, 8.0, 10.0),
+                (7.0, 0.0, 2.0),
+                (7.0, 8.0, 10.0),
+            ]
+        );
+
+        let lines = scanline_fill(&polygon, ScanDirection::Horizontal, bounds, 2.0, true);
+        assert_eq!(
+            spans(&lines),
+            vec![(3.0, 2.0, 8.0), (5.0, 2.0, 8.0), (7.0, 2.0, 8.0)]
+        );
+    }
+
+    #[test]
+    fn scanline_fill_of_square_with_hole() {
+        let polygon = MultiPolygon::new(vec![Polygon::new(
+            square(0.0, 10.0),
+            vec![square(4.0, 6.0)],
+        )]);
+        let bounds = (0.0, 1.0, 10.0, 9.0);
+
+        let lines = scanline_fill(&polygon, ScanDirection::Horizontal, bounds, 4.0, false);
+        assert_eq!(spans(&lines), vec![(5.0, 4.0, 6.0)]);
+
+        let lines = scanline_fill(&polygon, ScanDirection::Horizontal, bounds, 4.0, true);
+        assert_eq!(
+            spans(&lines),
+            vec![(1.0, 0.0, 10.0), (5.0, 0.0, 4.0), (5.0, 6.0, 10.0)]
+        );
+    }
+
+    #[test]
+    fn vertical_scanline_fill() {
+        let polygon = MultiPolygon::new(vec![Polygon::new(square(2.0, 8.0), vec![])]);
+
+        let lines = scanline_fill(
+            &polygon,
+            ScanDirection::Vertical,
+            (3.0, 0.0, 4.0, 10.0),
+            2.0,
+            true,
+        );
+        assert_eq!(
+            lines,
+            vec![[Vector2::new(3.0, 2.0), Vector2::new(3.0, 8.0)]]
+        );
+    }
+}
@@ -1,9 +1,8 @@
 use anyhow::{anyhow, bail, Context, Result};
-use geo::{BooleanOps, BoundingRect, Contains, Coord, MultiPolygon, Polygon};
+use geo::{BooleanOps, BoundingRect, Coord, MultiPolygon, Polygon};
 use geo_offset::Offset;
 use nalgebra::{Matrix2, Rotation2, Vector2};
-use progress_bar::*;
-use std::{collections::HashMap, fs, ops::Deref, path::Path};
+use std::{collections::HashMap, f64::consts::FRAC_PI_2, fs, ops::Deref, path::Path};
 use svg_composer::{
     document::Document as SvgDocument,
     element::{
@@ -13,56 +12,238 @@ use svg_composer::{
     },
 };
 use uom::si::{
-    length::{mil, millimeter, Length},
+    length::{inch, millimeter, Length},
     ratio::ratio,
 };
 
 use crate::{
-    forge_file::LineSelection,
+    config::machine::FillStrategy,
+    forge_file::{HoldingTabs, LineSelection, ObjectSelection},
     gcode_generation::{
-        add_point_string_to_gcode_vector, GCodeConfig, GCommand, MovementType, Tool, ToolSelection,
+        add_point_string_to_gcode_vector, segment_to_cut, GCodeConfig, GCommand, MovementType,
+        Tool, ToolSelection,
+    },
+    geometry::{
+        arc_sweep, fit_arcs, order_lines, scanline_fill, ArchDirection, ScanDirection, Segment,
+        Shape, ShapeConfiguration,
     },
-    geometry::{ArchDirection, Segment, Shape, ShapeConfiguration},
     parsing::{
         gerber::{
-            parse_gerber_file, ApertureTemplate, Attribute, GerberCommand, GerberCommandContext,
-            MacroContent, MirroringMode, Operation, Polarity, Span,
+            parse_gerber_file, ApertureTemplate, Attribute, DeprecatedCommand, GerberCommand,
+            GerberCommandContext, MacroContent, MirroringMode, Operation, Polarity, Span,
         },
         UnitMode,
     },
 };
 
+/// How far, in millimeters, single quadrant arcs may be from the limits of their quadrant.
+const SINGLE_QUADRANT_TOLERANCE: f64 = 1e-6;
+
+/// What is cut from the shapes of a gerber file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Toolpath {
+    /// Only the outlines of the shapes, such as to cut out a board.
+    Outline,
+
+    /// The outlines of the shapes, with the area within them cleared.
+    Engrave,
+
+    /// Several outlines around each shape, each further out than the last, to isolate it from the copper around it.
+    Isolate,
+}
+
 #[derive(Debug, Default)]
 pub struct GerberFile {
-    shapes: Vec<Shape>,
-    aperture_macro_flashes: Vec<Vec<Shape>>,
+    /// Graphical objects in the order they were drawn. Order matters, since clear objects only erase what came before them.
+    objects: Vec<GraphicalObject>,
+
+    /// Set by the deprecated IPNEG command, which swaps what is dark and what is clear across the whole image.
+    negative_image: bool,
+}
+
+#[derive(Debug)]
+enum GraphicalObject {
+    /// A draw or region, applied to the image with its own polarity.
+    Shape {
+        shape: Shape,
+        attributes: ObjectAttributes,
+    },
+
+    /// A flashed aperture. The polarity of the shapes within is relative to the aperture (clear shapes are holes in the aperture),
+    /// and the resulting aperture image is applied to the image with the polarity of the flash.
+    Flash {
+        polarity: Polarity,
+        shapes: Vec<Shape>,
+        attributes: ObjectAttributes,
+    },
+}
+
+impl GraphicalObject {
+    fn polarity(&self) -> Polarity {
+        match self {
+            GraphicalObject::Shape { shape, .. } => shape.polarity,
+            GraphicalObject::Flash { polarity, .. } => *polarity,
+        }
+    }
+
+    fn attributes(&self) -> &ObjectAttributes {
+        match self {
+            GraphicalObject::Shape { attributes, .. }
+            | GraphicalObject::Flash { attributes, .. } => attributes,
+        }
+    }
+
+    fn convert_to_geo_polygon(&self, distance_per_step: f64) -> MultiPolygon<f64> {
+        match self {
+            GraphicalObject::Shape { shape, .. } => {
+                MultiPolygon::new(vec![shape.convert_to_geo_polygon(distance_per_step)])
+            }
+            GraphicalObject::Flash { shapes, .. } => {
+                shapes
+                    .iter()
+                    .fold(MultiPolygon::new(vec![]), |image, shape| {
+                        apply_polarity(
+                            image,
+                            shape.polarity,
+                            &MultiPolygon::new(vec![
+                                shape.convert_to_geo_polygon(distance_per_step)
+                            ]),
+                        )
+                    })
+            }
+        }
+    }
+}
+
+/// The X2 attributes of a graphical object, which tell what the object is on the board. Section 5.6
+#[derive(Debug, Clone, Default)]
+struct ObjectAttributes {
+    /// The `.AperFunction` of the object's aperture, such as `ComponentPad` or `Conductor`, without its further fields.
+    aperture_function: Option<String>,
+
+    /// The nets the object is part of, from `.N`. Empty names mark objects that aren't connected to anything.
+    nets: Vec<String>,
+
+    /// The reference designator of the component, from `.C`.
+    component: Option<String>,
+
+    /// The reference designator and number of the component pin, from `.P`.
+    pin: Option<(String, String)>,
+}
+
+impl ObjectAttributes {
+    /// Whether the object is in each category of a selection. Categories left empty in the selection are skipped.
+    fn selected_by<'a>(
+        &'a self,
+        selection: &'a ObjectSelection,
+    ) -> impl Iterator<Item = bool> + 'a {
+        let pin_component = self.pin.as_ref().map(|(component, _number)| component);
+        let pin_name = self
+            .pin
+            .as_ref()
+            .map(|(component, number)| format!("{}-{}", component, number));
+
+        [
+            (!selection.aperture_functions.is_empty()).then(|| {
+                self.aperture_function
+                    .as_ref()
+                    .is_some_and(|function| selection.aperture_functions.contains(function))
+            }),
+            (!selection.nets.is_empty())
+                .then(|| self.nets.iter().any(|net| selection.nets.contains(net))),
+            (!selection.components.is_empty()).then(|| {
+                self.component
+                    .iter()
+                    .chain(pin_component)
+                    .any(|component| selection.components.contains(component))
+            }),
+            (!selection.pins.is_empty()).then(|| {
+                pin_name
+                    .as_ref()
+                    .is_some_and(|pin| selection.pins.contains(pin))
+            }),
+        ]
+        .into_iter()
+        .flatten()
+    }
+}
+
+/// Draws an object onto an image. Dark objects are added to the image, clear objects erase from it.
+fn apply_polarity(
+    image: MultiPolygon<f64>,
+    polarity: Polarity,
+    object: &MultiPolygon<f64>,
+) -> MultiPolygon<f64> {
+    match polarity {
+        Polarity::Dark => image.union(object),
+        Polarity::Clear => image.difference(object),
+    }
 }
 
 impl GerberFile {
-    fn iter_all_shapes(&self) -> impl Iterator<Item = &Shape> {
-        self.shapes
+    /// Iterates every shape along with the polarity it is effectively drawn with.
+    fn iter_all_shapes(&self) -> impl Iterator<Item = (Polarity, &Shape)> {
+        self.objects
             .iter()
-            .chain(self.aperture_macro_flashes.iter().flatten())
+            .flat_map(|object| -> Box<dyn Iterator<Item = (Polarity, &Shape)>> {
+                match object {
+                    GraphicalObject::Shape { shape, .. } => {
+                        Box::new(std::iter::once((shape.polarity, shape)))
+                    }
+                    GraphicalObject::Flash {
+                        polarity, shapes, ..
+                    } => Box::new(shapes.iter().map(move |shape| {
+                        let shape_polarity = match polarity {
+                            Polarity::Dark => shape.polarity,
+                            Polarity::Clear => shape.polarity.inverse(),
+                        };
+
+                        (shape_polarity, shape)
+                    })),
+                }
+            })
     }
 
     pub fn generate_gcode(
         &self,
         config: GCodeConfig,
-        generate_infill: bool,
+        toolpath: Toolpath,
         line_selection: LineSelection,
         invert: bool,
+        tabs: Option<&HoldingTabs>,
     ) -> Result<()> {
-        log::info!("Simplifying geometry.");
-        let distance_per_step = config.job_config.distance_per_step.get::<millimeter>();
+        let generate_infill = toolpath == Toolpath::Engrave;
 
-        let mut polygon = Vec::new();
+        // A negative image is the same as inverting a positive one.
+        let invert = invert != self.negative_image;
 
-        // Iterate all our shapes *and* the macro flashes within.
-        for shape in self.iter_all_shapes() {
-            polygon.push(shape.convert_to_geo_polygon(distance_per_step));
-        }
+        log::info!("Simplifying geometry.");
+        let distance_per_step = config.job_config.distance_per_step.get::<millimeter>();
 
-        let polygon = MultiPolygon::new(polygon);
+        let polygon = if self
+            .objects
+            .iter()
+            .any(|object| matches!(object.polarity(), Polarity::Clear))
+        {
+            // Clear objects only erase what was drawn before them, so the image has to be built up in file order.
+            self.objects
+                .iter()
+                .fold(MultiPolygon::new(vec![]), |image, object| {
+                    apply_polarity(
+                        image,
+                        object.polarity(),
+                        &object.convert_to_geo_polygon(distance_per_step),
+                    )
+                })
+        } else {
+            // Everything is dark, so the order doesn't matter and the merge can be left to the offset.
+            MultiPolygon::new(
+                self.objects
+                    .iter()
+                    .flat_map(|object| object.convert_to_geo_polygon(distance_per_step))
+                    .collect(),
+            )
+        };
 
         fn apply_line_selection(
             line_selection: LineSelection,
@@ -112,6 +293,57 @@ impl GerberFile {
             apply_line_selection(line_selection, polygon)
         };
 
+        // Isolation milling cuts further outlines around the first, so that no slivers of copper are left between them.
+        let outlines = if toolpath == Toolpath::Isolate {
+            let diameter = config.tool_config.diameter();
+            let passes = config.job_config.isolation_passes.max(1);
+            let width = config
+                .job_config
+                .isolation_width
+                .unwrap_or(diameter + diameter / 2.0 * (passes - 1) as f64);
+
+            if width < diameter {
+                bail!(
+                    "The isolation width of {} mm is smaller than the tool's diameter of {} mm.",
+                    width.get::<millimeter>(),
+                    diameter.get::<millimeter>()
+                );
+            }
+
+            let stepover = if passes > 1 {
+                (width - diameter) / (passes - 1) as f64
+            } else {
+                Length::new::<millimeter>(0.0)
+            };
+
+            if stepover > diameter || (passes == 1 && width > diameter) {
+                bail!(
+                    "Clearing an isolation width of {} mm with a {} mm tool takes at least {} passes.",
+                    width.get::<millimeter>(),
+                    diameter.get::<millimeter>(),
+                    ((width - diameter) / diameter).get::<ratio>().ceil() as usize + 1
+                );
+            }
+
+            log::info!(
+                "Isolating with {} passes, {} mm apart.",
+                passes,
+                stepover.get::<millimeter>()
+            );
+
+            let mut outlines = polygon.0.clone();
+            for pass in 1..passes {
+                let outline = polygon
+                    .offset(stepover.get::<millimeter>() * pass as f64)
+                    .map_err(|error| anyhow!("Failed to offset isolation pass: {:?}", error))?;
+                outlines.extend(outline.0);
+            }
+
+            MultiPolygon::new(outlines)
+        } else {
+            polygon.clone()
+        };
+
         // We can actually start to generate GCode now.
 
         let passes = match config.job_config.tool_power {
@@ -128,6 +360,7 @@ impl GerberFile {
                             GCommand::SetWorkSpeed(work_speed),
                             GCommand::EquipTool(Tool::Laser {
                                 max_power: laser.max_power,
+                                diameter: laser.point_diameter,
                             }),
                             GCommand::SetPower(laser_power),
                         ]
@@ -156,6 +389,7 @@ impl GerberFile {
                             GCommand::SetWorkSpeed(work_speed),
                             GCommand::EquipTool(Tool::Spindle {
                                 max_spindle_speed: spindle.max_speed,
+                                diameter: config.tool_config.diameter(),
                                 plunge_speed,
                                 travel_height,
                                 pass_depth,
@@ -167,6 +401,10 @@ impl GerberFile {
                         .cloned(),
                     );
 
+                    if let Some(spin_up_time) = spindle.spin_up_time {
+                        config.commands.push(GCommand::Dwell(spin_up_time));
+                    }
+
                     // The number of passes we are to do.
                     // This will have a tendency to undercut but that should be fine for most use cases.
                     pass_depth.map_or(1, |pass_depth| {
@@ -184,6 +422,50 @@ impl GerberFile {
             ));
         }
 
+        let tab_layout = match tabs {
+            Some(tabs) => {
+                if tabs.count.is_some() && tabs.spacing.is_some() {
+                    bail!("Holding tabs can be given a count or a spacing, but not both.");
+                }
+
+                if tabs
+                    .spacing
+                    .is_some_and(|spacing| spacing.get::<millimeter>() <= 0.0)
+                {
+                    bail!("The spacing of holding tabs must be larger than zero.");
+                }
+
+                // Tabs are measured from the bottom of the cut.
+                let top = match config.job_config.tool_power {
+                    crate::config::machine::ToolConfig::EndMill { cut_depth, .. } => {
+                        cut_depth + tabs.height
+                    }
+                    crate::config::machine::ToolConfig::Laser { .. } => tabs.height,
+                };
+
+                Some(TabLayout {
+                    tabs,
+                    length: (tabs.width + config.tool_config.diameter()).get::<millimeter>(),
+                    top,
+                })
+            }
+            None => None,
+        };
+
+        // Contour fills are the same for every pass, so they are only worked out once.
+        let contour_rings = match config.job_config.fill {
+            FillStrategy::Contour { stepover } if generate_infill => {
+                log::info!("Generating contour rings.");
+
+                let stepover = stepover.unwrap_or(config.tool_config.diameter() / 2.0);
+                let region = contour_region(&polygon, invert)?;
+
+                let stepover = stepover.get::<millimeter>();
+                Some((stepover, contour_rings(&region, stepover)?))
+            }
+            _ => None,
+        };
+
         for pass_index in 0..passes {
             log::info!("Processing pass {}.", pass_index + 1);
 
@@ -201,7 +483,7 @@ impl GerberFile {
             );
 
             {
-                let mut polygon_list = polygon.0.clone();
+                let mut polygon_list = outlines.0.clone();
                 let mut last_position = Vector2::new(min_x, min_y);
 
                 while !polygon_list.is_empty() {
@@ -228,10 +510,12 @@ impl GerberFile {
                         .expect("Polygon did not have any vertices.");
                     last_position = Vector2::new(new_position.x, new_position.y);
 
-                    add_point_string_to_gcode_vector(
+                    add_ring_to_gcode_vector(
                         config.commands,
-                        polygon.exterior().0.iter(),
+                        &polygon.exterior().0,
+                        tab_layout.as_ref(),
                         pass_index,
+                        config.job_config.arc_tolerance,
                     );
 
                     let mut interior_list = polygon.interiors().to_vec();
@@ -258,10 +542,12 @@ impl GerberFile {
                             .expect("Interior did not have any vertices.");
                         last_position = Vector2::new(new_position.x, new_position.y);
 
-                        add_point_string_to_gcode_vector(
+                        add_ring_to_gcode_vector(
                             config.commands,
-                            interior.0.iter(),
+                            &interior.0,
+                            tab_layout.as_ref(),
                             pass_index,
+                            config.job_config.arc_tolerance,
                         );
                     }
                 }
@@ -271,171 +557,52 @@ impl GerberFile {
                 // Now we generate the infill.
                 log::info!("Generating infill.");
 
-                struct InfillLine {
-                    start: Vector2<f64>,
-                    end: Vector2<f64>,
-                }
-
-                let mut lines = Vec::new();
-
-                if pass_index % 2 == 0 {
-                    init_progress_bar(
-                        ((max_y - min_y)
-                            / (config.tool_config.diameter() / 2.0).get::<millimeter>())
-                        .ceil() as usize,
-                    );
-                    set_progress_bar_action("Slicing", progress_bar::Color::Blue, Style::Bold);
-
-                    let mut y = min_y;
-                    while y < max_y {
-                        let mut x = min_x;
-                        let mut start = None;
-                        let mut end = None;
-
-                        while x < max_x {
-                            {
-                                let point = Coord { x, y };
-
-                                if !polygon.contains(&point) ^ invert {
-                                    if start.is_none() {
-                                        start = Some(point.x);
-                                    }
-
-                                    end = Some(point.x);
-                                } else if let (Some(start), Some(end)) = (start.take(), end.take())
-                                {
-                                    lines.push(InfillLine {
-                                        start: Vector2::new(start, point.y),
-                                        end: Vector2::new(end, point.y),
-                                    });
-                                }
-                            }
-
-                            x += (config.tool_config.diameter() / 2.0).get::<millimeter>();
-                        }
-
-                        y += (config.tool_config.diameter() / 2.0).get::<millimeter>();
-                        inc_progress_bar();
-                    }
-                } else {
-                    init_progress_bar(
-                        ((max_x - min_x)
-                            / (config.tool_config.diameter() / 2.0).get::<millimeter>())
-                        .ceil() as usize,
-                    );
-                    set_progress_bar_action("Slicing", progress_bar::Color::Blue, Style::Bold);
-
-                    let mut x = min_x;
-                    while x < max_x {
-                        let mut y = min_y;
-                        let mut start = None;
-                        let mut end = None;
-
-                        while y < max_y {
-                            {
-                                let point = Coord { x, y };
-
-                                if !polygon.contains(&point) ^ invert {
-                                    if start.is_none() {
-                                        start = Some(point.y);
-                                    }
-
-                                    end = Some(point.y);
-                                } else if let (Some(start), Some(end)) = (start.take(), end.take())
-                                {
-                                    lines.push(InfillLine {
-                                        start: Vector2::new(point.x, start),
-                                        end: Vector2::new(point.x, end),
-                                    });
-                                }
-                            }
-
-                            y += (config.tool_config.diameter() / 2.0).get::<millimeter>();
-                        }
-
-                        x += (config.tool_config.diameter() / 2.0).get::<millimeter>();
-                        inc_progress_bar();
-                    }
-                }
-
-                finalize_progress_bar();
-                init_progress_bar(lines.len());
-                set_progress_bar_action("Sorting", progress_bar::Color::Cyan, Style::Bold);
-
-                enum LineSelection {
-                    None,
-                    Start(usize),
-                    End(usize),
-                }
-
-                let mut last_position = Vector2::new(min_x, min_y);
-
-                while !lines.is_empty() {
-                    let mut last_distance = f64::INFINITY;
-                    let mut line_selection = LineSelection::None;
-
-                    for (line_index, line) in lines.iter().enumerate() {
-                        let distance_to_start = (line.start - last_position).norm();
-                        if distance_to_start < last_distance {
-                            last_distance = distance_to_start;
-                            line_selection = LineSelection::Start(line_index)
-                        }
-
-                        let distance_to_end = (line.end - last_position).norm();
-                        if distance_to_end < last_distance {
-                            last_distance = distance_to_end;
-                            line_selection = LineSelection::End(line_index)
-                        }
-                    }
-
-                    match line_selection {
-                        LineSelection::None => unreachable!(),
-                        LineSelection::Start(index) => {
-                            let line = lines.remove(index);
-
-                            config.commands.push(GCommand::MoveTo {
-                                target: (
-                                    Length::new::<millimeter>(line.start.x),
-                                    Length::new::<millimeter>(line.start.y),
-                                ),
-                            });
-                            config.commands.push(GCommand::Cut {
-                                pass_index,
-                                movement: MovementType::Linear,
-                                target: (
-                                    Length::new::<millimeter>(line.end.x),
-                                    Length::new::<millimeter>(line.end.y),
-                                ),
-                            });
-
-                            last_position = line.end;
-                        }
-                        LineSelection::End(index) => {
-                            let line = lines.remove(index);
+                match &contour_rings {
+                    None => {
+                        let direction = if pass_index % 2 == 0 {
+                            ScanDirection::Horizontal
+                        } else {
+                            ScanDirection::Vertical
+                        };
+                        let spacing = (config.tool_config.diameter() / 2.0).get::<millimeter>();
+
+                        let lines = scanline_fill(
+                            &polygon,
+                            direction,
+                            (min_x, min_y, max_x, max_y),
+                            spacing,
+                            invert,
+                        );
 
+                        for [start, end] in
+                            order_lines(lines, Vector2::new(min_x, min_y), spacing * 4.0)
+                        {
                             config.commands.push(GCommand::MoveTo {
                                 target: (
-                                    Length::new::<millimeter>(line.end.x),
-                                    Length::new::<millimeter>(line.end.y),
+                                    Length::new::<millimeter>(start.x),
+                                    Length::new::<millimeter>(start.y),
                                 ),
                             });
                             config.commands.push(GCommand::Cut {
                                 pass_index,
                                 movement: MovementType::Linear,
                                 target: (
-                                    Length::new::<millimeter>(line.start.x),
-                                    Length::new::<millimeter>(line.start.y),
+                                    Length::new::<millimeter>(end.x),
+                                    Length::new::<millimeter>(end.y),
                                 ),
                             });
-
-                            last_position = line.start;
                         }
                     }
-
-                    inc_progress_bar();
+                    Some((stepover, rings)) => {
+                        add_contour_rings_to_gcode_vector(
+                            config.commands,
+                            rings,
+                            *stepover,
+                            pass_index,
+                            config.job_config.arc_tolerance,
+                        );
+                    }
                 }
-
-                finalize_progress_bar();
             }
         }
 
@@ -451,14 +618,14 @@ impl GerberFile {
     }
 
     pub fn debug_render(&self, svg: &mut SvgDocument, include_outline: bool) -> Result<()> {
-        for (index, shape) in self.iter_all_shapes().enumerate() {
+        for (index, (polarity, shape)) in self.iter_all_shapes().enumerate() {
             let mut commands = Vec::new();
 
             shape.debug_render(&mut commands)?;
 
             commands.push(Box::new(End {}));
 
-            let color = match shape.polarity {
+            let color = match polarity {
                 Polarity::Clear => Color::from_rgba(0, (index % 255) as u8, 255, 128),
                 Polarity::Dark => Color::from_rgba(255, (index % 255) as u8, 0, 128),
             };
@@ -482,13 +649,13 @@ impl GerberFile {
     }
 
     pub fn calculate_bounds(&self) -> (f64, f64, f64, f64) {
-        if !self.shapes.is_empty() {
+        if !self.objects.is_empty() {
             let mut min_x = f64::MAX;
             let mut min_y = f64::MAX;
             let mut max_x = f64::MIN;
             let mut max_y = f64::MIN;
 
-            for shape in self.iter_all_shapes() {
+            for (_polarity, shape) in self.iter_all_shapes() {
                 let (local_min_x, local_min_y, local_max_x, local_max_y) = shape.calculate_bounds();
                 min_x = min_x.min(local_min_x);
                 min_y = min_y.min(local_min_y);
@@ -502,6 +669,29 @@ impl GerberFile {
         }
     }
 
+    /// Keeps the dark objects picked by `select` and drops those picked by `exclude`. Clear objects are always kept,
+    /// since they only erase from what is left. Returns how many dark objects remain.
+    pub fn filter_objects(
+        &mut self,
+        select: Option<&ObjectSelection>,
+        exclude: Option<&ObjectSelection>,
+    ) -> usize {
+        self.objects.retain(|object| {
+            let attributes = object.attributes();
+            let selected =
+                select.is_none_or(|select| attributes.selected_by(select).all(|selected| selected));
+            let excluded = exclude
+                .is_some_and(|exclude| attributes.selected_by(exclude).any(|selected| selected));
+
+            matches!(object.polarity(), Polarity::Clear) || (selected && !excluded)
+        });
+
+        self.objects
+            .iter()
+            .filter(|object| matches!(object.polarity(), Polarity::Dark))
+            .count()
+    }
+
     pub fn calculate_svg_bounds(&self) -> (f64, f64, f64, f64) {
         let (min_x, min_y, max_x, max_y) = self.calculate_bounds();
         (min_x, min_y, max_x - min_x, max_y - min_y)
@@ -510,7 +700,7 @@ impl GerberFile {
 
 pub fn load(gerber_file: &mut GerberFile, path: &Path) -> Result<()> {
     // The only reason we don't just construct a gerber file ourselves is so that we can debug render the partial gerber file in the case of an error.
-    assert!(gerber_file.shapes.is_empty());
+    assert!(gerber_file.objects.is_empty());
 
     let file_content = fs::read_to_string(path).context("Failed to read file into memory.")?;
     let parsing_result = parse_gerber_file(Span::new(&file_content));
@@ -525,6 +715,7 @@ pub fn load(gerber_file: &mut GerberFile, path: &Path) -> Result<()> {
 
                 aperture_macros: HashMap::new(),
                 aperture_definitions: HashMap::new(),
+                aperture_functions: HashMap::new(),
 
                 current_point: Vector2::new(0.0, 0.0),
                 current_aperture: 0,
@@ -536,11 +727,31 @@ pub fn load(gerber_file: &mut GerberFile, path: &Path) -> Result<()> {
                 },
 
                 polarity: Polarity::Dark,
+                toggle_polarity: false,
                 mirroring: MirroringMode::None,
                 rotation: 0.0,
                 scaling: 1.0,
+
+                arc_mode: ArcMode::MultiQuadrant,
+                image_mirroring: MirroringMode::None,
+                image_offset: Vector2::zeros(),
+                image_scale: 1.0,
             };
 
+            for command in commands.iter() {
+                command.for_each_command(&mut |command| {
+                    if let GerberCommand::Deprecated(deprecated) = &command.command {
+                        log::warn!(
+                            "{}:{}: {} is deprecated (Gerber spec section 8.1). {}",
+                            path.to_string_lossy(),
+                            command.location_info(),
+                            deprecated.code(),
+                            deprecated.replacement()
+                        );
+                    }
+                });
+            }
+
             for command in commands {
                 let location_info = command.location_info();
 
@@ -555,6 +766,21 @@ pub fn load(gerber_file: &mut GerberFile, path: &Path) -> Result<()> {
                     })?;
             }
 
+            // The deprecated image parameters apply to the whole image, no matter where they are in the file.
+            let (image_transform, image_offset) = context.image_transform();
+            for object in gerber_file.objects.iter_mut() {
+                match object {
+                    GraphicalObject::Shape { shape, .. } => {
+                        shape.transform(&image_transform, image_offset)
+                    }
+                    GraphicalObject::Flash { shapes, .. } => {
+                        for shape in shapes.iter_mut() {
+                            shape.transform(&image_transform, image_offset);
+                        }
+                    }
+                }
+            }
+
             Ok(())
         }
         Err(error) => match error {
@@ -582,6 +808,12 @@ struct Format {
 
 impl Format {
     fn internalize_coordinate_from_span(&self, coordinate: Span) -> Result<f64> {
+        // The integer part can be zero or left out, so the sign is taken from the coordinate itself.
+        let (sign, coordinate) = match coordinate.strip_prefix('-') {
+            Some(coordinate) => (-1.0, coordinate),
+            None => (1.0, coordinate.trim_start_matches('+')),
+        };
+
         // Get decimal part.
         let decimal = coordinate
             .get(
@@ -607,12 +839,11 @@ impl Format {
             0
         };
 
-        let sign = integer.signum();
         let integer = integer.abs();
 
         // Combine.
-        let new_position = sign as f64
-            * (integer as f64 + (decimal as f64 / (10.0f64.powi(self.decimal_digits as i32))));
+        let new_position =
+            sign * (integer as f64 + (decimal as f64 / (10.0f64.powi(self.decimal_digits as i32))));
 
         // Convert to mm for internal representation.
         Ok(self.internalize_coordinate_from_float(new_position))
@@ -622,25 +853,57 @@ impl Format {
         // Convert to mm for internal representation.
         match self.unit_mode {
             UnitMode::Metric => Length::<uom::si::SI<f64>, f64>::new::<millimeter>(coordinate),
-            UnitMode::Imperial => Length::<uom::si::SI<f64>, f64>::new::<mil>(coordinate),
+            UnitMode::Imperial => Length::<uom::si::SI<f64>, f64>::new::<inch>(coordinate),
         }
         .get::<millimeter>()
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 enum DrawMode {
     Linear,
     Clockwise,
     CounterClockwise,
 }
 
+/// How the I and J of arcs are read.
+#[derive(Debug, Clone, Copy)]
+enum ArcMode {
+    /// I and J are the signed offset from the start of the arc to its center. Section 4.7.2
+    MultiQuadrant,
+
+    /// I and J only give the size of the offset, and arcs turn at most 90°. Section 8.1
+    SingleQuadrant,
+}
+
 #[derive(Debug)]
 enum ApertureDefinition<'a> {
     Standard(ApertureTemplate<'a>),
     Block(Vec<GerberCommandContext<'a>>),
 }
 
+impl<'a> ApertureDefinition<'a> {
+    /// What kind of aperture this is, for error messages.
+    fn description(&self) -> &'static str {
+        match self {
+            Self::Standard(ApertureTemplate::Circle {
+                hole_diameter: Some(_),
+                ..
+            }) => "a circle with a hole",
+            Self::Standard(ApertureTemplate::Circle { .. }) => "a circle",
+            Self::Standard(ApertureTemplate::Rectangle {
+                hole_diameter: Some(_),
+                ..
+            }) => "a rectangle with a hole",
+            Self::Standard(ApertureTemplate::Rectangle { .. }) => "a rectangle",
+            Self::Standard(ApertureTemplate::Obround { .. }) => "an obround",
+            Self::Standard(ApertureTemplate::Polygon { .. }) => "a polygon",
+            Self::Standard(ApertureTemplate::Macro { .. }) => "a macro",
+            Self::Block(_) => "a block",
+        }
+    }
+}
+
 #[derive(Debug)]
 struct PlottingContext<'a> {
     user_attributes: HashMap<&'a str, Vec<Span<'a>>>,
@@ -650,6 +913,8 @@ struct PlottingContext<'a> {
 
     aperture_macros: HashMap<&'a str, Vec<MacroContent<'a>>>,
     aperture_definitions: HashMap<u32, ApertureDefinition<'a>>,
+    /// Apertures keep the `.AperFunction` that was in effect when they were defined.
+    aperture_functions: HashMap<u32, String>,
 
     current_point: Vector2<f64>,
     current_aperture: u32,
@@ -657,12 +922,84 @@ struct PlottingContext<'a> {
     format: Format,
 
     polarity: Polarity,
+    /// Set while flashing an aperture block with clear polarity, which toggles the polarity of everything in the block.
+    toggle_polarity: bool,
     mirroring: MirroringMode,
     rotation: f64,
     scaling: f64,
+
+    arc_mode: ArcMode,
+
+    /// The deprecated image parameters, which move the whole image once it has been drawn. Section 8.1
+    image_mirroring: MirroringMode,
+    image_offset: Vector2<f64>,
+    image_scale: f64,
 }
 
 impl<'a> PlottingContext<'a> {
+    /// The polarity new objects are drawn with.
+    fn object_polarity(&self) -> Polarity {
+        if self.toggle_polarity {
+            self.polarity.inverse()
+        } else {
+            self.polarity
+        }
+    }
+
+    fn record_aperture_function(&mut self, identity: u32) {
+        match self
+            .aperture_attributes
+            .get("AperFunction")
+            .and_then(|values| values.first())
+        {
+            Some(function) => {
+                self.aperture_functions
+                    .insert(identity, function.fragment().to_string());
+            }
+            None => {
+                self.aperture_functions.remove(&identity);
+            }
+        }
+    }
+
+    /// The attributes of an object drawn now. Draws and flashes take the aperture function of their aperture, while
+    /// regions take the one in effect. Section 5.3
+    fn object_attributes(&self, region: bool) -> ObjectAttributes {
+        let value = |values: &Vec<Span>, index: usize| {
+            values.get(index).map(|value| value.fragment().to_string())
+        };
+
+        let aperture_function = if region {
+            self.aperture_attributes
+                .get("AperFunction")
+                .and_then(|values| value(values, 0))
+        } else {
+            self.aperture_functions.get(&self.current_aperture).cloned()
+        };
+
+        ObjectAttributes {
+            aperture_function,
+            nets: self
+                .object_attributes
+                .get("N")
+                .map(|values| {
+                    values
+                        .iter()
+                        .map(|net| net.fragment().to_string())
+                        .collect()
+                })
+                .unwrap_or_default(),
+            component: self
+                .object_attributes
+                .get("C")
+                .and_then(|values| value(values, 0)),
+            pin: self
+                .object_attributes
+                .get("P")
+                .and_then(|values| value(values, 0).zip(value(values, 1))),
+        }
+    }
+
     fn process_command(
         &mut self,
         command: GerberCommand<'a>,
@@ -720,18 +1057,23 @@ impl<'a> PlottingContext<'a> {
                         .get(&self.current_aperture)
                         .context("Aperture was never equipped.")?;
 
-                    if let ApertureDefinition::Standard(ApertureTemplate::Circle {
-                        diameter,
-                        hole_diameter,
-                    }) = aperture
-                    {
-                        if hole_diameter.is_none() {
-                            match self.draw_mode {
+                    let draw_mode = self.plot_draw_mode(next_point);
+                    match (aperture, draw_mode) {
+                        (
+                            ApertureDefinition::Standard(ApertureTemplate::Circle {
+                                diameter,
+                                hole_diameter: None,
+                            }),
+                            _,
+                        ) => {
+                            let mut shapes = Vec::new();
+
+                            match draw_mode {
                                 DrawMode::Linear => Shape::line(
                                     ShapeConfiguration {
                                         transform: self.calculate_transformation_matrix(),
-                                        shapes: &mut gerber_file.shapes,
-                                        polarity: self.polarity,
+                                        shapes: &mut shapes,
+                                        polarity: self.object_polarity(),
                                     },
                                     *diameter,
                                     self.current_point,
@@ -746,13 +1088,17 @@ impl<'a> PlottingContext<'a> {
                                             j.context("J parameter is needed for arcs.")?,
                                         )?,
                                     );
-                                    let center = self.current_point + Vector2::new(i, j);
+                                    let center = self.arc_center(
+                                        next_point,
+                                        Vector2::new(i, j),
+                                        ArchDirection::Clockwise,
+                                    )?;
 
                                     Shape::arch(
                                         ShapeConfiguration {
                                             transform: self.calculate_transformation_matrix(),
-                                            shapes: &mut gerber_file.shapes,
-                                            polarity: self.polarity,
+                                            shapes: &mut shapes,
+                                            polarity: self.object_polarity(),
                                         },
                                         *diameter,
                                         center,
@@ -770,13 +1116,17 @@ impl<'a> PlottingContext<'a> {
                                             j.context("J parameter is needed for arcs.")?,
                                         )?,
                                     );
-                                    let center = self.current_point + Vector2::new(i, j);
+                                    let center = self.arc_center(
+                                        next_point,
+                                        Vector2::new(i, j),
+                                        ArchDirection::CounterClockwise,
+                                    )?;
 
                                     Shape::arch(
                                         ShapeConfiguration {
                                             transform: self.calculate_transformation_matrix(),
-                                            shapes: &mut gerber_file.shapes,
-                                            polarity: self.polarity,
+                                            shapes: &mut shapes,
+                                            polarity: self.object_polarity(),
                                         },
                                         *diameter,
                                         center,
@@ -787,12 +1137,63 @@ impl<'a> PlottingContext<'a> {
                                 }
                             };
 
+                            let attributes = self.object_attributes(false);
+                            gerber_file.objects.extend(shapes.into_iter().map(|shape| {
+                                GraphicalObject::Shape {
+                                    shape,
+                                    attributes: attributes.clone(),
+                                }
+                            }));
+
                             self.current_point = next_point;
-                        } else {
-                            bail!("Circles used for line draws cannot have a hole in them.")
                         }
-                    } else {
-                        bail!("Only circles are supported for line draws.")
+                        (
+                            ApertureDefinition::Standard(ApertureTemplate::Rectangle {
+                                width,
+                                height,
+                                hole_diameter: None,
+                            }),
+                            DrawMode::Linear,
+                        ) => {
+                            let mut shapes = Vec::new();
+
+                            Shape::rectangle_line(
+                                ShapeConfiguration {
+                                    transform: self.calculate_transformation_matrix(),
+                                    shapes: &mut shapes,
+                                    polarity: self.object_polarity(),
+                                },
+                                *width,
+                                *height,
+                                self.current_point,
+                                next_point,
+                            );
+
+                            let attributes = self.object_attributes(false);
+                            gerber_file.objects.extend(shapes.into_iter().map(|shape| {
+                                GraphicalObject::Shape {
+                                    shape,
+                                    attributes: attributes.clone(),
+                                }
+                            }));
+
+                            self.current_point = next_point;
+                        }
+                        (
+                            ApertureDefinition::Standard(ApertureTemplate::Rectangle {
+                                hole_diameter: None,
+                                ..
+                            }),
+                            _,
+                        ) => bail!(
+                            "Aperture D{} is a rectangle, which can't be used for arcs. Only solid circles can be used for arcs (Gerber spec section 4.7.2).",
+                            self.current_aperture
+                        ),
+                        (aperture, _) => bail!(
+                            "Aperture D{} is {}, which can't be used for draws. Only solid circles and rectangles can be used for draws (Gerber spec section 4.7.1).",
+                            self.current_aperture,
+                            aperture.description()
+                        ),
                     }
                 }
                 Operation::Move { x, y } => {
@@ -822,6 +1223,10 @@ impl<'a> PlottingContext<'a> {
                         .get(&self.current_aperture)
                         .context("Aperture was never equipped.")?;
 
+                    // The shapes of an aperture are drawn dark, with clear shapes being holes in the aperture.
+                    // The polarity of the flash itself decides what the aperture does to the image.
+                    let mut shapes = Vec::new();
+
                     match aperture {
                         ApertureDefinition::Standard(ApertureTemplate::Circle {
                             diameter,
@@ -829,8 +1234,8 @@ impl<'a> PlottingContext<'a> {
                         }) => Shape::circle(
                             ShapeConfiguration {
                                 transform: self.calculate_transformation_matrix(),
-                                shapes: &mut gerber_file.shapes,
-                                polarity: self.polarity,
+                                shapes: &mut shapes,
+                                polarity: Polarity::Dark,
                             },
                             self.current_point,
                             *diameter,
@@ -843,8 +1248,8 @@ impl<'a> PlottingContext<'a> {
                         }) => Shape::rectangle(
                             ShapeConfiguration {
                                 transform: self.calculate_transformation_matrix(),
-                                shapes: &mut gerber_file.shapes,
-                                polarity: self.polarity,
+                                shapes: &mut shapes,
+                                polarity: Polarity::Dark,
                             },
                             self.current_point,
                             *width,
@@ -858,8 +1263,8 @@ impl<'a> PlottingContext<'a> {
                         }) => Shape::obround(
                             ShapeConfiguration {
                                 transform: self.calculate_transformation_matrix(),
-                                shapes: &mut gerber_file.shapes,
-                                polarity: self.polarity,
+                                shapes: &mut shapes,
+                                polarity: Polarity::Dark,
                             },
                             self.current_point,
                             *width,
@@ -874,8 +1279,8 @@ impl<'a> PlottingContext<'a> {
                         }) => Shape::polygon(
                             ShapeConfiguration {
                                 transform: self.calculate_transformation_matrix(),
-                                shapes: &mut gerber_file.shapes,
-                                polarity: self.polarity,
+                                shapes: &mut shapes,
+                                polarity: Polarity::Dark,
                             },
                             self.current_point,
                             *diameter,
@@ -892,8 +1297,6 @@ impl<'a> PlottingContext<'a> {
                                 .get(name.fragment())
                                 .context("Macro was not defined.")?;
 
-                            let mut shapes = Vec::new();
-
                             let result = shape_from_aperture_macro(
                                 self.calculate_transformation_matrix(),
                                 &self.format,
@@ -904,28 +1307,62 @@ impl<'a> PlottingContext<'a> {
                             );
 
                             // Deferring the error handling until after we push the shape lets us get more into the debug render.
-                            gerber_file.aperture_macro_flashes.push(shapes);
+                            gerber_file.objects.push(GraphicalObject::Flash {
+                                polarity: self.object_polarity(),
+                                shapes,
+                                attributes: self.object_attributes(false),
+                            });
                             result?;
+
+                            return Ok(());
                         }
                         ApertureDefinition::Block(block) => {
-                            for command in block.clone() {
+                            // Section 4.11.3: The objects of a block keep their own polarity when flashed dark,
+                            // and have their polarity toggled when flashed clear.
+                            let block = block.clone();
+                            let outer_polarity = self.polarity;
+                            let outer_toggle_polarity = self.toggle_polarity;
+
+                            self.toggle_polarity =
+                                outer_toggle_polarity ^ matches!(outer_polarity, Polarity::Clear);
+                            self.polarity = Polarity::Dark;
+
+                            let result = block.into_iter().try_for_each(|command| {
+                                let location_info = command.location_info();
+
                                 self.process_command(
                                     command.command,
                                     gerber_file,
                                     gerber_file_path,
                                     offset,
-                                )?;
-                            }
+                                )
+                                .with_context(move || {
+                                    format!(
+                                        "error processing block command: {}:{}",
+                                        gerber_file_path.to_string_lossy(),
+                                        location_info
+                                    )
+                                })
+                            });
+
+                            self.polarity = outer_polarity;
+                            self.toggle_polarity = outer_toggle_polarity;
+
+                            return result;
                         }
                     }
+
+                    gerber_file.objects.push(GraphicalObject::Flash {
+                        polarity: self.object_polarity(),
+                        shapes,
+                        attributes: self.object_attributes(false),
+                    });
                 }
                 Operation::LinearMode => self.draw_mode = DrawMode::Linear,
                 Operation::ClockwiseMode => self.draw_mode = DrawMode::Clockwise,
                 Operation::CounterClockwiseMode => self.draw_mode = DrawMode::CounterClockwise,
             },
-            GerberCommand::MultiQuadrantMode => {
-                // We don't support any other arc mode so this doesn't need to actually do anything.
-            }
+            GerberCommand::MultiQuadrantMode => self.arc_mode = ArcMode::MultiQuadrant,
             GerberCommand::Region(operations) => {
                 let mut operations = operations.into_iter();
 
@@ -944,7 +1381,7 @@ impl<'a> PlottingContext<'a> {
                 }
 
                 let mut shape = Shape {
-                    polarity: self.polarity,
+                    polarity: self.object_polarity(),
                     starting_point: self.current_point,
                     segments: Vec::new(),
                 };
@@ -962,7 +1399,10 @@ impl<'a> PlottingContext<'a> {
                         .context("error processing region")?;
                 }
 
-                gerber_file.shapes.push(shape);
+                gerber_file.objects.push(GraphicalObject::Shape {
+                    shape,
+                    attributes: self.object_attributes(true),
+                });
             }
             GerberCommand::StepAndRepeat {
                 iterations,
@@ -996,6 +1436,7 @@ impl<'a> PlottingContext<'a> {
             }
             GerberCommand::ApertureDefine { identity, template } => {
                 if identity >= 10 {
+                    self.record_aperture_function(identity);
                     self.aperture_definitions
                         .insert(identity, ApertureDefinition::Standard(template));
                 } else {
@@ -1012,12 +1453,56 @@ impl<'a> PlottingContext<'a> {
             GerberCommand::LoadScaling(scaling) => self.scaling = scaling,
             GerberCommand::ApertureBlock(identity, commands) => {
                 if identity >= 10 {
+                    self.record_aperture_function(identity);
                     self.aperture_definitions
                         .insert(identity, ApertureDefinition::Block(commands));
                 } else {
                     bail!("Aperture identities 0 to 9 are reserved.");
                 }
             }
+
+            GerberCommand::Deprecated(command) => match command {
+                DeprecatedCommand::SelectAperture(index) => self.process_command(
+                    GerberCommand::SetAperture(index),
+                    gerber_file,
+                    gerber_file_path,
+                    offset,
+                )?,
+                DeprecatedCommand::UnitMode(new_mode) => self.format.unit_mode = new_mode,
+                DeprecatedCommand::SingleQuadrantMode => self.arc_mode = ArcMode::SingleQuadrant,
+                // M00 can only end the file, which the parser already took care of.
+                DeprecatedCommand::ProgramStop | DeprecatedCommand::OptionalStop => {}
+                // Names are only there for people reading the file.
+                DeprecatedCommand::ImageName(_name) | DeprecatedCommand::LoadName(_name) => {}
+                DeprecatedCommand::ImagePolarity { negative } => {
+                    gerber_file.negative_image = negative
+                }
+                DeprecatedCommand::MirrorImage { a, b } => {
+                    self.image_mirroring = match (a, b) {
+                        (false, false) => MirroringMode::None,
+                        (true, false) => MirroringMode::X,
+                        (false, true) => MirroringMode::Y,
+                        (true, true) => MirroringMode::XAndY,
+                    }
+                }
+                DeprecatedCommand::Offset(image_offset) => {
+                    self.image_offset = Vector2::new(
+                        self.format
+                            .internalize_coordinate_from_float(image_offset.x),
+                        self.format
+                            .internalize_coordinate_from_float(image_offset.y),
+                    )
+                }
+                DeprecatedCommand::ScaleFactor(scale) => {
+                    // Arcs can't be stretched along one axis.
+                    if scale.x != scale.y {
+                        bail!(
+                            "Scale factors that differ between the A and B axes are not supported."
+                        );
+                    }
+                    self.image_scale = scale.x;
+                }
+            },
         }
 
         Ok(())
@@ -1053,26 +1538,32 @@ impl<'a> PlottingContext<'a> {
                     None
                 };
 
-                match self.draw_mode {
+                match self.plot_draw_mode(next_point) {
                     DrawMode::Linear => {
                         shape.segments.push(Segment::Line { end: next_point });
                     }
                     DrawMode::Clockwise => shape.segments.push(Segment::ClockwiseCurve {
                         end: next_point,
-                        center: self.current_point
-                            + Vector2::new(
+                        center: self.arc_center(
+                            next_point,
+                            Vector2::new(
                                 i.context("i parameter missing")?,
                                 j.context("j parameter missing")?,
                             ),
+                            ArchDirection::Clockwise,
+                        )?,
                     }),
                     DrawMode::CounterClockwise => {
                         shape.segments.push(Segment::CounterClockwiseCurve {
                             end: next_point,
-                            center: self.current_point
-                                + Vector2::new(
+                            center: self.arc_center(
+                                next_point,
+                                Vector2::new(
                                     i.context("i parameter missing")?,
                                     j.context("j parameter missing")?,
                                 ),
+                                ArchDirection::CounterClockwise,
+                            )?,
                         })
                     }
                 }
@@ -1099,6 +1590,65 @@ impl<'a> PlottingContext<'a> {
         Ok(())
     }
 
+    /// Single quadrant arcs that end where they start have no length, so they are drawn as lines rather than full
+    /// circles. Section 8.1
+    fn plot_draw_mode(&self, end: Vector2<f64>) -> DrawMode {
+        match (self.arc_mode, self.draw_mode) {
+            (ArcMode::SingleQuadrant, DrawMode::Clockwise | DrawMode::CounterClockwise)
+                if (end - self.current_point).norm() < SINGLE_QUADRANT_TOLERANCE =>
+            {
+                DrawMode::Linear
+            }
+            (_, draw_mode) => draw_mode,
+        }
+    }
+
+    /// The center of an arc from the current point. Multi quadrant arcs give the offset of the center with I and J.
+    /// Single quadrant arcs only give the size of the offset, so the center is the one that makes an arc of at most
+    /// 90°. Section 8.1
+    fn arc_center(
+        &self,
+        end: Vector2<f64>,
+        offset: Vector2<f64>,
+        direction: ArchDirection,
+    ) -> Result<Vector2<f64>> {
+        let start = self.current_point;
+
+        match self.arc_mode {
+            ArcMode::MultiQuadrant => Ok(start + offset),
+            ArcMode::SingleQuadrant => {
+                let clockwise = matches!(direction, ArchDirection::Clockwise);
+                let radius_error =
+                    |center: &Vector2<f64>| ((start - center).norm() - (end - center).norm()).abs();
+
+                [(1.0, 1.0), (-1.0, 1.0), (1.0, -1.0), (-1.0, -1.0)]
+                    .into_iter()
+                    .map(|(x, y)| start + Vector2::new(offset.x.abs() * x, offset.y.abs() * y))
+                    .filter(|center| {
+                        arc_sweep(start, *center, end, clockwise).abs()
+                            <= FRAC_PI_2 + SINGLE_QUADRANT_TOLERANCE
+                    })
+                    .min_by(|a, b| f64::total_cmp(&radius_error(a), &radius_error(b)))
+                    .context("No arc of at most 90° fits the I and J of this single quadrant arc (Gerber spec section 8.1).")
+            }
+        }
+    }
+
+    /// The deprecated image parameters as a transform. The image is scaled and mirrored, and then offset.
+    fn image_transform(&self) -> (Matrix2<f64>, Vector2<f64>) {
+        let mirroring = match self.image_mirroring {
+            MirroringMode::None => Vector2::new(1.0, 1.0),
+            MirroringMode::X => Vector2::new(-1.0, 1.0),
+            MirroringMode::Y => Vector2::new(1.0, -1.0),
+            MirroringMode::XAndY => Vector2::new(-1.0, -1.0),
+        };
+
+        (
+            Matrix2::from_diagonal(&(mirroring * self.image_scale)),
+            self.image_offset,
+        )
+    }
+
     fn calculate_transformation_matrix(&self) -> Matrix2<f64> {
         // Apply mirroring
         let matrix = match self.mirroring {
@@ -1307,3 +1857,471 @@ fn shape_from_aperture_macro(
 
     Ok(())
 }
+
+/// A ring of a contour fill, and how many times the region was shrunk to produce it.
+struct ContourRing {
+    depth: usize,
+    points: Vec<Coord<f64>>,
+}
+
+/// The region a contour fill clears. That's the shapes themselves when inverted and everything around them otherwise.
+fn contour_region(polygon: &MultiPolygon<f64>, invert: bool) -> Result<MultiPolygon<f64>> {
+    if invert {
+        Ok(polygon.clone())
+    } else {
+        let bounds = polygon
+            .bounding_rect()
+            .context("Could not compute bounds for PCB.")?;
+        Ok(MultiPolygon::new(vec![bounds.to_polygon()]).difference(polygon))
+    }
+}
+
+/// Shrinks a region inwards by the stepover until nothing is left, collecting the outlines of each step.
+/// The outline of the region itself is left out, since the outline pass already cuts it.
+fn contour_rings(region: &MultiPolygon<f64>, stepover: f64) -> Result<Vec<ContourRing>> {
+    if stepover <= 0.0 {
+        bail!("The stepover of a contour fill must be larger than zero.");
+    }
+
+    let mut rings = Vec::new();
+    let mut region = region.clone();
+
+    for depth in 0.. {
+        region = region
+            .offset(-stepover)
+            .map_err(|error| anyhow!("Failed to shrink contour fill region: {:?}", error))?;
+
+        if region.0.is_empty() {
+            break;
+        }
+
+        rings.extend(
+            region
+                .iter()
+                .flat_map(|polygon| std::iter::once(polygon.exterior()).chain(polygon.interiors()))
+                .filter(|ring| ring.0.len() > 2)
+                .map(|ring| ContourRing {
+                    depth,
+                    points: ring.0.clone(),
+                }),
+        );
+    }
+
+    Ok(rings)
+}
+
+/// Cuts the rings of a contour fill. After each ring, the tool cuts straight over to the nearest point of a ring one
+/// step further in if there is one close enough, so that nested rings are cut as one continuous spiral.
+/// The tool only lifts to move to another part of the region.
+fn add_contour_rings_to_gcode_vector(
+    commands: &mut Vec<GCommand>,
+    rings: &[ContourRing],
+    stepover: f64,
+    pass_index: usize,
+    arc_tolerance: Length<uom::si::SI<f64>, f64>,
+) {
+    let mut remaining: Vec<&ContourRing> = rings.iter().collect();
+    let mut position: Option<(Coord<f64>, usize)> = None;
+
+    // Finds the remaining ring with the point nearest to a position, and the index of that point.
+    let nearest = |remaining: &[&ContourRing], from: Coord<f64>, depth: Option<usize>| {
+        remaining
+            .iter()
+            .enumerate()
+            .filter(|(_, ring)| depth.is_none_or(|depth| ring.depth == depth))
+            .flat_map(|(ring_index, ring)| {
+                ring.points
+                    .iter()
+                    .enumerate()
+                    .map(move |(point_index, point)| {
+                        let distance =
+                            ((point.x - from.x).powi(2) + (point.y - from.y).powi(2)).sqrt();
+                        (distance, ring_index, point_index)
+                    })
+            })
+            .min_by(|a, b| a.0.total_cmp(&b.0))
+    };
+
+    while !remaining.is_empty() {
+        // Rings one step in from the last one are linked to it if they are within two stepovers of where it ended.
+        // The link then stays within material that has already been cleared.
+        let linked = position.and_then(|(end, depth)| {
+            nearest(&remaining, end, Some(depth + 1))
+                .filter(|(distance, _, _)| *distance <= stepover * 2.0)
+        });
+
+        let (ring_index, point_index, link) = match linked {
+            Some((_, ring_index, point_index)) => (ring_index, point_index, true),
+            None => {
+                let from = position.map_or(Coord { x: 0.0, y: 0.0 }, |(end, _)| end);
+                let (_, ring_index, point_index) =
+                    nearest(&remaining, from, None).expect("No contour ring was selected.");
+                (ring_index, point_index, false)
+            }
+        };
+
+        let ring = remaining.remove(ring_index);
+
+        // Rings are closed, so they can be started from any of their points.
+        let open_ring = &ring.points[..ring.points.len() - 1];
+        let points: Vec<Coord<f64>> = open_ring[point_index % open_ring.len()..]
+            .iter()
+            .chain(open_ring[..point_index % open_ring.len()].iter())
+            .chain(std::iter::once(&open_ring[point_index % open_ring.len()]))
+            .copied()
+            .collect();
+
+        let start = points[0];
+        let target = (
+            Length::new::<millimeter>(start.x),
+            Length::new::<millimeter>(start.y),
+        );
+        if link {
+            commands.push(GCommand::Cut {
+                pass_index,
+                movement: MovementType::Linear,
+                target,
+            });
+        } else {
+            commands.push(GCommand::MoveTo { target });
+        }
+
+        for segment in fit_arcs(&points, arc_tolerance.get::<millimeter>()) {
+            commands.push(segment_to_cut(&segment, pass_index));
+        }
+
+        position = Some((start, ring.depth));
+    }
+}
+
+/// Holding tabs are never placed where the outline turns by more than this many radians, so that they don't end up
+/// on corners where they would be hard to break off and sand down.
+const MAX_TAB_TURNING: f64 = std::f64::consts::PI / 9.0;
+
+/// How holding tabs are cut into the outlines of a gerber file.
+struct TabLayout<'a> {
+    tabs: &'a HoldingTabs,
+
+    /// The length of the outline the tool skips for each tab, in millimeters. This is the width of the tab plus the
+    /// diameter of the tool, since the tool cuts on both sides of its path.
+    length: f64,
+
+    /// The height spindles rise to over a tab.
+    top: Length<uom::si::SI<f64>, f64>,
+}
+
+/// Cuts a closed ring, leaving holding tabs standing along it if there are any.
+fn add_ring_to_gcode_vector(
+    commands: &mut Vec<GCommand>,
+    ring: &[Coord<f64>],
+    tab_layout: Option<&TabLayout>,
+    pass_index: usize,
+    arc_tolerance: Length<uom::si::SI<f64>, f64>,
+) {
+    let tab_starts = match tab_layout {
+        // Degenerate rings are too small for tabs anyway.
+        Some(tab_layout) if ring.len() > 3 => place_tabs(ring, tab_layout, pass_index),
+        _ => Vec::new(),
+    };
+
+    let Some(tab_layout) = tab_layout.filter(|_| !tab_starts.is_empty()) else {
+        add_point_string_to_gcode_vector(commands, ring.iter(), pass_index, arc_tolerance);
+        return;
+    };
+
+    let distances = ring_distances(ring);
+    let perimeter = distances[distances.len() - 1];
+    let to_target = |point: Coord<f64>| {
+        (
+            Length::new::<millimeter>(point.x),
+            Length::new::<millimeter>(point.y),
+        )
+    };
+
+    // The cut starts at the end of the first tab and goes all the way around to finish with the same tab.
+    commands.push(GCommand::MoveTo {
+        target: to_target(point_along_ring(
+            ring,
+            &distances,
+            tab_starts[0] + tab_layout.length,
+        )),
+    });
+
+    for (tab_index, tab_start) in tab_starts.iter().enumerate() {
+        let next_tab_start = tab_starts
+            .get(tab_index + 1)
+            .copied()
+            .unwrap_or(tab_starts[0] + perimeter);
+
+        let cut = ring_section(
+            ring,
+            &distances,
+            tab_start + tab_layout.length,
+            next_tab_start,
+        );
+        for segment in fit_arcs(&cut, arc_tolerance.get::<millimeter>()) {
+            commands.push(segment_to_cut(&segment, pass_index));
+        }
+
+        commands.push(GCommand::SetHoldingTab(Some(tab_layout.top)));
+        let tab = ring_section(
+            ring,
+            &distances,
+            next_tab_start,
+            next_tab_start + tab_layout.length,
+        );
+        commands.extend(tab.into_iter().skip(1).map(|point| GCommand::Cut {
+            pass_index,
+            movement: MovementType::Linear,
+            target: to_target(point),
+        }));
+        commands.push(GCommand::SetHoldingTab(None));
+    }
+}
+
+/// Spreads holding tabs evenly along a closed ring, shifting them along it to keep them off of corners.
+/// Returns how far along the ring each tab starts, in order.
+fn place_tabs(ring: &[Coord<f64>], tab_layout: &TabLayout, pass_index: usize) -> Vec<f64> {
+    let distances = ring_distances(ring);
+    let perimeter = distances[distances.len() - 1];
+
+    // Tabs are placed the same way on every pass, so problems with them are only reported once.
+    let report = pass_index == 0;
+
+    let requested = match (tab_layout.tabs.count, tab_layout.tabs.spacing) {
+        (Some(count), _) => count,
+        (None, Some(spacing)) => {
+            (perimeter / spacing.get::<millimeter>()).round().max(1.0) as usize
+        }
+        (None, None) => 4,
+    };
+
+    // At least as much of the outline must be cut as is left standing in tabs.
+    let count = requested.min((perimeter / (tab_layout.length * 2.0)) as usize);
+    if count < requested && report {
+        log::warn!(
+            "An outline {:.1} mm long only has room for {} of {} holding tabs.",
+            perimeter,
+            count,
+            requested
+        );
+    }
+
+    if count == 0 {
+        return Vec::new();
+    }
+
+    // How much the outline turns at each of its corners.
+    let turning: Vec<(f64, f64)> = (0..ring.len() - 1)
+        .map(|index| {
+            let previous = ring[(index + ring.len() - 2) % (ring.len() - 1)];
+            let point = ring[index];
+            let next = ring[index + 1];
+
+            let incoming = (point.y - previous.y).atan2(point.x - previous.x);
+            let outgoing = (next.y - point.y).atan2(next.x - point.x);
+
+            let mut angle = outgoing - incoming;
+            while angle > std::f64::consts::PI {
+                angle -= std::f64::consts::TAU;
+            }
+            while angle < -std::f64::consts::PI {
+                angle += std::f64::consts::TAU;
+            }
+
+            (distances[index], angle.abs())
+        })
+        .collect();
+
+    let turning_within = |start: f64| {
+        turning
+            .iter()
+            .filter(|(distance, _)| {
+                // The ring is walked twice so that tabs can cross its start.
+                [*distance, *distance + perimeter]
+                    .iter()
+                    .any(|distance| *distance > start && *distance < start + tab_layout.length)
+            })
+            .map(|(_, angle)| angle)
+            .sum::<f64>()
+    };
+
+    let slot_length = perimeter / count as f64;
+    let max_shift = (slot_length - tab_layout.length) / 2.0;
+    let shift_step = tab_layout.length / 4.0;
+
+    let mut tab_starts = Vec::new();
+    for tab_index in 0..count {
+        let ideal_start = slot_length * (tab_index as f64 + 0.5) - tab_layout.length / 2.0;
+
+        // Try the ideal position first, then further and further away from it on either side.
+        let tab_start = (0..)
+            .map(|step: usize| {
+                let distance = shift_step * step.div_ceil(2) as f64;
+                if step.is_multiple_of(2) {
+                    distance
+                } else {
+                    -distance
+                }
+            })
+            .take_while(|shift| shift.abs() <= max_shift)
+            .map(|shift| (ideal_start + shift).rem_euclid(perimeter))
+            .find(|tab_start| turning_within(*tab_start) <= MAX_TAB_TURNING);
+
+        match tab_start {
+            Some(tab_start) => tab_starts.push(tab_start),
+            None => {
+                if report {
+                    let position = point_along_ring(ring, &distances, ideal_start);
+                    log::warn!(
+                        "Could not place a holding tab near X{:.3} Y{:.3} without putting it on a corner.",
+                        position.x,
+                        position.y
+                    );
+                }
+            }
+        }
+    }
+
+    tab_starts.sort_by(f64::total_cmp);
+    tab_starts
+}
+
+/// How far along a ring each of its points is.
+fn ring_distances(ring: &[Coord<f64>]) -> Vec<f64> {
+    let mut distance = 0.0;
+
+    std::iter::once(0.0)
+        .chain(ring.windows(2).map(|pair| {
+            distance += ((pair[1].x - pair[0].x).powi(2) + (pair[1].y - pair[0].y).powi(2)).sqrt();
+            distance
+        }))
+        .collect()
+}
+
+/// The point a distance along a closed ring. Distances past the end wrap around.
+fn point_along_ring(ring: &[Coord<f64>], distances: &[f64], distance: f64) -> Coord<f64> {
+    let perimeter = distances[distances.len() - 1];
+    let distance = distance.rem_euclid(perimeter);
+
+    let index = distances
+        .partition_point(|point_distance| *point_distance <= distance)
+        .clamp(1, ring.len() - 1);
+    let (start, end) = (ring[index - 1], ring[index]);
+    let length = distances[index] - distances[index - 1];
+
+    if length <= 0.0 {
+        return start;
+    }
+
+    let fraction = (distance - distances[index - 1]) / length;
+    Coord {
+        x: start.x + (end.x - start.x) * fraction,
+        y: start.y + (end.y - start.y) * fraction,
+    }
+}
+
+/// The points of a closed ring between two distances along it. The end may be up to one lap past the end of the ring.
+fn ring_section(ring: &[Coord<f64>], distances: &[f64], start: f64, end: f64) -> Vec<Coord<f64>> {
+    let perimeter = distances[distances.len() - 1];
+
+    let length = (end - start).clamp(0.0, perimeter);
+    let start = start.rem_euclid(perimeter);
+    let end = start + length;
+
+    let corners = (0..2).flat_map(|lap| {
+        distances[..distances.len() - 1]
+            .iter()
+            .zip(ring.iter())
+            .map(move |(distance, point)| (distance + perimeter * lap as f64, *point))
+    });
+
+    std::iter::once(point_along_ring(ring, distances, start))
+        .chain(
+            corners
+                .filter(|(distance, _)| *distance > start && *distance < end)
+                .map(|(_, point)| point),
+        )
+        .chain(std::iter::once(point_along_ring(ring, distances, end)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use geo::LineString;
+
+    fn square(min: f64, max: f64) -> LineString<f64> {
+        LineString::from(vec![
+            (min, min),
+            (max, min),
+            (max, max),
+            (min, max),
+            (min, min),
+        ])
+    }
+
+    /// The depth and the (min, max) corner of every ring's bounding box, sorted so the order rings come out in
+    /// doesn't matter.
+    fn ring_bounds(rings: &[ContourRing]) -> Vec<(usize, f64, f64)> {
+        let mut bounds: Vec<_> = rings
+            .iter()
+            .map(|ring| {
+                let bounds = LineString::from(ring.points.clone())
+                    .bounding_rect()
+                    .expect("ring has no points");
+
+                // Rings of a square stay square, so one corner describes them.
+                assert!((bounds.min().x - bounds.min().y).abs() < 1e-6);
+                assert!((bounds.max().x - bounds.max().y).abs() < 1e-6);
+                (ring.depth, bounds.min().x, bounds.max().x)
+            })
+            .collect();
+        bounds.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        bounds
+    }
+
+    fn assert_rings(rings: &[ContourRing], expected: &[(usize, f64, f64)]) {
+        let bounds = ring_bounds(rings);
+        assert_eq!(bounds.len(), expected.len(), "{:?}", bounds);
+        for (actual, expected) in bounds.iter().zip(expected) {
+            assert_eq!(actual.0, expected.0, "{:?}", bounds);
+            assert!((actual.1 - expected.1).abs() < 1e-6, "{:?}", bounds);
+            assert!((actual.2 - expected.2).abs() < 1e-6, "{:?}", bounds);
+        }
+    }
+
+    #[test]
+    fn contour_rings_of_square_shrink_by_stepover() {
+        let polygon = MultiPolygon::new(vec![Polygon::new(square(0.0, 10.0), vec![])]);
+
+        let rings = contour_rings(&contour_region(&polygon, true).unwrap(), 1.5).unwrap();
+        assert_rings(&rings, &[(0, 1.5, 8.5), (1, 3.0, 7.0), (2, 4.5, 5.5)]);
+
+        // Around a lone square there's nothing to clear, since the bounds are the square itself.
+        let rings = contour_rings(&contour_region(&polygon, false).unwrap(), 1.5).unwrap();
+        assert!(rings.is_empty());
+    }
+
+    #[test]
+    fn contour_rings_of_square_with_hole_shrink_by_stepover() {
+        let polygon = MultiPolygon::new(vec![Polygon::new(
+            square(0.0, 20.0),
+            vec![square(7.0, 13.0)],
+        )]);
+
+        // The frame shrinks from both sides until the hole swallows it.
+        let rings = contour_rings(&contour_region(&polygon, true).unwrap(), 2.0).unwrap();
+        assert_rings(&rings, &[(0, 2.0, 18.0), (0, 5.0, 15.0)]);
+
+        // Outside of the shape is only the hole.
+        let rings = contour_rings(&contour_region(&polygon, false).unwrap(), 2.0).unwrap();
+        assert_rings(&rings, &[(0, 9.0, 11.0)]);
+    }
+
+    #[test]
+    fn contour_rings_need_a_stepover() {
+        let polygon = MultiPolygon::new(vec![Polygon::new(square(0.0, 10.0), vec![])]);
+        assert!(contour_rings(&polygon, 0.0).is_err());
+    }
+}
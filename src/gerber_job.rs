@@ -0,0 +1,316 @@
+//! KiCad and other CAD tools write a gerber job file next to their gerbers. It is JSON, and lists every gerber file of
+//! the board along with the layer it holds, the thickness of the board and how many copper layers it has. Forge files
+//! can find their layers through one, and new forge files can be started from one.
+
+use std::{
+    fmt::Write,
+    fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::{bail, Context, Result};
+use semver::Version;
+use serde::Deserialize;
+use uom::si::length::{millimeter, Length};
+
+use crate::{
+    arguments::NewCommand,
+    layers::{LayerMap, LayerRole},
+};
+
+#[derive(Debug, Deserialize)]
+pub struct GerberJob {
+    #[serde(rename = "GeneralSpecs")]
+    pub general_specs: GeneralSpecs,
+
+    /// The gerber files of the board. Drill files aren't listed.
+    #[serde(rename = "FilesAttributes", default)]
+    pub files_attributes: Vec<JobFile>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GeneralSpecs {
+    #[serde(rename = "ProjectId", default)]
+    pub project_id: Option<ProjectId>,
+
+    /// In millimeters.
+    #[serde(rename = "BoardThickness", default)]
+    pub board_thickness: Option<f64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ProjectId {
+    #[serde(rename = "Name")]
+    pub name: String,
+
+    #[serde(rename = "Revision", default)]
+    pub revision: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct JobFile {
+    /// Relative to the job file.
+    #[serde(rename = "Path")]
+    pub path: PathBuf,
+
+    /// Written the same way as the `TF.FileFunction` attribute, such as `Copper,L1,Top`.
+    #[serde(rename = "FileFunction")]
+    pub file_function: String,
+
+    #[serde(rename = "FilePolarity", default)]
+    pub file_polarity: Option<String>,
+}
+
+impl GerberJob {
+    pub fn load(path: &Path) -> Result<Self> {
+        let source = fs::read_to_string(path).context("Failed to read gerber job file.")?;
+
+        serde_json::from_str(&source)
+            .with_context(|| format!("Failed to decode gerber job file {:?}.", path))
+    }
+
+    pub fn board_thickness(&self) -> Option<Length<uom::si::SI<f64>, f64>> {
+        self.general_specs
+            .board_thickness
+            .map(Length::new::<millimeter>)
+    }
+}
+
+/// The fabrication processes new forge files can be set up for.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Process {
+    /// Drill and cut out the board with a mill, then engrave an etching mask and solder mask into paint with a laser.
+    LaserEtch,
+
+    /// Make the whole board with a mill, isolating the copper instead of etching it.
+    Mill,
+}
+
+impl FromStr for Process {
+    type Err = String;
+
+    fn from_str(process: &str) -> Result<Self, Self::Err> {
+        match process {
+            "laser_etch" => Ok(Self::LaserEtch),
+            "mill" => Ok(Self::Mill),
+            _ => Err(format!(
+                "Unknown process `{}`. Supported processes are laser_etch and mill.",
+                process
+            )),
+        }
+    }
+}
+
+/// A stage of a new forge file, which is only written if the board has its layer.
+struct TemplateStage {
+    comment: &'static str,
+    stage: &'static str,
+    layer: LayerRole,
+    options: &'static [&'static str],
+}
+
+impl Process {
+    /// The gcode files of the process, in the order they are made.
+    fn gcode_files(&self) -> Vec<(&'static str, Vec<TemplateStage>)> {
+        let drill = || {
+            vec![
+                TemplateStage {
+                    comment: "Drill the plated holes.",
+                    stage: "cut_board",
+                    layer: LayerRole::PlatedDrill,
+                    options: &[],
+                },
+                TemplateStage {
+                    comment: "Drill the non-plated holes.",
+                    stage: "cut_board",
+                    layer: LayerRole::NonPlatedDrill,
+                    options: &[],
+                },
+            ]
+        };
+
+        match self {
+            Self::LaserEtch => vec![
+                (
+                    "drill.gcode",
+                    drill()
+                        .into_iter()
+                        .chain([TemplateStage {
+                            comment: "Cut out the board.",
+                            stage: "cut_board",
+                            layer: LayerRole::Profile,
+                            options: &["select_lines: outer"],
+                        }])
+                        .collect(),
+                ),
+                (
+                    "jig.gcode",
+                    vec![TemplateStage {
+                        comment: "Cut the outline of the board into cardboard with the laser, to hold the board in place.",
+                        stage: "cut_board",
+                        layer: LayerRole::Profile,
+                        options: &["select_lines: outer"],
+                    }],
+                ),
+                (
+                    "etch_top.gcode",
+                    vec![TemplateStage {
+                        comment: "Engrave the paint off of the copper that is etched away.",
+                        stage: "engrave_mask",
+                        layer: LayerRole::TopCopper,
+                        options: &[],
+                    }],
+                ),
+                (
+                    "etch_bottom.gcode",
+                    vec![TemplateStage {
+                        comment: "Engrave the paint off of the copper that is etched away.",
+                        stage: "engrave_mask",
+                        layer: LayerRole::BottomCopper,
+                        options: &[],
+                    }],
+                ),
+                (
+                    "solder_mask_top.gcode",
+                    vec![TemplateStage {
+                        comment: "Engrave the second coat of paint off of the pads.",
+                        stage: "engrave_mask",
+                        layer: LayerRole::TopSoldermask,
+                        options: &["invert: true"],
+                    }],
+                ),
+                (
+                    "solder_mask_bottom.gcode",
+                    vec![TemplateStage {
+                        comment: "Engrave the second coat of paint off of the pads.",
+                        stage: "engrave_mask",
+                        layer: LayerRole::BottomSoldermask,
+                        options: &["invert: true"],
+                    }],
+                ),
+            ],
+            Self::Mill => vec![
+                (
+                    "isolation_top.gcode",
+                    vec![TemplateStage {
+                        comment: "Mill around the traces and pads.",
+                        stage: "isolation_mill",
+                        layer: LayerRole::TopCopper,
+                        options: &[],
+                    }],
+                ),
+                (
+                    "isolation_bottom.gcode",
+                    vec![TemplateStage {
+                        comment: "Mill around the traces and pads.",
+                        stage: "isolation_mill",
+                        layer: LayerRole::BottomCopper,
+                        options: &[],
+                    }],
+                ),
+                ("drill.gcode", drill()),
+                (
+                    "outline.gcode",
+                    vec![TemplateStage {
+                        comment: "Cut out the board, leaving tabs to hold it in place.",
+                        stage: "cut_board",
+                        layer: LayerRole::Profile,
+                        options: &[
+                            "select_lines: outer",
+                            "tabs:",
+                            "  count: 4",
+                            "  width: 2 mm",
+                            "  height: 0.5 mm",
+                        ],
+                    }],
+                ),
+            ],
+        }
+    }
+}
+
+/// Writes a forge file for a board's gerber job, set up for a fabrication process.
+pub fn new_forge_file(new_configuration: NewCommand) -> Result<()> {
+    let forge_file_path = &new_configuration.forge_file_path;
+    if forge_file_path.exists() {
+        bail!("Forge file {:?} already exists.", forge_file_path);
+    }
+
+    let gerber_job_path = &new_configuration.gerber_job;
+    log::info!("Read Gerber Job File: {:?}", gerber_job_path);
+    let gerber_job = GerberJob::load(gerber_job_path)?;
+    let layers = LayerMap::from_gerber_job(&gerber_job, Path::new(""), gerber_job_path)?;
+
+    // The forge file refers to the job relative to itself.
+    let forge_file_directory = forge_file_path
+        .parent()
+        .context("Could not get parent directory of forge file.")?;
+    let gerber_job_reference = match gerber_job_path.strip_prefix(forge_file_directory) {
+        Ok(path) => path.to_path_buf(),
+        Err(_) => gerber_job_path
+            .canonicalize()
+            .context("Failed to find gerber job file.")?,
+    };
+
+    let project_id = gerber_job.general_specs.project_id.as_ref();
+    let project_name = project_id.map_or("board", |project_id| project_id.name.as_str());
+    let board_version = project_id
+        .and_then(|project_id| project_id.revision.as_deref())
+        .and_then(|revision| Version::parse(revision).ok())
+        .unwrap_or(Version::new(0, 1, 0));
+
+    let mut forge_file = String::new();
+    writeln!(forge_file, "project_name: {:?}", project_name)?;
+    writeln!(forge_file, "board_version: {}", board_version)?;
+    writeln!(forge_file)?;
+    writeln!(
+        forge_file,
+        "# Stages find their files in the gerber job by the layer they name."
+    )?;
+    writeln!(forge_file, "gerber_job: {:?}", gerber_job_reference)?;
+    writeln!(forge_file)?;
+    writeln!(
+        forge_file,
+        "# Stages without a machine_config use the default_engraver and default_cutter of the global config."
+    )?;
+    if let Some(thickness) = gerber_job.general_specs.board_thickness {
+        writeln!(
+            forge_file,
+            "# The board is {} mm thick, so cutting profiles must cut at least that deep.",
+            thickness
+        )?;
+    }
+    writeln!(forge_file, "gcode_files:")?;
+
+    for (gcode_file, stages) in new_configuration.process.gcode_files() {
+        let stages: Vec<TemplateStage> = stages
+            .into_iter()
+            .filter(|stage| {
+                layers
+                    .layers
+                    .iter()
+                    .any(|layer| layer.role == Some(stage.layer))
+            })
+            .collect();
+
+        if stages.is_empty() {
+            continue;
+        }
+
+        writeln!(forge_file, "  {}:", gcode_file)?;
+        for stage in stages {
+            writeln!(forge_file, "    # {}", stage.comment)?;
+            writeln!(forge_file, "    - !{}", stage.stage)?;
+            writeln!(forge_file, "        layer: {}", stage.layer)?;
+            for option in stage.options {
+                writeln!(forge_file, "        {}", option)?;
+            }
+        }
+    }
+
+    fs::write(forge_file_path, forge_file).context("Failed to write forge file.")?;
+    log::info!("Wrote Forge File: {:?}", forge_file_path);
+
+    Ok(())
+}
@@ -0,0 +1,328 @@
+//! Boards are never perfectly flat, so shallow cuts made at a fixed depth come out uneven.
+//! The board's surface is probed on a grid, and cuts are moved up and down to follow it.
+
+use std::{fs, path::Path};
+
+use anyhow::{bail, Context, Result};
+use nalgebra::{Vector2, Vector3};
+use uom::si::length::{millimeter, Length};
+
+use crate::{
+    config::machine::ProbeConfig,
+    gcode_generation::{GCodeConfig, GCommand, MovementType, Tool, ToolSelection},
+    geometry::arc_sweep,
+    parsing::UnitMode,
+};
+
+/// Probed positions closer together than this, in millimeters, are considered to be on the same grid line.
+const GRID_TOLERANCE: f64 = 0.01;
+
+/// The height of the board's surface across a grid of probed points. Positions and heights are in millimeters.
+#[derive(Debug)]
+pub struct HeightMap {
+    xs: Vec<f64>,
+    ys: Vec<f64>,
+
+    /// One row of heights for each Y position.
+    heights: Vec<f64>,
+}
+
+impl HeightMap {
+    pub fn load(path: &Path) -> Result<Self> {
+        let source = fs::read_to_string(path).context("Failed to read probe log.")?;
+
+        Self::parse(&source).with_context(|| format!("Failed to parse probe log {:?}.", path))
+    }
+
+    /// Probe logs have one probed point per line, given as its X, Y and Z position in millimeters, separated by
+    /// spaces or commas. Any further values on a line are ignored, so the logs written by LinuxCNC's `PROBEOPEN` can be
+    /// used directly. Empty lines and lines starting with `;`, `#` or `(` are skipped.
+    ///
+    /// Heights are relative to the Z zero of the machine, so it must not be zeroed again between probing and cutting.
+    pub fn parse(source: &str) -> Result<Self> {
+        let mut points = Vec::new();
+
+        for (line_index, line) in source.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with([';', '#', '(']) {
+                continue;
+            }
+
+            let values: Vec<f64> = line
+                .split(|character: char| character.is_whitespace() || character == ',')
+                .filter(|value| !value.is_empty())
+                .take(3)
+                .map(|value| value.parse::<f64>())
+                .collect::<Result<_, _>>()
+                .with_context(|| format!("Line {}: expected X, Y and Z values.", line_index + 1))?;
+
+            match values[..] {
+                [x, y, z] => points.push(Vector3::new(x, y, z)),
+                _ => bail!("Line {}: expected X, Y and Z values.", line_index + 1),
+            }
+        }
+
+        if points.is_empty() {
+            bail!("Probe log does not contain any points.");
+        }
+
+        let grid_lines = |values: Vec<f64>| {
+            let mut values = values;
+            values.sort_by(f64::total_cmp);
+            values.dedup_by(|a, b| (*a - *b).abs() < GRID_TOLERANCE);
+            values
+        };
+
+        let xs = grid_lines(points.iter().map(|point| point.x).collect());
+        let ys = grid_lines(points.iter().map(|point| point.y).collect());
+
+        let mut heights = vec![None; xs.len() * ys.len()];
+        for point in points.iter() {
+            let column = nearest_index(&xs, point.x);
+            let row = nearest_index(&ys, point.y);
+
+            // Points that were probed more than once use the last result.
+            heights[row * xs.len() + column] = Some(point.z);
+        }
+
+        let heights = heights
+            .into_iter()
+            .enumerate()
+            .map(|(index, height)| {
+                height.with_context(|| {
+                    format!(
+                        "Probed points must form a grid, but X{} Y{} is missing.",
+                        xs[index % xs.len()],
+                        ys[index / xs.len()]
+                    )
+                })
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Self { xs, ys, heights })
+    }
+
+    /// The height of the board at a position, interpolated between the four surrounding probed points.
+    /// Positions outside of the grid use the height at its edge.
+    pub fn height_at(
+        &self,
+        x: Length<uom::si::SI<f64>, f64>,
+        y: Length<uom::si::SI<f64>, f64>,
+    ) -> Length<uom::si::SI<f64>, f64> {
+        let (column, next_column, x_fraction) = interpolate(&self.xs, x.get::<millimeter>());
+        let (row, next_row, y_fraction) = interpolate(&self.ys, y.get::<millimeter>());
+
+        let height = |column: usize, row: usize| self.heights[row * self.xs.len() + column];
+
+        let bottom =
+            height(column, row) * (1.0 - x_fraction) + height(next_column, row) * x_fraction;
+        let top = height(column, next_row) * (1.0 - x_fraction)
+            + height(next_column, next_row) * x_fraction;
+
+        Length::new::<millimeter>(bottom * (1.0 - y_fraction) + top * y_fraction)
+    }
+
+    /// Cuts are split into moves no longer than half of the grid spacing so that they follow the board closely.
+    /// Cuts don't need to be split if the grid is a single point.
+    pub fn segment_length(&self) -> Option<Length<uom::si::SI<f64>, f64>> {
+        self.xs
+            .windows(2)
+            .chain(self.ys.windows(2))
+            .map(|pair| pair[1] - pair[0])
+            .min_by(f64::total_cmp)
+            .map(|spacing| Length::new::<millimeter>(spacing / 2.0))
+    }
+
+    /// Splits the cuts of a stage so that none of them are longer than the segment length.
+    /// Cuts made before the stage moves to a known position are left as they are.
+    pub fn subdivide_cuts(&self, commands: Vec<GCommand>) -> Vec<GCommand> {
+        let Some(segment_length) = self.segment_length() else {
+            return commands;
+        };
+        let segment_length = segment_length.get::<millimeter>();
+
+        let to_vector = |(x, y): (Length<uom::si::SI<f64>, f64>, Length<uom::si::SI<f64>, f64>)| {
+            Vector2::new(x.get::<millimeter>(), y.get::<millimeter>())
+        };
+        let to_target = |point: Vector2<f64>| {
+            (
+                Length::new::<millimeter>(point.x),
+                Length::new::<millimeter>(point.y),
+            )
+        };
+
+        let mut position: Option<Vector2<f64>> = None;
+        let mut subdivided = Vec::with_capacity(commands.len());
+
+        for command in commands {
+            match (&command, position) {
+                (
+                    GCommand::Cut {
+                        pass_index,
+                        movement,
+                        target,
+                    },
+                    Some(start),
+                ) => {
+                    let end = to_vector(*target);
+
+                    let points: Vec<Vector2<f64>> = match movement {
+                        MovementType::Linear => {
+                            let steps = ((end - start).norm() / segment_length).ceil().max(1.0);
+
+                            (1..steps as usize)
+                                .map(|step| start + (end - start) * (step as f64 / steps))
+                                .collect()
+                        }
+                        MovementType::ClockwiseCurve { center }
+                        | MovementType::CounterClockwiseCurve { center } => {
+                            let center = to_vector(*center);
+                            let clockwise = matches!(movement, MovementType::ClockwiseCurve { .. });
+
+                            let radius = (start - center).norm();
+                            let start_angle = (start.y - center.y).atan2(start.x - center.x);
+                            let sweep = arc_sweep(start, center, end, clockwise);
+                            let steps = (radius * sweep.abs() / segment_length).ceil().max(1.0);
+
+                            (1..steps as usize)
+                                .map(|step| {
+                                    let angle = start_angle + sweep * step as f64 / steps;
+                                    center + Vector2::new(angle.cos(), angle.sin()) * radius
+                                })
+                                .collect()
+                        }
+                    };
+
+                    subdivided.extend(points.into_iter().map(|point| GCommand::Cut {
+                        pass_index: *pass_index,
+                        movement: movement.clone(),
+                        target: to_target(point),
+                    }));
+
+                    position = Some(end);
+                }
+                (GCommand::Cut { target, .. }, None)
+                | (GCommand::MoveTo { target }, _)
+                | (GCommand::Drill { target, .. }, _) => position = Some(to_vector(*target)),
+                _ => {}
+            }
+
+            subdivided.push(command);
+        }
+
+        subdivided
+    }
+}
+
+fn nearest_index(values: &[f64], value: f64) -> usize {
+    values
+        .iter()
+        .enumerate()
+        .min_by(|(_, a), (_, b)| f64::total_cmp(&(*a - value).abs(), &(*b - value).abs()))
+        .map_or(0, |(index, _)| index)
+}
+
+/// Finds the grid lines on either side of a value, and how far it is between them.
+fn interpolate(values: &[f64], value: f64) -> (usize, usize, f64) {
+    let last = values.len() - 1;
+
+    if value <= values[0] {
+        return (0, 0, 0.0);
+    }
+    if value >= values[last] {
+        return (last, last, 0.0);
+    }
+
+    let next = values
+        .partition_point(|grid_line| *grid_line <= value)
+        .min(last);
+    let previous = next - 1;
+
+    (
+        previous,
+        next,
+        (value - values[previous]) / (values[next] - values[previous]),
+    )
+}
+
+/// Generates the GCode to probe a grid of points covering the given bounds, in millimeters.
+/// The grid is probed in rows, alternating direction to keep the moves between points short.
+pub fn generate_probe_grid(
+    config: GCodeConfig,
+    (min_x, min_y, max_x, max_y): (f64, f64, f64, f64),
+    grid_spacing: Length<uom::si::SI<f64>, f64>,
+    probe: &ProbeConfig,
+    probe_log: &str,
+) -> Result<()> {
+    let grid_spacing = grid_spacing.get::<millimeter>();
+    if grid_spacing <= 0.0 {
+        bail!("The grid spacing must be larger than zero.");
+    }
+
+    let (spindle, travel_height, cut_depth, plunge_speed, pass_depth) =
+        match (&config.job_config.tool_power, config.tool_config) {
+            (
+                crate::config::machine::ToolConfig::EndMill {
+                    spindle_speed: _,
+                    travel_height,
+                    cut_depth,
+                    plunge_speed,
+                    pass_depth,
+                    work_speed: _,
+                },
+                ToolSelection::Spindle { spindle, bit: _ },
+            ) => (
+                spindle,
+                *travel_height,
+                *cut_depth,
+                *plunge_speed,
+                *pass_depth,
+            ),
+            _ => bail!("Height maps can only be probed with a spindle."),
+        };
+
+    // Points are spread evenly so that the grid reaches all the way to the edges of the board.
+    let grid_points = |min: f64, max: f64| {
+        let intervals = ((max - min) / grid_spacing).ceil().max(1.0);
+        (0..=intervals as usize)
+            .map(move |index| min + (max - min) * index as f64 / intervals)
+            .collect::<Vec<_>>()
+    };
+    let xs = grid_points(min_x, max_x);
+    let ys = grid_points(min_y, max_y);
+
+    log::info!("Probing a grid of {} by {} points.", xs.len(), ys.len());
+
+    config.commands.extend([
+        GCommand::UnitMode(UnitMode::Metric),
+        GCommand::SetRapidTransverseSpeed(config.machine_config.jog_speed),
+        GCommand::EquipTool(Tool::Spindle {
+            max_spindle_speed: spindle.max_speed,
+            diameter: config.tool_config.diameter(),
+            plunge_speed,
+            travel_height,
+            cut_depth,
+            pass_depth,
+        }),
+        GCommand::StartProbeLog(probe_log.to_string()),
+    ]);
+
+    for (row, y) in ys.iter().enumerate() {
+        let mut row_xs = xs.clone();
+        if row % 2 == 1 {
+            row_xs.reverse();
+        }
+
+        config
+            .commands
+            .extend(row_xs.into_iter().map(|x| GCommand::Probe {
+                target: (Length::new::<millimeter>(x), Length::new::<millimeter>(*y)),
+                search_depth: probe.search_depth,
+                speed: probe.speed,
+            }));
+    }
+
+    config.commands.push(GCommand::EndProbeLog);
+
+    Ok(())
+}
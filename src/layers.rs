@@ -0,0 +1,540 @@
+//! Works out which layer of the board each gerber and drill file of a fabrication output directory holds, so that stages
+//! can name a layer instead of a file. Files are classified by their `TF.FileFunction` attribute, or by the file name
+//! conventions of KiCad and Altium for files written without attributes.
+
+use std::{
+    fmt::Display,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+use itertools::Itertools;
+use serde::Deserialize;
+use uom::si::length::Length;
+
+use crate::{
+    arguments::LayersCommand,
+    forge_file::{CutBoardFile, ForgeFile, GerberSource},
+    gerber_job::GerberJob,
+    parsing::{
+        drill::{self, HeaderCommand},
+        gerber::{self, Attribute, GerberCommand},
+    },
+};
+
+/// What a file is used for when making the board.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LayerRole {
+    #[serde(rename = "top_copper")]
+    TopCopper,
+    #[serde(rename = "bottom_copper")]
+    BottomCopper,
+    #[serde(rename = "top_soldermask")]
+    TopSoldermask,
+    #[serde(rename = "bottom_soldermask")]
+    BottomSoldermask,
+    #[serde(rename = "top_legend")]
+    TopLegend,
+    #[serde(rename = "bottom_legend")]
+    BottomLegend,
+    #[serde(rename = "top_paste")]
+    TopPaste,
+    #[serde(rename = "bottom_paste")]
+    BottomPaste,
+    #[serde(rename = "profile")]
+    Profile,
+    #[serde(rename = "plated_drill")]
+    PlatedDrill,
+    #[serde(rename = "non_plated_drill")]
+    NonPlatedDrill,
+}
+
+impl Display for LayerRole {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::TopCopper => write!(f, "top_copper"),
+            Self::BottomCopper => write!(f, "bottom_copper"),
+            Self::TopSoldermask => write!(f, "top_soldermask"),
+            Self::BottomSoldermask => write!(f, "bottom_soldermask"),
+            Self::TopLegend => write!(f, "top_legend"),
+            Self::BottomLegend => write!(f, "bottom_legend"),
+            Self::TopPaste => write!(f, "top_paste"),
+            Self::BottomPaste => write!(f, "bottom_paste"),
+            Self::Profile => write!(f, "profile"),
+            Self::PlatedDrill => write!(f, "plated_drill"),
+            Self::NonPlatedDrill => write!(f, "non_plated_drill"),
+        }
+    }
+}
+
+impl LayerRole {
+    /// Classifies a file by the values of its `TF.FileFunction` attribute. Section 5.6.3
+    fn from_file_function(values: &[String]) -> Option<Self> {
+        // Gerber job files spell some functions differently, such as `SolderMask` for `Soldermask`.
+        let values: Vec<String> = values.iter().map(|value| value.to_lowercase()).collect();
+        let values: Vec<&str> = values.iter().map(String::as_str).collect();
+
+        match values[..] {
+            ["copper", _, "top", ..] => Some(Self::TopCopper),
+            ["copper", _, "bot", ..] => Some(Self::BottomCopper),
+            ["soldermask", "top", ..] => Some(Self::TopSoldermask),
+            ["soldermask", "bot", ..] => Some(Self::BottomSoldermask),
+            ["legend", "top", ..] => Some(Self::TopLegend),
+            ["legend", "bot", ..] => Some(Self::BottomLegend),
+            ["paste" | "solderpaste", "top", ..] => Some(Self::TopPaste),
+            ["paste" | "solderpaste", "bot", ..] => Some(Self::BottomPaste),
+            ["profile", ..] => Some(Self::Profile),
+            // Files that mix both kinds of holes are drilled the same way as plated ones.
+            ["plated", ..] | ["mixedplating", ..] => Some(Self::PlatedDrill),
+            ["nonplated", ..] => Some(Self::NonPlatedDrill),
+            _ => None,
+        }
+    }
+
+    /// Classifies a file written without attributes by its name.
+    fn from_file_name(path: &Path) -> Option<Self> {
+        // KiCad ends the name of each file with the name of its layer, such as `board-F_Cu.gbr`.
+        // Older versions wrote `board-F.Cu.gbr` instead.
+        const KICAD_SUFFIXES: [(&str, LayerRole); 15] = [
+            ("-f_cu", LayerRole::TopCopper),
+            ("-b_cu", LayerRole::BottomCopper),
+            ("-f_mask", LayerRole::TopSoldermask),
+            ("-b_mask", LayerRole::BottomSoldermask),
+            ("-f_silkscreen", LayerRole::TopLegend),
+            ("-f_silks", LayerRole::TopLegend),
+            ("-b_silkscreen", LayerRole::BottomLegend),
+            ("-b_silks", LayerRole::BottomLegend),
+            ("-f_paste", LayerRole::TopPaste),
+            ("-b_paste", LayerRole::BottomPaste),
+            ("-edge_cuts", LayerRole::Profile),
+            ("-npth", LayerRole::NonPlatedDrill),
+            ("-pth", LayerRole::PlatedDrill),
+            // Altium names its drill files the same way.
+            ("-nonplated", LayerRole::NonPlatedDrill),
+            ("-plated", LayerRole::PlatedDrill),
+        ];
+
+        // Altium and other tools following Protel's conventions use the extension instead, such as `board.GTL`.
+        const PROTEL_EXTENSIONS: [(&str, LayerRole); 10] = [
+            ("gtl", LayerRole::TopCopper),
+            ("gbl", LayerRole::BottomCopper),
+            ("gts", LayerRole::TopSoldermask),
+            ("gbs", LayerRole::BottomSoldermask),
+            ("gto", LayerRole::TopLegend),
+            ("gbo", LayerRole::BottomLegend),
+            ("gtp", LayerRole::TopPaste),
+            ("gbp", LayerRole::BottomPaste),
+            ("gko", LayerRole::Profile),
+            ("gm1", LayerRole::Profile),
+        ];
+
+        let stem = path.file_stem()?.to_str()?.to_lowercase().replace('.', "_");
+        let extension = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .map(str::to_lowercase);
+
+        KICAD_SUFFIXES
+            .iter()
+            .find(|(suffix, _role)| stem.ends_with(suffix))
+            .or_else(|| {
+                PROTEL_EXTENSIONS.iter().find(|(protel_extension, _role)| {
+                    extension.as_deref() == Some(protel_extension)
+                })
+            })
+            .map(|(_name, role)| *role)
+    }
+
+    /// Whether the layer is part of the back side of the board.
+    pub fn is_bottom(&self) -> bool {
+        matches!(
+            self,
+            Self::BottomCopper | Self::BottomSoldermask | Self::BottomLegend | Self::BottomPaste
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LayerKind {
+    Gerber,
+    Drill,
+}
+
+impl LayerKind {
+    /// Guesses the kind of a file that couldn't be parsed from its extension.
+    fn from_extension(path: &Path) -> Self {
+        match path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .map(str::to_lowercase)
+            .as_deref()
+        {
+            Some("drl" | "xln" | "exc" | "txt" | "nc") => Self::Drill,
+            _ => Self::Gerber,
+        }
+    }
+}
+
+/// A gerber or drill file found in the layer directory.
+#[derive(Debug)]
+pub struct Layer {
+    /// The path of the file, relative to the forge file.
+    pub path: PathBuf,
+    pub kind: LayerKind,
+
+    /// Files that can't be classified are kept so that they can be listed.
+    pub role: Option<LayerRole>,
+
+    /// The values of the file's `TF.FileFunction` attribute, if it has one.
+    pub file_function: Option<Vec<String>>,
+
+    /// Set by `TF.FilePolarity,Negative`, which means the dark objects of the file mark where there is no material.
+    /// Only listed by `pcb_forge layers`. Stages have to set `invert` for negative layers themselves.
+    pub negative: bool,
+}
+
+impl Layer {
+    /// Reads a file's attributes to work out what it is. Returns `None` for files that are neither gerber nor drill
+    /// files, such as the forge file itself.
+    fn detect(full_path: &Path, path: PathBuf) -> Option<Self> {
+        let source = fs::read_to_string(full_path).ok();
+        let parsed = source.as_deref().and_then(|source| {
+            read_gerber_attributes(source)
+                .map(|attributes| (LayerKind::Gerber, attributes))
+                .or_else(|| {
+                    read_drill_attributes(source).map(|attributes| (LayerKind::Drill, attributes))
+                })
+        });
+
+        let (kind, attributes) = match parsed {
+            Some(parsed) => parsed,
+            // Files that fail to parse are still picked up by name, so that stages report the parsing error.
+            None => {
+                LayerRole::from_file_name(&path)?;
+                (LayerKind::from_extension(&path), Vec::new())
+            }
+        };
+
+        let attribute = |name: &str| {
+            attributes
+                .iter()
+                .find(|(attribute_name, _values)| attribute_name == name)
+                .map(|(_name, values)| values.clone())
+        };
+
+        let file_function = attribute("FileFunction");
+        let negative = attribute("FilePolarity")
+            .is_some_and(|values| values.first().is_some_and(|value| value == "Negative"));
+
+        let role = file_function
+            .as_deref()
+            .and_then(LayerRole::from_file_function)
+            .or_else(|| LayerRole::from_file_name(&path));
+
+        Some(Self {
+            path,
+            kind,
+            role,
+            file_function,
+            negative,
+        })
+    }
+}
+
+/// The file attributes of a gerber file, or `None` if it isn't one.
+fn read_gerber_attributes(source: &str) -> Option<Vec<(String, Vec<String>)>> {
+    let (_remainder, commands) = gerber::parse_gerber_file(gerber::Span::new(source)).ok()?;
+
+    Some(
+        commands
+            .into_iter()
+            .filter_map(|command| match command.command {
+                GerberCommand::Attribute(Attribute::File { name, values }) => {
+                    Some(attribute_strings(name, values))
+                }
+                _ => None,
+            })
+            .collect(),
+    )
+}
+
+/// The file attributes of a drill file, or `None` if it isn't one.
+fn read_drill_attributes(source: &str) -> Option<Vec<(String, Vec<String>)>> {
+    let (_remainder, (header_commands, _commands)) =
+        drill::parse_drill_file(drill::Span::new(source)).ok()?;
+
+    Some(
+        header_commands
+            .into_iter()
+            .filter_map(|command| match command.command {
+                HeaderCommand::Attribute(Attribute::File { name, values }) => {
+                    Some(attribute_strings(name, values))
+                }
+                _ => None,
+            })
+            .collect(),
+    )
+}
+
+fn attribute_strings(name: gerber::Span, values: Vec<gerber::Span>) -> (String, Vec<String>) {
+    (
+        name.fragment().to_string(),
+        values
+            .into_iter()
+            .map(|value| value.fragment().trim().to_string())
+            .collect(),
+    )
+}
+
+/// Every gerber and drill file of a board, along with the layer each one holds.
+#[derive(Debug)]
+pub struct LayerMap {
+    pub layers: Vec<Layer>,
+
+    /// Only known when the layers come from a gerber job file.
+    pub board_thickness: Option<Length<uom::si::SI<f64>, f64>>,
+}
+
+impl LayerMap {
+    /// Classifies the files of `directory`, which is relative to the forge file's directory.
+    pub fn discover(forge_file_directory: &Path, directory: &Path) -> Result<Self> {
+        let entries = fs::read_dir(forge_file_directory.join(directory))
+            .with_context(|| format!("Failed to read layer directory {:?}.", directory))?;
+
+        let mut layers = Vec::new();
+        for entry in entries {
+            let entry = entry.context("Failed to read layer directory entry.")?;
+            if !entry.file_type().is_ok_and(|file_type| file_type.is_file()) {
+                continue;
+            }
+
+            let path = directory.join(entry.file_name());
+            if let Some(layer) = Layer::detect(&entry.path(), path) {
+                layers.push(layer);
+            }
+        }
+
+        Ok(Self {
+            layers: Self::sorted(layers),
+            board_thickness: None,
+        })
+    }
+
+    /// The layers listed by a gerber job file, along with the drill files next to it, which job files don't list.
+    /// `gerber_job_path` is relative to the forge file's directory.
+    pub fn from_gerber_job(
+        gerber_job: &GerberJob,
+        forge_file_directory: &Path,
+        gerber_job_path: &Path,
+    ) -> Result<Self> {
+        let gerber_job_directory = gerber_job_path
+            .parent()
+            .context("Could not get parent directory of gerber job file.")?;
+
+        let mut layers: Vec<Layer> = gerber_job
+            .files_attributes
+            .iter()
+            .map(|file| {
+                let file_function: Vec<String> = file
+                    .file_function
+                    .split(',')
+                    .map(|value| value.trim().to_string())
+                    .collect();
+
+                Layer {
+                    path: gerber_job_directory.join(&file.path),
+                    kind: LayerKind::Gerber,
+                    role: LayerRole::from_file_function(&file_function),
+                    file_function: Some(file_function),
+                    negative: file.file_polarity.as_deref() == Some("Negative"),
+                }
+            })
+            .collect();
+
+        layers.extend(
+            Self::discover(forge_file_directory, gerber_job_directory)?
+                .layers
+                .into_iter()
+                .filter(|layer| layer.kind == LayerKind::Drill),
+        );
+
+        Ok(Self {
+            layers: Self::sorted(layers),
+            board_thickness: gerber_job.board_thickness(),
+        })
+    }
+
+    /// Layers are listed by role, with the files that don't hold one last.
+    fn sorted(mut layers: Vec<Layer>) -> Vec<Layer> {
+        layers.sort_by(|a, b| {
+            Ord::cmp(
+                &(a.role.is_none(), a.role, &a.path),
+                &(b.role.is_none(), b.role, &b.path),
+            )
+        });
+
+        layers
+    }
+
+    /// Finds the layers of a forge file through its `gerber_job`, or else in its `layer_directory`.
+    pub fn for_forge_file(forge_file: &ForgeFile, forge_file_directory: &Path) -> Result<Self> {
+        match (&forge_file.gerber_job, &forge_file.layer_directory) {
+            (Some(_), Some(_)) => {
+                bail!("Forge files can't have both a gerber_job and a layer_directory.")
+            }
+            (Some(gerber_job_path), None) => {
+                let gerber_job = GerberJob::load(&forge_file_directory.join(gerber_job_path))?;
+                Self::from_gerber_job(&gerber_job, forge_file_directory, gerber_job_path)
+            }
+            (None, layer_directory) => Self::discover(
+                forge_file_directory,
+                layer_directory.as_deref().unwrap_or(Path::new("")),
+            ),
+        }
+    }
+
+    pub fn find(&self, role: LayerRole) -> Result<&Layer> {
+        let mut found = self.layers.iter().filter(|layer| layer.role == Some(role));
+
+        match (found.next(), found.next()) {
+            (Some(layer), None) => Ok(layer),
+            (Some(first), Some(second)) => bail!(
+                "Both {:?} and {:?} hold the {} layer. Name the file of the stage instead.",
+                first.path,
+                second.path,
+                role
+            ),
+            (None, _) => bail!("No file holds the {} layer.", role),
+        }
+    }
+}
+
+/// The layers of a forge file, which are only discovered once a stage names one.
+pub struct ProjectLayers<'a> {
+    forge_file: &'a ForgeFile,
+    forge_file_directory: &'a Path,
+    map: Option<LayerMap>,
+}
+
+impl<'a> ProjectLayers<'a> {
+    pub fn new(forge_file: &'a ForgeFile, forge_file_directory: &'a Path) -> Self {
+        Self {
+            forge_file,
+            forge_file_directory,
+            map: None,
+        }
+    }
+
+    fn map(&mut self) -> Result<&LayerMap> {
+        match &mut self.map {
+            Some(map) => Ok(map),
+            map => Ok(map.insert(LayerMap::for_forge_file(
+                self.forge_file,
+                self.forge_file_directory,
+            )?)),
+        }
+    }
+
+    pub fn find(&mut self, role: LayerRole) -> Result<&Layer> {
+        self.map()?.find(role)
+    }
+
+    /// The thickness of the board, if the forge file has a gerber job that gives it.
+    pub fn board_thickness(&mut self) -> Result<Option<Length<uom::si::SI<f64>, f64>>> {
+        if self.forge_file.gerber_job.is_none() {
+            return Ok(None);
+        }
+
+        Ok(self.map()?.board_thickness)
+    }
+
+    /// The path of a stage's gerber file, relative to the forge file.
+    pub fn gerber_file(&mut self, source: &GerberSource) -> Result<PathBuf> {
+        match source {
+            GerberSource::File { gerber_file } => Ok(gerber_file.clone()),
+            GerberSource::Layer { layer } => {
+                let found = self.find(*layer)?;
+                if found.kind != LayerKind::Gerber {
+                    bail!(
+                        "The {} layer is the drill file {:?}, but the stage needs a gerber file.",
+                        layer,
+                        found.path
+                    );
+                }
+
+                Ok(found.path.clone())
+            }
+        }
+    }
+
+    /// The path of the file a board is cut from, relative to the forge file, along with its kind.
+    pub fn cut_board_file(&mut self, file: &CutBoardFile) -> Result<(LayerKind, PathBuf)> {
+        match file {
+            CutBoardFile::Gerber { gerber_file, .. } => {
+                Ok((LayerKind::Gerber, gerber_file.clone()))
+            }
+            CutBoardFile::Drill { drill_file } => Ok((LayerKind::Drill, drill_file.clone())),
+            CutBoardFile::Layer { layer, .. } => {
+                let found = self.find(*layer)?;
+                Ok((found.kind, found.path.clone()))
+            }
+        }
+    }
+}
+
+pub fn layers(layers_configuration: LayersCommand) -> Result<()> {
+    let map = match &layers_configuration.directory {
+        Some(directory) => LayerMap::discover(Path::new(""), directory)?,
+        None => {
+            let forge_file_path = &layers_configuration.forge_file_path;
+            log::info!("Read Forge File: {:?}", forge_file_path);
+
+            let forge_file =
+                ForgeFile::load_from_path(forge_file_path).context("Failed to load forge file.")?;
+            let forge_file_directory = forge_file_path
+                .parent()
+                .context("Could not get parent directory of forge file.")?;
+
+            LayerMap::for_forge_file(&forge_file, forge_file_directory)?
+        }
+    };
+
+    for layer in map.layers.iter() {
+        let role = layer
+            .role
+            .map_or(String::from("unknown"), |role| role.to_string());
+        let detection = match &layer.file_function {
+            Some(values) => format!("TF.FileFunction,{}", values.iter().join(",")),
+            None if layer.role.is_some() => String::from("file name"),
+            None => String::from("no file function"),
+        };
+        let polarity = if layer.negative { ", negative" } else { "" };
+
+        println!(
+            "{:<18} {} ({}{})",
+            role,
+            layer.path.display(),
+            detection,
+            polarity
+        );
+    }
+
+    // Layers are sorted by role, so files holding the same layer are next to each other.
+    for (count, role) in map
+        .layers
+        .iter()
+        .filter_map(|layer| layer.role)
+        .dedup_with_count()
+    {
+        if count > 1 {
+            log::warn!(
+                "{} files hold the {} layer, so stages must name which one they use.",
+                count,
+                role
+            );
+        }
+    }
+
+    Ok(())
+}
@@ -1,34 +1,59 @@
 use std::{
+    collections::{BTreeSet, HashMap},
     fs,
     path::{Path, PathBuf},
+    rc::Rc,
 };
 
 use anyhow::{bail, Context, Result};
 
 mod arguments;
+mod check;
 mod config;
+mod estimate;
 use camino::Utf8PathBuf;
 use config::{
-    machine::{JobConfig, Machine},
+    machine::{JobConfig, Machine, WorkspaceSize},
     Config,
 };
-use forge_file::LineSelection;
+use forge_file::{HoldingTabs, LineSelection, ObjectSelection, Panel, Registration};
 use gcode_generation::GCommand;
 use itertools::Itertools;
-use uom::si::length::{millimeter, Length};
+use nalgebra::Isometry2;
+use uom::si::{
+    acceleration::Acceleration,
+    length::{millimeter, Length},
+};
 
 mod drill_file;
+mod gcode_dialect;
 mod gcode_generation;
+mod gcode_simulation;
 mod geometry;
 mod gerber_file;
+mod gerber_job;
+mod height_map;
+mod layers;
+mod panel;
 mod parsing;
+mod registration;
+mod toolpath_preview;
 
 use crate::{
     config::machine::Tool,
     forge_file::ForgeFile,
-    gcode_generation::{BoardSide, GCodeConfig, GCodeFile, ToolSelection},
-    gerber_file::GerberFile,
+    gcode_dialect::GCodeDialect,
+    gcode_generation::{transform_commands, BoardSide, GCodeConfig, GCodeFile, ToolSelection},
+    gcode_simulation::{JobStatistics, SimulatedStage},
+    gerber_file::{GerberFile, Toolpath},
+    height_map::HeightMap,
+    layers::{LayerKind, ProjectLayers},
+    panel::{BoardBounds, PanelInstance, PanelProject},
+    toolpath_preview::ToolpathPreview,
 };
+
+/// The resolution of toolpath previews. Large boards are scaled down to keep the image a reasonable size.
+const PREVIEW_PIXELS_PER_MILLIMETER: f64 = 20.0;
 mod forge_file;
 
 fn main() {
@@ -39,6 +64,7 @@ fn main() {
 
     if let Err(error) = trampoline() {
         log::error!("Fatal error: {:?}", error);
+        std::process::exit(1);
     }
 }
 
@@ -61,6 +87,18 @@ fn trampoline() -> Result<()> {
 
     match arguments.command {
         arguments::CommandEnum::Build(build_configuration) => build(build_configuration, config),
+        arguments::CommandEnum::Check(check_configuration) => {
+            check::check(check_configuration, config)
+        }
+        arguments::CommandEnum::Estimate(estimate_configuration) => {
+            estimate::estimate(estimate_configuration, config)
+        }
+        arguments::CommandEnum::Layers(layers_configuration) => {
+            layers::layers(layers_configuration)
+        }
+        arguments::CommandEnum::New(new_configuration) => {
+            gerber_job::new_forge_file(new_configuration)
+        }
     }
 }
 
@@ -69,26 +107,422 @@ fn build(build_configuration: arguments::BuildCommand, global_config: Config) ->
     let forge_file = ForgeFile::load_from_path(&build_configuration.forge_file_path)
         .context("Failed to load forge file.")?;
 
-    let forge_file_directory = build_configuration
-        .forge_file_path
+    let target_directory = build_configuration
+        .target_directory
+        .join(&forge_file.project_name);
+
+    fs::create_dir_all(&target_directory).context("Failed to create output directory.")?;
+
+    let debug_directory = build_configuration
+        .debug
+        .then(|| target_directory.join("debug"));
+
+    let generated_files = generate_gcode_files(
+        &forge_file,
+        &build_configuration.forge_file_path,
+        debug_directory.as_deref(),
+        &global_config,
+    )?;
+
+    for generated_file in generated_files {
+        let GeneratedGCodeFile {
+            path: gcode_file_path,
+            gcode_file,
+            dialect,
+            backside_offset,
+            stage_spans,
+        } = &generated_file;
+
+        let output_file = target_directory.join(gcode_file_path);
+
+        check_workspace(gcode_file, *backside_offset, stage_spans).with_context(|| {
+            format!("GCode file {:?} does not fit the machine.", gcode_file_path)
+        })?;
+
+        let output = gcode_file
+            .to_string(*backside_offset, dialect.backend())
+            .with_context(|| format!("Failed to produce GCode for file: {:?}", gcode_file_path))?;
+        fs::write(&output_file, output).context("Failed to save GCode file.")?;
+
+        let stage_statistics = generated_file.estimate();
+        log::info!(
+            "Estimate for {:?}: {}",
+            gcode_file_path,
+            stage_statistics.iter().sum::<JobStatistics>()
+        );
+        for (span, statistics) in stage_spans.iter().zip(stage_statistics.iter()) {
+            log::info!("\t{}: {}", span.description, statistics);
+        }
+
+        if build_configuration.preview {
+            let preview = ToolpathPreview::new(gcode_file, *backside_offset);
+
+            fs::write(
+                output_file.with_extension("preview.svg"),
+                preview.render_svg()?,
+            )
+            .context("Failed to save toolpath preview SVG file.")?;
+            fs::write(
+                output_file.with_extension("preview.png"),
+                preview.render_png(PREVIEW_PIXELS_PER_MILLIMETER)?,
+            )
+            .context("Failed to save toolpath preview PNG file.")?;
+        }
+    }
+
+    Ok(())
+}
+
+/// A GCode file of a forge file, generated but not yet written out.
+struct GeneratedGCodeFile {
+    /// Where the file goes, relative to the target directory.
+    path: PathBuf,
+    gcode_file: GCodeFile,
+    dialect: GCodeDialect,
+    backside_offset: Length<uom::si::SI<f64>, f64>,
+    stage_spans: Vec<StageSpan>,
+}
+
+impl GeneratedGCodeFile {
+    /// Estimates what it takes to run each stage of the file.
+    fn estimate(&self) -> Vec<JobStatistics> {
+        let stages: Vec<_> = self
+            .stage_spans
+            .iter()
+            .map(|span| SimulatedStage {
+                start: span.start,
+                acceleration: span.acceleration,
+            })
+            .collect();
+
+        gcode_simulation::simulate(&self.gcode_file, self.dialect.backend(), &stages)
+    }
+}
+
+/// Generates the commands of every GCode file of a forge file.
+/// Debug output of each stage is placed in the debug directory if one is given.
+fn generate_gcode_files(
+    forge_file: &ForgeFile,
+    forge_file_path: &Path,
+    debug_directory: Option<&Path>,
+    global_config: &Config,
+) -> Result<Vec<GeneratedGCodeFile>> {
+    let forge_file_directory = forge_file_path
         .parent()
         .context("Could not get parent directory of forge file.")?;
-    let config_directory = Config::get_path()
-        .map(|path| {
-            path.parent()
-                .map(|path| path.to_path_buf())
-                .context("Could not get parent directory of global config file.")
+
+    // Other projects on the panel are loaded up front so that all of the boards can be laid out together.
+    let mut panel_projects: Vec<(PathBuf, ForgeFile)> = Vec::new();
+    for board in forge_file
+        .panel
+        .iter()
+        .flat_map(|panel| panel.boards.iter())
+    {
+        if let Some(path) = &board.forge_file {
+            if !panel_projects
+                .iter()
+                .any(|(loaded_path, _)| loaded_path == path)
+            {
+                let project = ForgeFile::load_from_path(&forge_file_directory.join(path))
+                    .with_context(|| format!("Failed to load panel board {:?}.", path))?;
+                panel_projects.push((path.clone(), project));
+            }
+        }
+    }
+
+    let mut projects = vec![generate_project_stages(
+        forge_file,
+        forge_file_path,
+        debug_directory,
+        global_config,
+    )?];
+    for (path, project) in panel_projects.iter() {
+        log::info!("Starting panel board {:?}", path);
+
+        projects.push(generate_project_stages(
+            project,
+            &forge_file_directory.join(path),
+            debug_directory
+                .map(|debug_directory| debug_directory.join(&project.project_name))
+                .as_deref(),
+            global_config,
+        )?);
+    }
+
+    let mut instances = match &forge_file.panel {
+        Some(panel) => {
+            let board_projects: Vec<usize> = panel
+                .boards
+                .iter()
+                .map(|board| {
+                    board.forge_file.as_ref().map_or(0, |path| {
+                        1 + panel_projects
+                            .iter()
+                            .position(|(loaded_path, _)| loaded_path == path)
+                            .expect("Panel board was not loaded.")
+                    })
+                })
+                .collect();
+
+            let panel_project_names: Vec<PanelProject> = std::iter::once(forge_file)
+                .chain(panel_projects.iter().map(|(_, project)| project))
+                .zip(projects.iter())
+                .map(|(project, stages)| PanelProject {
+                    name: &project.project_name,
+                    bounds: stages.bounds,
+                })
+                .collect();
+
+            panel::layout(panel, &board_projects, &panel_project_names)?
+        }
+        None => vec![PanelInstance {
+            project: 0,
+            transform: Isometry2::identity(),
+            description: forge_file.project_name.clone(),
+        }],
+    };
+
+    let panel_bounds = |instances: &[PanelInstance]| {
+        let mut panel_bounds = BoardBounds::default();
+        for instance in instances.iter() {
+            panel_bounds.union(
+                &projects[instance.project]
+                    .bounds
+                    .transformed(&instance.transform),
+            );
+        }
+        panel_bounds
+    };
+
+    // The placement moves the board, or the whole panel, to where it sits on the bed.
+    let mut stock_bounds = panel_bounds(&instances);
+    if let Some(panel) = &forge_file.panel {
+        let margin = panel.margin.get::<millimeter>();
+        stock_bounds.include((
+            stock_bounds.min.x - margin,
+            stock_bounds.min.y - margin,
+            stock_bounds.max.x + margin,
+            stock_bounds.max.y + margin,
+        ));
+    }
+    let placement = panel::placement_transform(&forge_file.placement, &stock_bounds)?;
+    for instance in instances.iter_mut() {
+        instance.transform = placement * instance.transform;
+    }
+
+    let panel_bounds = panel_bounds(&instances);
+
+    // The back side is mirrored about the middle of the panel, so that it lines up once the stock is flipped over.
+    // Stock held by registration pins is flipped over the pins instead.
+    let backside_offset = if let Some(registration) = &forge_file.registration {
+        registration::pin_axis(registration, &panel_bounds)? * 2.0
+    } else if forge_file.align_backside && !panel_bounds.is_empty() {
+        panel_bounds.min.x + panel_bounds.max.x
+    } else {
+        0.0
+    };
+
+    let mut generated_files = Vec::new();
+
+    let gcode_file_paths: BTreeSet<&PathBuf> = projects
+        .iter()
+        .flat_map(|project| project.files.keys())
+        .collect();
+
+    for gcode_file_path in gcode_file_paths {
+        let files: Vec<Option<&GeneratedProjectFile>> = projects
+            .iter()
+            .map(|project| project.files.get(gcode_file_path))
+            .collect();
+
+        let mut dialect = None;
+        for file in files.iter().flatten() {
+            match (dialect, file.dialect) {
+                (Some(panel_dialect), Some(file_dialect)) if panel_dialect != file_dialect => {
+                    bail!(
+                        "Boards on the panel use both the {} and {} dialects for {:?}.",
+                        panel_dialect,
+                        file_dialect,
+                        gcode_file_path
+                    )
+                }
+                (None, file_dialect) => dialect = file_dialect,
+                _ => {}
+            }
+        }
+
+        let mut gcode = Vec::new();
+        let mut stage_spans: Vec<StageSpan> = Vec::new();
+
+        // Each stage is done for every board before moving on to the next stage, so that tools are changed as
+        // little as possible.
+        let stage_count = files
+            .iter()
+            .flatten()
+            .map(|file| file.stages.len())
+            .max()
+            .unwrap_or(0);
+
+        for stage_index in 0..stage_count {
+            let mut registration_pins_made = false;
+
+            for instance in instances.iter() {
+                let Some(stage) =
+                    files[instance.project].and_then(|file| file.stages.get(stage_index))
+                else {
+                    continue;
+                };
+
+                if stage.registration_pins.is_some() && registration_pins_made {
+                    continue;
+                }
+
+                if let Some(panel) = &forge_file.panel {
+                    check_panel_fits(panel, &panel_bounds, &stage.span)?;
+                }
+
+                // Copies of a stage are reported together.
+                if stage_spans
+                    .last()
+                    .is_none_or(|span| span.description != stage.span.description)
+                {
+                    stage_spans.push(StageSpan {
+                        start: gcode.len(),
+                        description: stage.span.description.clone(),
+                        workspace: stage.span.workspace,
+                        acceleration: stage.span.acceleration,
+                    });
+                }
+
+                let start = gcode.len();
+                gcode.extend(stage.commands.iter().cloned());
+
+                match &stage.registration_pins {
+                    Some(machine_selection) => {
+                        // The pins are already in place on the bed, so they don't move with the board.
+                        let registration = forge_file.registration.as_ref().context(
+                            "Registration pin stages need a `registration` section in the forge file.",
+                        )?;
+
+                        generate_registration_pins(
+                            registration,
+                            &panel_bounds,
+                            machine_selection,
+                            &mut gcode,
+                        )
+                        .with_context(|| {
+                            format!("Failed to generate {}.", stage.span.description)
+                        })?;
+
+                        registration_pins_made = true;
+                    }
+                    None => transform_commands(&mut gcode[start..], &instance.transform),
+                }
+            }
+        }
+
+        generated_files.push(GeneratedGCodeFile {
+            path: gcode_file_path.clone(),
+            gcode_file: GCodeFile::new(gcode),
+            dialect: dialect.unwrap_or_default(),
+            backside_offset: Length::new::<millimeter>(backside_offset),
+            stage_spans,
+        });
+    }
+
+    Ok(generated_files)
+}
+
+/// Drills the holes of the registration pins, or marks them with a laser.
+fn generate_registration_pins(
+    registration: &Registration,
+    panel_bounds: &BoardBounds,
+    machine_selection: &MachineSelection,
+    gcode: &mut Vec<GCommand>,
+) -> Result<()> {
+    let MachineSelection {
+        machine_name: _,
+        machine_config,
+        job_config,
+        include_file_search_directory,
+    } = machine_selection;
+
+    let pin_holes = registration::pin_holes(registration, panel_bounds)?;
+    let tool_selection = get_tool_selection(machine_config, &job_config.tool)?;
+
+    pin_holes
+        .generate_gcode(GCodeConfig {
+            commands: gcode,
+            job_config,
+            tool_config: &tool_selection,
+            machine_config,
+            include_file_search_directory: include_file_search_directory.clone(),
         })
-        .context("Failed to get search directory for global config.")??;
+        .context("Failed to generate gcode for registration pins.")
+}
 
-    let target_directory = build_configuration
-        .target_directory
-        .join(forge_file.project_name);
+/// Fails if the panel, including its margin, doesn't fit the workspace of a stage's machine.
+fn check_panel_fits(panel: &Panel, panel_bounds: &BoardBounds, span: &StageSpan) -> Result<()> {
+    let margin = panel.margin.get::<millimeter>();
+    let (width, height) = (
+        span.workspace.width.get::<millimeter>(),
+        span.workspace.height.get::<millimeter>(),
+    );
+
+    if panel_bounds.min.x - margin < 0.0
+        || panel_bounds.min.y - margin < 0.0
+        || panel_bounds.max.x + margin > width
+        || panel_bounds.max.y + margin > height
+    {
+        bail!(
+            "The panel covers X{} Y{} to X{} Y{} with its margin, which doesn't fit the {} by {} mm workspace used by {}.",
+            panel_bounds.min.x - margin,
+            panel_bounds.min.y - margin,
+            panel_bounds.max.x + margin,
+            panel_bounds.max.y + margin,
+            width,
+            height,
+            span.description
+        );
+    }
 
-    fs::create_dir_all(&target_directory).context("Failed to create output directory.")?;
+    Ok(())
+}
 
-    let mut min_x = f64::INFINITY;
-    let mut max_x = -f64::INFINITY;
+/// The stages of a forge project, generated in the board's own coordinates.
+struct GeneratedProject<'a> {
+    files: HashMap<PathBuf, GeneratedProjectFile<'a>>,
+    bounds: BoardBounds,
+}
+
+struct GeneratedProjectFile<'a> {
+    dialect: Option<GCodeDialect>,
+    stages: Vec<GeneratedStage<'a>>,
+}
+
+struct GeneratedStage<'a> {
+    span: StageSpan,
+    commands: Vec<GCommand>,
+
+    /// Registration pins are placed around the whole panel, so their holes are only made once it has been laid out.
+    registration_pins: Option<MachineSelection<'a>>,
+}
+
+/// Generates the commands of every stage of a forge project.
+fn generate_project_stages<'a>(
+    forge_file: &'a ForgeFile,
+    forge_file_path: &Path,
+    debug_directory: Option<&Path>,
+    global_config: &'a Config,
+) -> Result<GeneratedProject<'a>> {
+    let forge_file_directory = forge_file_path
+        .parent()
+        .context("Could not get parent directory of forge file.")?;
+    let config_directory = get_config_directory()?;
+
+    let mut bounds = BoardBounds::default();
+    let mut files = HashMap::new();
+    let mut layers = ProjectLayers::new(forge_file, forge_file_directory);
 
     for (gcode_file_path, stages) in forge_file
         .gcode_files
@@ -96,14 +530,17 @@ fn build(build_configuration: arguments::BuildCommand, global_config: Config) ->
         .sorted_by(|a, b| Ord::cmp(&a.0, &b.0))
     {
         let mut gcode = Vec::new();
+        let mut dialect = None;
+        let mut stage_spans = Vec::new();
+        let mut registration_pins = HashMap::new();
 
         log::info!("Starting gcode file {:?}", gcode_file_path);
 
         for (stage_index, stage) in stages.iter().enumerate() {
-            let debug_output_directory = if build_configuration.debug {
-                let debug_output_directory = target_directory
-                    .join("debug")
-                    .join(format!("stage{}", stage_index));
+            let stage_start = gcode.len();
+
+            let debug_output_directory = if let Some(debug_directory) = debug_directory {
+                let debug_output_directory = debug_directory.join(format!("stage{}", stage_index));
                 fs::create_dir_all(&debug_output_directory)
                     .context("Failed to create directory for debug output.")?;
 
@@ -114,149 +551,212 @@ fn build(build_configuration: arguments::BuildCommand, global_config: Config) ->
                 None
             };
 
-            match stage {
+            let height_map = match stage {
                 forge_file::Stage::EngraveMask {
                     machine_config,
-                    gerber_file,
-                    backside,
+                    file,
+                    backside: _,
                     invert,
+                    select,
+                    exclude,
+                    height_map,
                 } => {
+                    let gerber_file = layers.gerber_file(file)?;
                     log::info!("Process engrave stage: {:?}", gerber_file);
+                    let description = format!("Stage {}: engrave {:?}", stage_index, gerber_file);
+                    gcode.push(GCommand::Comment(description.clone()));
 
-                    gcode.push(GCommand::SetSide(if *backside {
+                    gcode.push(GCommand::SetSide(if stage.backside() {
                         BoardSide::Back
                     } else {
                         BoardSide::Front
                     }));
 
-                    let machine_config_path = machine_config
-                        .as_ref()
-                        .or(global_config.default_engraver.as_ref())
-                        .context(
-                            "An engraver was not specified and a global default is not set.",
-                        )?;
-                    log::info!("Using machine configuration: {}", machine_config_path);
-
-                    let mut machine_config_path = machine_config_path.iter();
-                    let machine_name = machine_config_path
-                        .next()
-                        .context("Machine name not provided by machine config path.")?
-                        .to_string();
-                    let machine_profile = machine_config_path
-                        .next()
-                        .context("Machine profile not provided by machine config path.")?
-                        .to_string();
-
-                    if machine_config_path.next().is_some() {
-                        bail!("Too many parts to machine config path.");
-                    }
-
-                    let (include_file_search_directory, machine_config) = forge_file
-                        .machines
-                        .get(&machine_name)
-                        .map(|machine_config| (forge_file_directory.to_path_buf(), machine_config))
-                        .or(global_config
-                            .machines
-                            .get(&machine_name)
-                            .map(|machine_config| (config_directory.clone(), machine_config)))
-                        .context("Failed to find machine configuration.")?;
-
-                    let job_config = machine_config
-                        .engraving_configs
-                        .get(&machine_profile)
-                        .context("Failed to find machine profile.")?;
+                    let MachineSelection {
+                        machine_name,
+                        machine_config,
+                        job_config,
+                        include_file_search_directory,
+                    } = select_machine(
+                        machine_config.as_ref(),
+                        ProfileKind::Engraving,
+                        forge_file,
+                        global_config,
+                        forge_file_directory,
+                        &config_directory,
+                    )?;
+
+                    select_dialect(&mut dialect, &machine_name, machine_config)?;
+                    stage_spans.push(StageSpan {
+                        start: stage_start,
+                        description,
+                        workspace: machine_config.workspace_area,
+                        acceleration: machine_config.acceleration,
+                    });
 
                     process_gerber_file(GerberConfig {
-                        build_configuration: &build_configuration,
+                        forge_file_path,
                         machine_config,
                         job_config,
                         invert: *invert,
-                        gerber_file: gerber_file.as_ref(),
+                        gerber_file: &gerber_file,
+                        debug_output_directory: debug_output_directory.as_ref(),
+                        toolpath: Toolpath::Engrave,
+                        select_lines: LineSelection::All,
+                        select_objects: select.as_ref(),
+                        exclude_objects: exclude.as_ref(),
+                        tabs: None,
+                        gcode: &mut gcode,
+                        bounds: &mut bounds,
+                        include_file_search_directory,
+                    })?;
+
+                    height_map.as_ref()
+                }
+                forge_file::Stage::IsolationMill {
+                    machine_config,
+                    file,
+                    backside: _,
+                    select,
+                    exclude,
+                    height_map,
+                } => {
+                    let gerber_file = layers.gerber_file(file)?;
+                    log::info!("Process isolation stage: {:?}", gerber_file);
+                    let description = format!("Stage {}: isolate {:?}", stage_index, gerber_file);
+                    gcode.push(GCommand::Comment(description.clone()));
+
+                    gcode.push(GCommand::SetSide(if stage.backside() {
+                        BoardSide::Back
+                    } else {
+                        BoardSide::Front
+                    }));
+
+                    let MachineSelection {
+                        machine_name,
+                        machine_config,
+                        job_config,
+                        include_file_search_directory,
+                    } = select_machine(
+                        machine_config.as_ref(),
+                        ProfileKind::Engraving,
+                        forge_file,
+                        global_config,
+                        forge_file_directory,
+                        &config_directory,
+                    )?;
+
+                    select_dialect(&mut dialect, &machine_name, machine_config)?;
+                    stage_spans.push(StageSpan {
+                        start: stage_start,
+                        description,
+                        workspace: machine_config.workspace_area,
+                        acceleration: machine_config.acceleration,
+                    });
+
+                    process_gerber_file(GerberConfig {
+                        forge_file_path,
+                        machine_config,
+                        job_config,
+                        invert: false,
+                        gerber_file: &gerber_file,
                         debug_output_directory: debug_output_directory.as_ref(),
-                        generate_infill: true,
+                        toolpath: Toolpath::Isolate,
                         select_lines: LineSelection::All,
+                        select_objects: select.as_ref(),
+                        exclude_objects: exclude.as_ref(),
+                        tabs: None,
                         gcode: &mut gcode,
-                        min_x: &mut min_x,
-                        max_x: &mut max_x,
+                        bounds: &mut bounds,
                         include_file_search_directory,
                     })?;
+
+                    height_map.as_ref()
                 }
                 forge_file::Stage::CutBoard {
                     machine_config,
                     file,
-                    backside,
+                    backside: _,
+                    height_map,
                 } => {
                     log::info!("Process cutting stage: {}", file);
+                    let description = format!("Stage {}: cut {}", stage_index, file);
+                    gcode.push(GCommand::Comment(description.clone()));
 
-                    gcode.push(GCommand::SetSide(if *backside {
+                    gcode.push(GCommand::SetSide(if stage.backside() {
                         BoardSide::Back
                     } else {
                         BoardSide::Front
                     }));
 
-                    let machine_config_path = machine_config
-                        .as_ref()
-                        .or(global_config.default_cutter.as_ref())
-                        .context(
-                            "An engraver was not specified and a global default is not set.",
-                        )?;
-                    log::info!("Using machine configuration: {}", machine_config_path);
-
-                    let mut machine_config_path = machine_config_path.iter();
-                    let machine_name = machine_config_path
-                        .next()
-                        .context("Machine name not provided by machine config path.")?
-                        .to_string();
-                    let machine_profile = machine_config_path
-                        .next()
-                        .context("Machine profile not provided by machine config path.")?
-                        .to_string();
-
-                    if machine_config_path.next().is_some() {
-                        bail!("Too many parts to machine config path.");
+                    let MachineSelection {
+                        machine_name,
+                        machine_config,
+                        job_config,
+                        include_file_search_directory,
+                    } = select_machine(
+                        machine_config.as_ref(),
+                        ProfileKind::Cutting,
+                        forge_file,
+                        global_config,
+                        forge_file_directory,
+                        &config_directory,
+                    )?;
+
+                    select_dialect(&mut dialect, &machine_name, machine_config)?;
+                    stage_spans.push(StageSpan {
+                        start: stage_start,
+                        description,
+                        workspace: machine_config.workspace_area,
+                        acceleration: machine_config.acceleration,
+                    });
+
+                    // Cut depths are heights above the board's surface, so a cut through the board is below minus its thickness.
+                    if let (
+                        Some(board_thickness),
+                        config::machine::ToolConfig::EndMill { cut_depth, .. },
+                    ) = (layers.board_thickness()?, &job_config.tool_power)
+                    {
+                        if -*cut_depth < board_thickness {
+                            log::warn!(
+                                "Cut depth of {} mm does not reach through the {} mm thick board.",
+                                cut_depth.get::<millimeter>(),
+                                board_thickness.get::<millimeter>()
+                            );
+                        }
                     }
 
-                    let (include_file_search_directory, machine_config) = forge_file
-                        .machines
-                        .get(&machine_name)
-                        .map(|machine_config| (forge_file_directory.to_path_buf(), machine_config))
-                        .or(global_config
-                            .machines
-                            .get(&machine_name)
-                            .map(|machine_config| (config_directory.clone(), machine_config)))
-                        .context("Failed to find machine configuration.")?;
-
-                    let job_config = machine_config
-                        .cutting_configs
-                        .get(&machine_profile)
-                        .context("Failed to find machine profile.")?;
-
-                    match file {
+                    let (select_lines, tabs) = match file {
                         forge_file::CutBoardFile::Gerber {
-                            gerber_file,
-                            select_lines,
-                        } => {
+                            select_lines, tabs, ..
+                        }
+                        | forge_file::CutBoardFile::Layer {
+                            select_lines, tabs, ..
+                        } => (*select_lines, tabs.as_ref()),
+                        forge_file::CutBoardFile::Drill { .. } => (LineSelection::All, None),
+                    };
+
+                    match layers.cut_board_file(file)? {
+                        (LayerKind::Gerber, gerber_file) => {
                             process_gerber_file(GerberConfig {
-                                build_configuration: &build_configuration,
+                                forge_file_path,
                                 machine_config,
                                 job_config,
                                 invert: false,
-                                gerber_file: gerber_file.as_ref(),
+                                gerber_file: &gerber_file,
                                 debug_output_directory: debug_output_directory.as_ref(),
-                                generate_infill: false,
-                                select_lines: *select_lines,
+                                toolpath: Toolpath::Outline,
+                                select_lines,
+                                select_objects: None,
+                                exclude_objects: None,
+                                tabs,
                                 gcode: &mut gcode,
-                                min_x: &mut min_x,
-                                max_x: &mut max_x,
+                                bounds: &mut bounds,
                                 include_file_search_directory,
                             })?;
                         }
-                        forge_file::CutBoardFile::Drill { drill_file } => {
-                            let file_path = build_configuration
-                                .forge_file_path
-                                .parent()
-                                .context("Could not get working directory of forge file.")?
-                                .join(drill_file);
+                        (LayerKind::Drill, drill_file) => {
+                            let file_path = forge_file_directory.join(drill_file);
 
                             let mut drill_file = drill_file::DrillFile::default();
                             drill_file::load(&mut drill_file, &file_path)
@@ -276,39 +776,320 @@ fn build(build_configuration: arguments::BuildCommand, global_config: Config) ->
                                 .context("Failed to generate gcode file.")?;
                         }
                     }
+
+                    height_map.as_ref()
                 }
+                forge_file::Stage::ProbeHeightMap {
+                    machine_config,
+                    file,
+                    grid_spacing,
+                    probe_log,
+                    backside: _,
+                } => {
+                    let gerber_file = layers.gerber_file(file)?;
+                    log::info!("Process probing stage: {:?}", gerber_file);
+                    let description = format!(
+                        "Stage {}: probe height map over {:?}",
+                        stage_index, gerber_file
+                    );
+                    gcode.push(GCommand::Comment(description.clone()));
+
+                    gcode.push(GCommand::SetSide(if stage.backside() {
+                        BoardSide::Back
+                    } else {
+                        BoardSide::Front
+                    }));
+
+                    let MachineSelection {
+                        machine_name,
+                        machine_config,
+                        job_config,
+                        include_file_search_directory,
+                    } = select_machine(
+                        machine_config.as_ref(),
+                        ProfileKind::Cutting,
+                        forge_file,
+                        global_config,
+                        forge_file_directory,
+                        &config_directory,
+                    )?;
+
+                    select_dialect(&mut dialect, &machine_name, machine_config)?;
+                    stage_spans.push(StageSpan {
+                        start: stage_start,
+                        description,
+                        workspace: machine_config.workspace_area,
+                        acceleration: machine_config.acceleration,
+                    });
+
+                    let probe = machine_config.probe.as_ref().with_context(|| {
+                        format!("Machine {} does not have a probe configured.", machine_name)
+                    })?;
+
+                    let mut gerber = GerberFile::default();
+                    gerber_file::load(&mut gerber, &forge_file_directory.join(gerber_file))
+                        .context("Failed to load gerber file.")?;
+
+                    let tool_selection = get_tool_selection(machine_config, &job_config.tool)?;
+
+                    height_map::generate_probe_grid(
+                        GCodeConfig {
+                            commands: &mut gcode,
+                            job_config,
+                            tool_config: &tool_selection,
+                            machine_config,
+                            include_file_search_directory,
+                        },
+                        gerber.calculate_bounds(),
+                        *grid_spacing,
+                        probe,
+                        probe_log,
+                    )
+                    .context("Failed to generate probing grid.")?;
+
+                    None
+                }
+                forge_file::Stage::RegistrationPins { machine_config } => {
+                    log::info!("Process registration pin stage");
+                    let description = format!("Stage {}: registration pins", stage_index);
+                    gcode.push(GCommand::Comment(description.clone()));
+                    gcode.push(GCommand::SetSide(BoardSide::Front));
+
+                    let machine_selection = select_machine(
+                        machine_config.as_ref(),
+                        ProfileKind::Cutting,
+                        forge_file,
+                        global_config,
+                        forge_file_directory,
+                        &config_directory,
+                    )?;
+
+                    select_dialect(
+                        &mut dialect,
+                        &machine_selection.machine_name,
+                        machine_selection.machine_config,
+                    )?;
+                    stage_spans.push(StageSpan {
+                        start: stage_start,
+                        description,
+                        workspace: machine_selection.machine_config.workspace_area,
+                        acceleration: machine_selection.machine_config.acceleration,
+                    });
+
+                    registration_pins.insert(stage_start, machine_selection);
+
+                    None
+                }
+            };
+
+            // Cuts are split up so that they can follow the surface of the board closely.
+            if let Some(height_map) = height_map {
+                let height_map = Rc::new(
+                    HeightMap::load(&forge_file_directory.join(height_map))
+                        .context("Failed to load height map.")?,
+                );
+
+                let stage_commands = gcode.split_off(stage_start);
+                gcode.push(GCommand::SetHeightMap(Some(height_map.clone())));
+                gcode.extend(height_map.subdivide_cuts(stage_commands));
+                gcode.push(GCommand::SetHeightMap(None));
             }
         }
 
-        let backside_offset = if forge_file.align_backside {
-            max_x - min_x
-        } else {
-            0.0
-        };
+        // The commands are split back up by stage so that each can be copied for every board on the panel.
+        let mut stages = Vec::new();
+        for span in stage_spans.into_iter().rev() {
+            let commands = gcode.split_off(span.start);
+            stages.push(GeneratedStage {
+                registration_pins: registration_pins.remove(&span.start),
+                span,
+                commands,
+            });
+        }
+        stages.reverse();
+
+        files.insert(
+            gcode_file_path.clone(),
+            GeneratedProjectFile { dialect, stages },
+        );
+    }
 
-        let output_file = target_directory.join(gcode_file_path);
-        let gcode_file = GCodeFile::new(gcode);
-        let output = gcode_file
-            .to_string(Length::new::<millimeter>(backside_offset))
-            .with_context(|| format!("Failed to produce GCode for file: {:?}", gcode_file_path))?;
-        fs::write(output_file, output).context("Failed to save GCode file.")?;
+    Ok(GeneratedProject { files, bounds })
+}
+
+/// Include files of machines from the global config are searched for next to it.
+fn get_config_directory() -> Result<PathBuf> {
+    Config::get_path()
+        .map(|path| {
+            path.parent()
+                .map(|path| path.to_path_buf())
+                .context("Could not get parent directory of global config file.")
+        })
+        .context("Failed to get search directory for global config.")?
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ProfileKind {
+    Engraving,
+    Cutting,
+}
+
+/// The machine and profile selected by a stage's `machine_config`.
+struct MachineSelection<'a> {
+    machine_name: String,
+    machine_config: &'a Machine,
+    job_config: &'a JobConfig,
+    include_file_search_directory: PathBuf,
+}
+
+/// Resolves a `machine/profile` path against the machines of the forge file and then the global config.
+/// The global default engraver or cutter is used if no path is given.
+fn select_machine<'a>(
+    machine_config_path: Option<&'a Utf8PathBuf>,
+    profile_kind: ProfileKind,
+    forge_file: &'a ForgeFile,
+    global_config: &'a Config,
+    forge_file_directory: &Path,
+    config_directory: &Path,
+) -> Result<MachineSelection<'a>> {
+    let machine_config_path = match profile_kind {
+        ProfileKind::Engraving => machine_config_path
+            .or(global_config.default_engraver.as_ref())
+            .context("An engraver was not specified and a global default is not set.")?,
+        ProfileKind::Cutting => machine_config_path
+            .or(global_config.default_cutter.as_ref())
+            .context("A cutter was not specified and a global default is not set.")?,
+    };
+    log::info!("Using machine configuration: {}", machine_config_path);
+
+    let mut machine_config_path = machine_config_path.iter();
+    let machine_name = machine_config_path
+        .next()
+        .context("Machine name not provided by machine config path.")?
+        .to_string();
+    let machine_profile = machine_config_path
+        .next()
+        .context("Machine profile not provided by machine config path.")?
+        .to_string();
+
+    if machine_config_path.next().is_some() {
+        bail!("Too many parts to machine config path.");
+    }
+
+    let (include_file_search_directory, machine_config) = forge_file
+        .machines
+        .get(&machine_name)
+        .map(|machine_config| (forge_file_directory.to_path_buf(), machine_config))
+        .or(global_config
+            .machines
+            .get(&machine_name)
+            .map(|machine_config| (config_directory.to_path_buf(), machine_config)))
+        .with_context(|| format!("Failed to find machine configuration `{}`.", machine_name))?;
+
+    let job_config = match profile_kind {
+        ProfileKind::Engraving => machine_config.engraving_configs.get(&machine_profile),
+        ProfileKind::Cutting => machine_config.cutting_configs.get(&machine_profile),
+    }
+    .with_context(|| format!("Failed to find machine profile `{}`.", machine_profile))?;
+
+    Ok(MachineSelection {
+        machine_name,
+        machine_config,
+        job_config,
+        include_file_search_directory,
+    })
+}
+
+/// Where the commands of a stage start within its GCode file, so that problems can be traced back to the stage.
+struct StageSpan {
+    start: usize,
+    description: String,
+    workspace: WorkspaceSize,
+    acceleration: Option<Acceleration<uom::si::SI<f64>, f64>>,
+}
+
+/// Fails if any stage moves outside of the workspace of its machine.
+fn check_workspace(
+    gcode_file: &GCodeFile,
+    x_offset: Length<uom::si::SI<f64>, f64>,
+    stage_spans: &[StageSpan],
+) -> Result<()> {
+    // Only the first few positions are listed so that a misplaced board doesn't flood the log.
+    const LISTED_VIOLATIONS: usize = 5;
+
+    let mut report = String::new();
+
+    for (span_index, span) in stage_spans.iter().enumerate() {
+        let end = stage_spans
+            .get(span_index + 1)
+            .map_or(usize::MAX, |next_span| next_span.start);
+
+        let violations: Vec<_> = gcode_file
+            .check_bounds(x_offset, &span.workspace)
+            .into_iter()
+            .filter(|violation| (span.start..end).contains(&violation.command_index()))
+            .collect();
+
+        if !violations.is_empty() {
+            report += &format!(
+                "\n\t{}: {} moves leave the workspace, at {}",
+                span.description,
+                violations.len(),
+                violations
+                    .iter()
+                    .take(LISTED_VIOLATIONS)
+                    .map(|violation| violation.to_string())
+                    .join(", ")
+            );
+
+            if violations.len() > LISTED_VIOLATIONS {
+                report += ", ...";
+            }
+        }
+    }
+
+    if !report.is_empty() {
+        bail!("Moves leave the machine's workspace:{}", report);
     }
 
     Ok(())
 }
 
+/// All stages of a GCode file run on the same machine, so they must agree on the dialect of GCode to produce.
+fn select_dialect(
+    dialect: &mut Option<GCodeDialect>,
+    machine_name: &str,
+    machine_config: &Machine,
+) -> Result<()> {
+    match dialect {
+        Some(dialect) if *dialect != machine_config.dialect => bail!(
+            "Machine {} uses the {} dialect but previous stages of this file use the {} dialect.",
+            machine_name,
+            machine_config.dialect,
+            dialect
+        ),
+        Some(_) => Ok(()),
+        None => {
+            *dialect = Some(machine_config.dialect);
+            Ok(())
+        }
+    }
+}
+
 struct GerberConfig<'a> {
-    build_configuration: &'a arguments::BuildCommand,
+    forge_file_path: &'a Path,
     machine_config: &'a Machine,
     job_config: &'a JobConfig,
     invert: bool,
     gerber_file: &'a Path,
     debug_output_directory: Option<&'a PathBuf>,
-    generate_infill: bool,
+    toolpath: Toolpath,
     select_lines: LineSelection,
+    select_objects: Option<&'a ObjectSelection>,
+    exclude_objects: Option<&'a ObjectSelection>,
+    tabs: Option<&'a HoldingTabs>,
     gcode: &'a mut Vec<GCommand>,
-    min_x: &'a mut f64,
-    max_x: &'a mut f64,
+    bounds: &'a mut BoardBounds,
     include_file_search_directory: PathBuf,
 }
 
@@ -318,7 +1099,6 @@ fn process_gerber_file(config: GerberConfig) -> Result<()> {
     let tool_selection = get_tool_selection(config.machine_config, &config.job_config.tool)?;
 
     let file_path = config
-        .build_configuration
         .forge_file_path
         .parent()
         .context("Could not get working directory of forge file.")?
@@ -378,10 +1158,22 @@ fn process_gerber_file(config: GerberConfig) -> Result<()> {
             .context("Failed to save gerber debug SVG file.")?;
     }
 
-    let (min_x, _min_y, max_x, _max_y) = gerber.calculate_bounds();
+    // The bounds cover the whole file, so that picking objects doesn't move the board.
+    config.bounds.include(gerber.calculate_bounds());
+
+    if config.select_objects.is_some() || config.exclude_objects.is_some() {
+        let remaining = gerber.filter_objects(config.select_objects, config.exclude_objects);
+        log::info!("{} objects match the selection.", remaining);
+
+        if remaining == 0 {
+            log::warn!(
+                "No objects of {:?} match the selection. Is it missing X2 attributes?",
+                config.gerber_file
+            );
+        }
+    }
 
-    *config.min_x = config.min_x.min(min_x);
-    *config.max_x = config.max_x.max(max_x);
+    let gcode_start = config.gcode.len();
 
     gerber
         .generate_gcode(
@@ -392,12 +1184,27 @@ fn process_gerber_file(config: GerberConfig) -> Result<()> {
                 machine_config: config.machine_config,
                 include_file_search_directory: config.include_file_search_directory,
             },
-            config.generate_infill,
+            config.toolpath,
             config.select_lines,
             config.invert,
+            config.tabs,
         )
         .context("Failed to generate GCode file.")?;
 
+    // Debug render of the toolpaths, which shows where holding tabs ended up.
+    if let Some(debug_output_directory) = config.debug_output_directory.as_ref() {
+        let preview = ToolpathPreview::new(
+            &GCodeFile::new(config.gcode[gcode_start..].to_vec()),
+            Length::new::<millimeter>(0.0),
+        );
+
+        fs::write(
+            debug_output_directory.join("toolpath.svg"),
+            preview.render_svg()?,
+        )
+        .context("Failed to save toolpath debug SVG file.")?;
+    }
+
     Ok(())
 }
 
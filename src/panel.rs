@@ -0,0 +1,228 @@
+//! Lays out the boards of a panel on a sheet of stock. Every stage of every board is generated once, in the board's own
+//! coordinates, and then moved into place for each copy of the board on the panel. The placement of the forge file then
+//! moves the whole panel to where it sits on the bed.
+
+use anyhow::{bail, Result};
+use nalgebra::{Isometry2, Point2, Vector2};
+use uom::si::length::millimeter;
+
+use crate::forge_file::{Panel, PanelLayout, Placement, PlacementOrigin};
+
+/// The area covered by the gerber files of a board, in millimeters.
+#[derive(Debug, Clone, Copy)]
+pub struct BoardBounds {
+    pub min: Vector2<f64>,
+    pub max: Vector2<f64>,
+}
+
+impl Default for BoardBounds {
+    fn default() -> Self {
+        Self {
+            min: Vector2::new(f64::INFINITY, f64::INFINITY),
+            max: Vector2::new(-f64::INFINITY, -f64::INFINITY),
+        }
+    }
+}
+
+impl BoardBounds {
+    /// Grows the bounds to cover an area given as `(min_x, min_y, max_x, max_y)`.
+    pub fn include(&mut self, (min_x, min_y, max_x, max_y): (f64, f64, f64, f64)) {
+        self.min = self.min.inf(&Vector2::new(min_x, min_y));
+        self.max = self.max.sup(&Vector2::new(max_x, max_y));
+    }
+
+    pub fn union(&mut self, other: &BoardBounds) {
+        self.include((other.min.x, other.min.y, other.max.x, other.max.y));
+    }
+
+    /// Boards without any gerber files don't have bounds.
+    pub fn is_empty(&self) -> bool {
+        self.min.x > self.max.x || self.min.y > self.max.y
+    }
+
+    pub fn size(&self) -> Vector2<f64> {
+        self.max - self.min
+    }
+
+    /// The bounds of the board after it has been moved.
+    pub fn transformed(&self, transform: &Isometry2<f64>) -> Self {
+        let mut bounds = Self::default();
+
+        for corner in [
+            Point2::new(self.min.x, self.min.y),
+            Point2::new(self.max.x, self.min.y),
+            Point2::new(self.max.x, self.max.y),
+            Point2::new(self.min.x, self.max.y),
+        ] {
+            let corner = transform.transform_point(&corner);
+            bounds.include((corner.x, corner.y, corner.x, corner.y));
+        }
+
+        bounds
+    }
+
+    fn overlaps(&self, other: &BoardBounds) -> bool {
+        // Boards that only touch don't overlap, even with a little rounding error.
+        const TOLERANCE: f64 = 1e-6;
+
+        self.min.x + TOLERANCE < other.max.x
+            && other.min.x + TOLERANCE < self.max.x
+            && self.min.y + TOLERANCE < other.max.y
+            && other.min.y + TOLERANCE < self.max.y
+    }
+}
+
+/// A project that can be placed on a panel.
+pub struct PanelProject<'a> {
+    pub name: &'a str,
+    pub bounds: BoardBounds,
+}
+
+/// A copy of a board on the panel.
+#[derive(Debug)]
+pub struct PanelInstance {
+    /// The index of the board's project.
+    pub project: usize,
+
+    /// Moves the board from its own coordinates to its place on the panel.
+    pub transform: Isometry2<f64>,
+
+    pub description: String,
+}
+
+/// Places every board of the panel. `board_projects` gives the project of each board entry of the panel.
+/// Boards are returned in the order they should be cut, which is the same for every stage.
+pub fn layout(
+    panel: &Panel,
+    board_projects: &[usize],
+    projects: &[PanelProject],
+) -> Result<Vec<PanelInstance>> {
+    let spacing = panel.spacing.get::<millimeter>();
+    let margin = panel.margin.get::<millimeter>();
+
+    let mut instances = Vec::new();
+
+    // Grids are stacked on top of each other.
+    let mut grid_y = margin;
+
+    for (board, project_index) in panel.boards.iter().zip(board_projects.iter().copied()) {
+        let project = &projects[project_index];
+
+        if project.bounds.is_empty() {
+            bail!(
+                "Board {} doesn't have any gerber files, so its size on the panel is unknown.",
+                project.name
+            );
+        }
+
+        match &board.layout {
+            PanelLayout::Grid { columns, rows } => {
+                let cell = project.bounds.size() + Vector2::new(spacing, spacing);
+
+                for row in 0..*rows {
+                    for column in 0..*columns {
+                        let corner = Vector2::new(
+                            margin + cell.x * column as f64,
+                            grid_y + cell.y * row as f64,
+                        );
+
+                        instances.push(PanelInstance {
+                            project: project_index,
+                            transform: Isometry2::translation(
+                                corner.x - project.bounds.min.x,
+                                corner.y - project.bounds.min.y,
+                            ),
+                            description: format!(
+                                "{} at column {}, row {}",
+                                project.name, column, row
+                            ),
+                        });
+                    }
+                }
+
+                grid_y += cell.y * *rows as f64;
+            }
+            PanelLayout::Positions { positions } => {
+                for position in positions {
+                    let rotation = Isometry2::rotation(position.rotation.to_radians());
+                    let rotated_bounds = project.bounds.transformed(&rotation);
+
+                    let (x, y) = (
+                        position.x.get::<millimeter>(),
+                        position.y.get::<millimeter>(),
+                    );
+
+                    instances.push(PanelInstance {
+                        project: project_index,
+                        transform: Isometry2::translation(
+                            x - rotated_bounds.min.x,
+                            y - rotated_bounds.min.y,
+                        ) * rotation,
+                        description: format!("{} at X{} Y{}", project.name, x, y),
+                    });
+                }
+            }
+        }
+    }
+
+    let placed_bounds: Vec<BoardBounds> = instances
+        .iter()
+        .map(|instance| {
+            projects[instance.project]
+                .bounds
+                .transformed(&instance.transform)
+        })
+        .collect();
+
+    for (first, second) in (0..instances.len())
+        .flat_map(|first| (first + 1..instances.len()).map(move |second| (first, second)))
+    {
+        if placed_bounds[first].overlaps(&placed_bounds[second]) {
+            bail!(
+                "Panel boards {} and {} overlap.",
+                instances[first].description,
+                instances[second].description
+            );
+        }
+    }
+
+    // Boards are visited nearest first, starting from the origin.
+    let mut remaining: Vec<(PanelInstance, BoardBounds)> =
+        instances.into_iter().zip(placed_bounds).collect();
+    let mut ordered = Vec::with_capacity(remaining.len());
+    let mut position = Vector2::new(0.0, 0.0);
+
+    while let Some((index, _)) = remaining
+        .iter()
+        .enumerate()
+        .map(|(index, (_, bounds))| (index, (bounds.min - position).norm()))
+        .min_by(|(_, a), (_, b)| a.total_cmp(b))
+    {
+        let (instance, bounds) = remaining.remove(index);
+        position = bounds.min;
+        ordered.push(instance);
+    }
+
+    Ok(ordered)
+}
+
+/// Moves a board, or a whole panel, covering `bounds` to its place on the bed.
+pub fn placement_transform(placement: &Placement, bounds: &BoardBounds) -> Result<Isometry2<f64>> {
+    let rotation = Isometry2::rotation(placement.rotation.to_radians());
+    let rotated_bounds = bounds.transformed(&rotation);
+
+    let origin = match placement.origin {
+        PlacementOrigin::Gerber => Vector2::new(0.0, 0.0),
+        _ if rotated_bounds.is_empty() => bail!(
+            "The board doesn't have any gerber files, so its {} can't be placed.",
+            placement.origin
+        ),
+        PlacementOrigin::LowerLeft => rotated_bounds.min,
+        PlacementOrigin::Center => (rotated_bounds.min + rotated_bounds.max) / 2.0,
+    };
+
+    Ok(Isometry2::translation(
+        placement.x.get::<millimeter>() - origin.x,
+        placement.y.get::<millimeter>() - origin.y,
+    ) * rotation)
+}
@@ -4,19 +4,19 @@
 //! Several structures and functions in this file will state section numbers referencing that document.
 //!
 
-use nalgebra::Vector2;
+use itertools::Itertools;
 use nom::{
     branch::alt,
     bytes::complete::{tag, take_while, take_while1},
-    character::complete::char as nom_char,
-    combinator::{map, map_res, opt, value},
+    character::complete::{char as nom_char, one_of, space0},
+    combinator::{map, map_opt, map_res, not, opt, recognize, value, verify},
     multi::many0,
     sequence::{delimited, pair, preceded, terminated, tuple},
     IResult,
 };
 use nom_locate::LocatedSpan;
 
-use super::{LocationInfo, UnitMode};
+use super::{gerber::Attribute, LocationInfo, UnitMode};
 
 pub type Span<'a> = LocatedSpan<&'a str>;
 
@@ -37,14 +37,60 @@ impl<'a> HeaderCommandContext<'a> {
 
 #[derive(Debug)]
 pub enum HeaderCommand<'a> {
-    Comment(Span<'a>),  // 3.1
-    UnitMode(UnitMode), // 3.3
+    Comment(Span<'a>),        // 3.1
+    Attribute(Attribute<'a>), // Written as a comment starting with `#@!`.
+    UnitMode {
+        // 3.3
+        unit_mode: UnitMode,
+
+        // Older Excellon files follow the unit with the zeros they keep and the number of digits, such as `INCH,LZ,00.0000`.
+        included_zeros: Option<IncludedZeros>,
+        digits: Option<(u32, u32)>,
+    },
     Format(Span<'a>),
+    IncrementalInput(bool), // ICI, Excellon only.
     ToolDeclaration {
         // 3.4
         index: usize,
         diameter: f64,
+
+        /// Feeds, speeds and other parameters given along with the diameter.
+        ignored_parameters: Vec<char>,
     },
+    Unsupported(Span<'a>),
+}
+
+impl<'a> HeaderCommand<'a> {
+    /// Describes what was left out when reading the command, if anything was.
+    pub fn ignored(&self) -> Option<String> {
+        match self {
+            HeaderCommand::ToolDeclaration {
+                index,
+                ignored_parameters,
+                ..
+            } if !ignored_parameters.is_empty() => Some(format!(
+                "Parameters {} of tool {} are ignored. The machine profile sets how holes are cut.",
+                ignored_parameters.iter().join(", "),
+                index
+            )),
+            HeaderCommand::Unsupported(line) => Some(format!(
+                "Header command `{}` is not supported and was ignored.",
+                line.fragment()
+            )),
+            _ => None,
+        }
+    }
+}
+
+/// Which zeros of a coordinate without a decimal point were written out, and so where the decimal point is implied.
+/// XNC files always write the decimal point, but older Excellon files leave it out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IncludedZeros {
+    /// `LZ`, trailing zeros are left out so digits are counted from the start of the number.
+    Leading,
+
+    /// `TZ`, leading zeros are left out so digits are counted from the end of the number.
+    Trailing,
 }
 
 #[derive(Debug)]
@@ -62,36 +108,123 @@ impl<'a> DrillCommandContext<'a> {
     }
 }
 
+/// The X and Y of a command as they were written. Either can be left out, in which case it keeps its previous value.
+#[derive(Debug, Clone, Default)]
+pub struct Coordinates<'a> {
+    pub x: Option<Span<'a>>,
+    pub y: Option<Span<'a>>,
+}
+
 #[derive(Debug, Clone)]
 pub enum DrillCommand<'a> {
     Comment(Span<'a>), // 3.1
+    Attribute(Attribute<'a>),
     AbsoluteMode,
     IncrementalMode,
-    DrillMode,         // 3.6
-    RouteMode,         // 3.7
-    SelectTool(usize), // 3.8
+    UnitMode(UnitMode), // M71 and M72, Excellon only.
+    DrillMode,          // 3.6
+    RouteMode,          // 3.7
+    SelectTool(usize),  // 3.8
     DrillHit {
         // 3.9
-        target: Vector2<f64>,
+        target: Coordinates<'a>,
+    },
+    Repeat {
+        // R, Excellon only. Repeats the last hole, moving it by the step each time.
+        count: usize,
+        step: Coordinates<'a>,
     },
-    Route(Vec<RouteCommand>), // 3.10,
+    Slot {
+        // G85, Excellon only.
+        start: Coordinates<'a>,
+        end: Coordinates<'a>,
+    },
+    CutterCompensation(CutterCompensation), // G40, G41 and G42, Excellon only.
+    Route(Vec<RouteCommandContext<'a>>),    // 3.10,
+    Circle {
+        // G32 and G33, Excellon only. Routes a full circle around the center.
+        clockwise: bool,
+        center: Coordinates<'a>,
+        radius: Span<'a>,
+    },
+    Unsupported(Span<'a>),
+}
+
+impl<'a> DrillCommand<'a> {
+    /// Describes what was left out when reading the command, if anything was.
+    pub fn ignored(&self) -> Option<String> {
+        match self {
+            DrillCommand::CutterCompensation(CutterCompensation::Left) => Some(String::from(
+                "Cutter compensation G41 is ignored. Routes are cut centered on their path.",
+            )),
+            DrillCommand::CutterCompensation(CutterCompensation::Right) => Some(String::from(
+                "Cutter compensation G42 is ignored. Routes are cut centered on their path.",
+            )),
+            DrillCommand::Unsupported(line) => Some(format!(
+                "Command `{}` is not supported and was ignored.",
+                line.fragment()
+            )),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CutterCompensation {
+    Off,
+    Left,
+    Right,
 }
 
 #[derive(Debug, Clone)]
-pub enum RouteCommand {
+pub struct RouteCommandContext<'a> {
+    pub span: Span<'a>,
+    pub command: RouteCommand<'a>,
+}
+
+impl<'a> RouteCommandContext<'a> {
+    pub fn location_info(&self) -> LocationInfo {
+        LocationInfo {
+            line: self.span.location_line(),
+            column: self.span.get_utf8_column(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum RouteCommand<'a> {
     LinearMove {
         // 3.12
-        target: Vector2<f64>,
+        target: Coordinates<'a>,
     },
     ClockwiseCurve {
         // 3.13
-        target: Vector2<f64>,
-        diameter: f64,
+        target: Coordinates<'a>,
+        center: ArcCenter<'a>,
     },
     CounterClockwiseCurve {
         // 3.14
-        target: Vector2<f64>,
-        diameter: f64,
+        target: Coordinates<'a>,
+        center: ArcCenter<'a>,
+    },
+
+    /// Coordinates without a G code continue in the mode of the last move.
+    Move {
+        target: Coordinates<'a>,
+        center: Option<ArcCenter<'a>>,
+    },
+}
+
+/// How the center of an arc is given.
+#[derive(Debug, Clone)]
+pub enum ArcCenter<'a> {
+    /// `A`, the radius of the arc.
+    Radius(Span<'a>),
+
+    /// `I` and `J`, the offset from the start of the arc to its center. Excellon only.
+    Offset {
+        i: Option<Span<'a>>,
+        j: Option<Span<'a>>,
     },
 }
 
@@ -102,20 +235,27 @@ pub fn parse_drill_file(
 }
 
 fn parse_header(input: Span) -> IResult<Span, Vec<HeaderCommandContext>> {
+    // The header ends with `%`, or `M95` in older Excellon files.
     delimited(
         terminated(tag("M48"), space),
         many0(terminated(parse_header_command, space)),
-        terminated(nom_char('%'), space),
+        terminated(alt((tag("%"), tag("M95"))), space),
     )(input)
 }
 
 fn parse_header_command(input: Span) -> IResult<Span, HeaderCommandContext> {
     map(
         alt((
+            map(parse_attribute, HeaderCommand::Attribute),
             map(parse_comment, HeaderCommand::Comment),
             parse_format_specification,
             parse_unit_mode,
+            parse_incremental_input,
             parse_tool_declaration,
+            map(
+                preceded(not(alt((tag("%"), tag("M95")))), parse_line),
+                HeaderCommand::Unsupported,
+            ),
         )),
         |command| HeaderCommandContext {
             span: input,
@@ -125,15 +265,54 @@ fn parse_header_command(input: Span) -> IResult<Span, HeaderCommandContext> {
 }
 
 fn parse_unit_mode(input: Span) -> IResult<Span, HeaderCommand> {
+    alt((
+        map(
+            tuple((
+                alt((
+                    value(UnitMode::Metric, tag("METRIC")),
+                    value(UnitMode::Imperial, tag("INCH")),
+                )),
+                opt(preceded(
+                    nom_char(','),
+                    alt((
+                        value(IncludedZeros::Leading, tag("LZ")),
+                        value(IncludedZeros::Trailing, tag("TZ")),
+                    )),
+                )),
+                opt(preceded(nom_char(','), parse_digits)),
+            )),
+            |(unit_mode, included_zeros, digits)| HeaderCommand::UnitMode {
+                unit_mode,
+                included_zeros,
+                digits,
+            },
+        ),
+        map(parse_unit_switch, |unit_mode| HeaderCommand::UnitMode {
+            unit_mode,
+            included_zeros: None,
+            digits: None,
+        }),
+    ))(input)
+}
+
+/// The number of integer and decimal digits is given as zeros on either side of a decimal point, such as `000.000`.
+fn parse_digits(input: Span) -> IResult<Span, (u32, u32)> {
     map(
-        alt((
-            value(UnitMode::Metric, tag("METRIC")),
-            value(UnitMode::Imperial, tag("INCH")),
-        )),
-        HeaderCommand::UnitMode,
+        pair(
+            take_while1(|c| c == '0'),
+            preceded(nom_char('.'), take_while1(|c| c == '0')),
+        ),
+        |(integer, decimal): (Span, Span)| (integer.len() as u32, decimal.len() as u32),
     )(input)
 }
 
+fn parse_unit_switch(input: Span) -> IResult<Span, UnitMode> {
+    alt((
+        value(UnitMode::Metric, tag("M71")),
+        value(UnitMode::Imperial, tag("M72")),
+    ))(input)
+}
+
 /// KiCad seems to produce specification compliant drill files, but they also include a
 /// format command at the start and it's not even in a comment, so I have to account for it.
 fn parse_format_specification(input: Span) -> IResult<Span, HeaderCommand> {
@@ -143,34 +322,77 @@ fn parse_format_specification(input: Span) -> IResult<Span, HeaderCommand> {
     )(input)
 }
 
-fn parse_tool_declaration(input: Span) -> IResult<Span, HeaderCommand> {
+fn parse_incremental_input(input: Span) -> IResult<Span, HeaderCommand> {
     map(
+        preceded(
+            tag("ICI"),
+            opt(preceded(
+                nom_char(','),
+                alt((value(true, tag("ON")), value(false, tag("OFF")))),
+            )),
+        ),
+        |enabled| HeaderCommand::IncrementalInput(enabled.unwrap_or(true)),
+    )(input)
+}
+
+fn parse_tool_declaration(input: Span) -> IResult<Span, HeaderCommand> {
+    // Other tools write feeds and speeds around the diameter, in any order.
+    map_opt(
         pair(
             preceded(nom_char('T'), parse_unsigned_integer),
-            preceded(nom_char('C'), parse_unsigned_decimal),
+            many0(pair(one_of("BCFHSZ"), parse_unsigned_decimal)),
         ),
-        |(index, diameter)| HeaderCommand::ToolDeclaration { index, diameter },
+        |(index, parameters)| {
+            let diameter = parameters
+                .iter()
+                .find(|(name, _value)| *name == 'C')
+                .map(|(_name, value)| *value)?;
+
+            Some(HeaderCommand::ToolDeclaration {
+                index,
+                diameter,
+                ignored_parameters: parameters
+                    .iter()
+                    .map(|(name, _value)| *name)
+                    .filter(|name| *name != 'C')
+                    .collect(),
+            })
+        },
     )(input)
 }
 
 fn parse_body(input: Span) -> IResult<Span, Vec<DrillCommandContext>> {
     terminated(
         many0(terminated(parse_drill_command, space)),
-        terminated(tag("M30"), space),
+        terminated(parse_end_of_program, space),
     )(input)
 }
 
+fn parse_end_of_program(input: Span) -> IResult<Span, Span> {
+    alt((tag("M30"), tag("M00")))(input)
+}
+
 fn parse_drill_command(input: Span) -> IResult<Span, DrillCommandContext> {
     map(
         alt((
+            map(parse_attribute, DrillCommand::Attribute),
             map(parse_comment, DrillCommand::Comment),
             parse_absolute_mode,
             parse_incremental_mode,
+            map(parse_unit_switch, DrillCommand::UnitMode),
             parse_dill_mode,
             parse_route_mode,
+            parse_cutter_compensation,
             parse_select_tool,
+            parse_circle,
+            parse_slot,
             parse_drill_hit,
+            parse_repeat,
             parse_route,
+            map(
+                preceded(not(parse_end_of_program), parse_line),
+                DrillCommand::Unsupported,
+            ),
         )),
         |command| DrillCommandContext {
             span: input,
@@ -186,7 +408,7 @@ fn parse_absolute_mode(input: Span) -> IResult<Span, DrillCommand> {
 
 fn parse_incremental_mode(input: Span) -> IResult<Span, DrillCommand> {
     // KiCad adds a custom "incremental mode". All coordinates are relative to the previous position.
-    value(DrillCommand::IncrementalMode, tag("G90"))(input)
+    value(DrillCommand::IncrementalMode, tag("G91"))(input)
 }
 
 fn parse_dill_mode(input: Span) -> IResult<Span, DrillCommand> {
@@ -197,6 +419,17 @@ fn parse_route_mode(input: Span) -> IResult<Span, DrillCommand> {
     value(DrillCommand::RouteMode, tag("G00"))(input)
 }
 
+fn parse_cutter_compensation(input: Span) -> IResult<Span, DrillCommand> {
+    map(
+        alt((
+            value(CutterCompensation::Off, tag("G40")),
+            value(CutterCompensation::Left, tag("G41")),
+            value(CutterCompensation::Right, tag("G42")),
+        )),
+        DrillCommand::CutterCompensation,
+    )(input)
+}
+
 fn parse_select_tool(input: Span) -> IResult<Span, DrillCommand> {
     map(
         preceded(nom_char('T'), parse_unsigned_integer),
@@ -204,105 +437,193 @@ fn parse_select_tool(input: Span) -> IResult<Span, DrillCommand> {
     )(input)
 }
 
+fn parse_coordinates(input: Span) -> IResult<Span, Coordinates> {
+    map(
+        verify(
+            pair(
+                opt(preceded(nom_char('X'), parse_number)),
+                opt(preceded(nom_char('Y'), parse_number)),
+            ),
+            |(x, y)| x.is_some() || y.is_some(),
+        ),
+        |(x, y)| Coordinates { x, y },
+    )(input)
+}
+
 fn parse_drill_hit(input: Span) -> IResult<Span, DrillCommand> {
+    map(parse_coordinates, |target| DrillCommand::DrillHit {
+        target,
+    })(input)
+}
+
+fn parse_repeat(input: Span) -> IResult<Span, DrillCommand> {
     map(
         pair(
-            preceded(nom_char('X'), parse_decimal),
-            preceded(nom_char('Y'), parse_decimal),
+            preceded(nom_char('R'), parse_unsigned_integer),
+            parse_coordinates,
         ),
-        |(x, y)| DrillCommand::DrillHit {
-            target: Vector2::new(x, y),
+        |(count, step)| DrillCommand::Repeat { count, step },
+    )(input)
+}
+
+fn parse_slot(input: Span) -> IResult<Span, DrillCommand> {
+    // The start of the slot can be left out to start it where the last command ended.
+    map(
+        pair(
+            opt(parse_coordinates),
+            preceded(tag("G85"), parse_coordinates),
+        ),
+        |(start, end)| DrillCommand::Slot {
+            start: start.unwrap_or_default(),
+            end,
         },
     )(input)
 }
 
 fn parse_route(input: Span) -> IResult<Span, DrillCommand> {
+    // M17 lifts the tool like M16, without retracting it all the way.
     map(
         delimited(
             terminated(tag("M15"), space),
-            many0(terminated(
-                alt((
-                    parse_linear_move,
-                    parse_clockwise_curve,
-                    parse_counter_clockwise_curve,
-                )),
-                space,
-            )),
-            terminated(tag("M16"), space),
+            many0(terminated(parse_route_command, space)),
+            terminated(alt((tag("M16"), tag("M17"))), space),
         ),
         DrillCommand::Route,
     )(input)
 }
 
-fn parse_linear_move(input: Span) -> IResult<Span, RouteCommand> {
+fn parse_route_command(input: Span) -> IResult<Span, RouteCommandContext> {
     map(
-        preceded(
-            tag("G01"),
-            pair(
-                preceded(nom_char('X'), parse_decimal),
-                preceded(nom_char('Y'), parse_decimal),
-            ),
-        ),
-        |(x, y)| RouteCommand::LinearMove {
-            target: Vector2::new(x, y),
+        alt((
+            parse_linear_move,
+            parse_clockwise_curve,
+            parse_counter_clockwise_curve,
+            parse_modal_move,
+        )),
+        |command| RouteCommandContext {
+            span: input,
+            command,
         },
     )(input)
 }
 
+fn parse_linear_move(input: Span) -> IResult<Span, RouteCommand> {
+    map(preceded(tag("G01"), parse_coordinates), |target| {
+        RouteCommand::LinearMove { target }
+    })(input)
+}
+
 fn parse_clockwise_curve(input: Span) -> IResult<Span, RouteCommand> {
     map(
-        preceded(
-            tag("G02"),
-            tuple((
-                preceded(nom_char('X'), parse_decimal),
-                preceded(nom_char('Y'), parse_decimal),
-                preceded(nom_char('A'), parse_decimal),
-            )),
-        ),
-        |(x, y, a)| RouteCommand::ClockwiseCurve {
-            target: Vector2::new(x, y),
-            diameter: a,
-        },
+        preceded(tag("G02"), pair(parse_coordinates, parse_arc_center)),
+        |(target, center)| RouteCommand::ClockwiseCurve { target, center },
     )(input)
 }
 
 fn parse_counter_clockwise_curve(input: Span) -> IResult<Span, RouteCommand> {
     map(
-        preceded(
-            tag("G02"),
-            tuple((
-                preceded(nom_char('X'), parse_decimal),
-                preceded(nom_char('Y'), parse_decimal),
-                preceded(nom_char('A'), parse_decimal),
-            )),
+        preceded(tag("G03"), pair(parse_coordinates, parse_arc_center)),
+        |(target, center)| RouteCommand::CounterClockwiseCurve { target, center },
+    )(input)
+}
+
+fn parse_modal_move(input: Span) -> IResult<Span, RouteCommand> {
+    map(
+        pair(parse_coordinates, opt(parse_arc_center)),
+        |(target, center)| RouteCommand::Move { target, center },
+    )(input)
+}
+
+fn parse_arc_center(input: Span) -> IResult<Span, ArcCenter> {
+    alt((
+        map(preceded(nom_char('A'), parse_number), ArcCenter::Radius),
+        map(
+            verify(
+                pair(
+                    opt(preceded(nom_char('I'), parse_number)),
+                    opt(preceded(nom_char('J'), parse_number)),
+                ),
+                |(i, j)| i.is_some() || j.is_some(),
+            ),
+            |(i, j)| ArcCenter::Offset { i, j },
         ),
-        |(x, y, a)| RouteCommand::CounterClockwiseCurve {
-            target: Vector2::new(x, y),
-            diameter: a,
+    ))(input)
+}
+
+fn parse_circle(input: Span) -> IResult<Span, DrillCommand> {
+    map(
+        tuple((
+            alt((value(true, tag("G32")), value(false, tag("G33")))),
+            parse_coordinates,
+            preceded(nom_char('A'), parse_number),
+        )),
+        |(clockwise, center, radius)| DrillCommand::Circle {
+            clockwise,
+            center,
+            radius,
         },
     )(input)
 }
 
+/// Attributes are written as comments so that they don't bother older readers, such as `; #@! TF.FileFunction,Plated,1,2,PTH`.
+fn parse_attribute(input: Span) -> IResult<Span, Attribute> {
+    fn parse_name_and_values(input: Span) -> IResult<Span, (Span, Vec<Span>)> {
+        pair(
+            take_while1(|c| !matches!(c, ',' | '\r' | '\n')),
+            many0(preceded(
+                nom_char(','),
+                take_while(|c| !matches!(c, ',' | '\r' | '\n')),
+            )),
+        )(input)
+    }
+
+    delimited(
+        tuple((nom_char(';'), space0, tag("#@!"), space0)),
+        alt((
+            map(
+                preceded(tag("TF."), parse_name_and_values),
+                |(name, values)| Attribute::File { name, values },
+            ),
+            map(
+                preceded(tag("TA."), parse_name_and_values),
+                |(name, values)| Attribute::Aperture { name, values },
+            ),
+            map(
+                preceded(tag("TO."), parse_name_and_values),
+                |(name, values)| Attribute::Object { name, values },
+            ),
+            map(
+                preceded(
+                    tag("TD"),
+                    opt(preceded(
+                        opt(nom_char('.')),
+                        take_while1(|c| !matches!(c, '\r' | '\n')),
+                    )),
+                ),
+                |name| Attribute::Delete { name },
+            ),
+        )),
+        space,
+    )(input)
+}
+
 fn parse_unsigned_integer(input: Span) -> IResult<Span, usize> {
     map_res(take_while1(|c: char| c.is_ascii_digit()), |digits: Span| {
         digits.fragment().parse::<usize>()
     })(input)
 }
 
-fn parse_decimal(input: Span) -> IResult<Span, f64> {
-    // decimal          = /[+-]?((([0-9]+)(\.[0-9]*)?)|(\.[0-9]+))/;
-
-    // Get the sign of the number..
-    let (input, sign) = map(
-        opt(alt((value(1.0, nom_char('+')), value(-1.0, nom_char('-'))))),
-        |sign| sign.unwrap_or(1.0),
-    )(input)?;
+/// A number as it was written. Whether it has a decimal point decides how it's read, so that's left to the file's format.
+fn parse_number(input: Span) -> IResult<Span, Span> {
+    recognize(pair(
+        opt(one_of("+-")),
+        take_while1(|c| matches!(c, '.' | '0'..='9')),
+    ))(input)
+}
 
-    // Now we can parse the digits.
-    map_res(
-        take_while(|c| matches!(c, '.' | '0'..='9')),
-        move |number: Span| number.fragment().parse::<f64>().map(|value| value * sign),
-    )(input)
+/// The rest of a line, for commands that aren't understood.
+fn parse_line(input: Span) -> IResult<Span, Span> {
+    take_while1(|c| !matches!(c, '\r' | '\n'))(input)
 }
 
 fn parse_comment(input: Span) -> IResult<Span, Span> {
@@ -324,3 +645,108 @@ fn is_space(c: char) -> bool {
 fn space(input: Span) -> IResult<Span, ()> {
     value((), take_while(is_space))(input)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fragment<'a>(span: &Option<Span<'a>>) -> Option<&'a str> {
+        span.as_ref().map(|span| *span.fragment())
+    }
+
+    #[test]
+    fn unit_mode_with_zeros_and_digits() {
+        let (_, command) = parse_unit_mode(Span::new("METRIC,TZ,000.000")).unwrap();
+        assert!(matches!(
+            command,
+            HeaderCommand::UnitMode {
+                unit_mode: UnitMode::Metric,
+                included_zeros: Some(IncludedZeros::Trailing),
+                digits: Some((3, 3)),
+            }
+        ));
+
+        let (_, command) = parse_unit_mode(Span::new("INCH,LZ")).unwrap();
+        assert!(matches!(
+            command,
+            HeaderCommand::UnitMode {
+                unit_mode: UnitMode::Imperial,
+                included_zeros: Some(IncludedZeros::Leading),
+                digits: None,
+            }
+        ));
+    }
+
+    #[test]
+    fn unit_switch() {
+        let (_, command) = parse_unit_mode(Span::new("M71")).unwrap();
+        assert!(matches!(
+            command,
+            HeaderCommand::UnitMode {
+                unit_mode: UnitMode::Metric,
+                included_zeros: None,
+                digits: None,
+            }
+        ));
+
+        let (_, command) = parse_drill_command(Span::new("M72")).unwrap();
+        assert!(matches!(
+            command.command,
+            DrillCommand::UnitMode(UnitMode::Imperial)
+        ));
+    }
+
+    #[test]
+    fn number_keeps_sign_and_decimal_point() {
+        let (remainder, number) = parse_number(Span::new("-00125Y")).unwrap();
+        assert_eq!(*number.fragment(), "-00125");
+        assert_eq!(*remainder.fragment(), "Y");
+
+        let (_, number) = parse_number(Span::new("+1.5")).unwrap();
+        assert_eq!(*number.fragment(), "+1.5");
+    }
+
+    #[test]
+    fn repeat() {
+        let (_, command) = parse_repeat(Span::new("R4X0100")).unwrap();
+        match command {
+            DrillCommand::Repeat { count, step } => {
+                assert_eq!(count, 4);
+                assert_eq!(fragment(&step.x), Some("0100"));
+                assert_eq!(fragment(&step.y), None);
+            }
+            command => panic!("Expected a repeat, got {:?}", command),
+        }
+    }
+
+    #[test]
+    fn header_ends() {
+        for source in [
+            "M48\nINCH,LZ\nT1C0.02\n%\nT1\nX01Y01\nM30\n",
+            "M48\nINCH,LZ\nT1C0.02\nM95\nT1\nX01Y01\nM30\n",
+        ] {
+            let (_, (header, body)) = parse_drill_file(Span::new(source)).unwrap();
+            assert_eq!(header.len(), 2);
+            assert_eq!(body.len(), 2);
+        }
+    }
+
+    #[test]
+    fn unknown_commands_are_kept_to_be_warned_about() {
+        let source = "M48\nMETRIC\nVER,1\nT1C0.8\n%\nT1\nG93X1Y1\nX1.0Y2.0\nM30\n";
+        let (_, (header, body)) = parse_drill_file(Span::new(source)).unwrap();
+
+        assert!(matches!(
+            header[1].command,
+            HeaderCommand::Unsupported(line) if *line.fragment() == "VER,1"
+        ));
+        assert!(header[1].command.ignored().is_some());
+
+        assert!(matches!(
+            body[1].command,
+            DrillCommand::Unsupported(line) if *line.fragment() == "G93X1Y1"
+        ));
+        assert!(body[1].command.ignored().is_some());
+        assert!(matches!(body[2].command, DrillCommand::DrillHit { .. }));
+    }
+}
@@ -102,6 +102,88 @@ pub enum GerberCommand<'a> {
     LoadScaling(f64),             // LS 4.9.5
 
     ApertureBlock(u32, Vec<GerberCommandContext<'a>>), // AB 4.11
+
+    // Commands from older revisions of the specification, still found in files from older CAD tools.
+    Deprecated(DeprecatedCommand<'a>), // 8.1
+}
+
+/// Section 8.1
+#[derive(Debug, Clone)]
+pub enum DeprecatedCommand<'a> {
+    SelectAperture(u32), // G54Dnn
+    UnitMode(UnitMode),  // G70 and G71
+    SingleQuadrantMode,  // G74
+    ProgramStop,         // M00
+    OptionalStop,        // M01
+    ImagePolarity {
+        // IP
+        negative: bool,
+    },
+    MirrorImage {
+        // MI
+        a: bool,
+        b: bool,
+    },
+    Offset(Vector2<f64>),      // OF
+    ScaleFactor(Vector2<f64>), // SF
+    ImageName(Span<'a>),       // IN
+    LoadName(Span<'a>),        // LN
+}
+
+impl<'a> DeprecatedCommand<'a> {
+    /// The command as it is written in a file.
+    pub fn code(&self) -> &'static str {
+        match self {
+            DeprecatedCommand::SelectAperture(_) => "G54",
+            DeprecatedCommand::UnitMode(UnitMode::Imperial) => "G70",
+            DeprecatedCommand::UnitMode(UnitMode::Metric) => "G71",
+            DeprecatedCommand::SingleQuadrantMode => "G74",
+            DeprecatedCommand::ProgramStop => "M00",
+            DeprecatedCommand::OptionalStop => "M01",
+            DeprecatedCommand::ImagePolarity { .. } => "IP",
+            DeprecatedCommand::MirrorImage { .. } => "MI",
+            DeprecatedCommand::Offset(_) => "OF",
+            DeprecatedCommand::ScaleFactor(_) => "SF",
+            DeprecatedCommand::ImageName(_) => "IN",
+            DeprecatedCommand::LoadName(_) => "LN",
+        }
+    }
+
+    /// What the current specification does instead.
+    pub fn replacement(&self) -> &'static str {
+        match self {
+            DeprecatedCommand::SelectAperture(_) => "Apertures are selected with Dnn alone.",
+            DeprecatedCommand::UnitMode(_) => "The unit is set with MO.",
+            DeprecatedCommand::SingleQuadrantMode => "Arcs are drawn in G75 multi quadrant mode.",
+            DeprecatedCommand::ProgramStop => "Files end with M02.",
+            DeprecatedCommand::OptionalStop => "It has no effect.",
+            DeprecatedCommand::ImagePolarity { .. } => "Clear objects are drawn with LP.",
+            DeprecatedCommand::MirrorImage { .. } => "Apertures are mirrored with LM.",
+            DeprecatedCommand::Offset(_) => "Coordinates are written where they belong.",
+            DeprecatedCommand::ScaleFactor(_) => "Apertures are scaled with LS.",
+            DeprecatedCommand::ImageName(_) => {
+                "Files are described by the .FileFunction attribute."
+            }
+            DeprecatedCommand::LoadName(_) => "Names are given in G04 comments.",
+        }
+    }
+}
+
+impl<'a> GerberCommandContext<'a> {
+    /// Visits the command and every command nested within it.
+    pub fn for_each_command(&self, visit: &mut impl FnMut(&GerberCommandContext<'a>)) {
+        visit(self);
+
+        match &self.command {
+            GerberCommand::StepAndRepeat { commands, .. }
+            | GerberCommand::ApertureBlock(_, commands) => {
+                for command in commands {
+                    command.for_each_command(visit);
+                }
+            }
+            _ => {}
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -360,12 +442,28 @@ pub enum MirroringMode {
 }
 
 pub fn parse_gerber_file(input: Span) -> IResult<Span, Vec<GerberCommandContext>> {
-    terminated(many0(delimited(space, parse_command, space)), end_of_file)(input)
+    map(
+        pair(many0(delimited(space, parse_command, space)), end_of_file),
+        |(mut commands, end)| {
+            commands.extend(end);
+            commands
+        },
+    )(input)
 }
 
-pub fn end_of_file(input: Span) -> IResult<Span, Span> {
-    // M02 4.13
-    tag("M02*")(input)
+/// Older files may end with M00 instead, which is kept so that it can be warned about.
+pub fn end_of_file(input: Span) -> IResult<Span, Option<GerberCommandContext>> {
+    alt((
+        // M02 4.13
+        value(None, tag("M02*")),
+        // M00 8.1
+        map(tag("M00*"), |_| {
+            Some(GerberCommandContext {
+                command: GerberCommand::Deprecated(DeprecatedCommand::ProgramStop),
+                span: input,
+            })
+        }),
+    ))(input)
 }
 
 fn parse_command(input: Span) -> IResult<Span, GerberCommandContext> {
@@ -388,6 +486,7 @@ fn parse_normal_command(input: Span) -> IResult<Span, GerberCommand> {
         parse_multi_quadrant_mode,
         parse_region,
         parse_step_and_repeat,
+        parse_deprecated_command,
     ))(input)
 }
 
@@ -526,6 +625,23 @@ fn parse_step_and_repeat(input: Span) -> IResult<Span, GerberCommand> {
     )(input)
 }
 
+// Section 8.1
+fn parse_deprecated_command(input: Span) -> IResult<Span, GerberCommand> {
+    map(
+        alt((
+            map(
+                delimited(tag("G54D"), cut(parse_unsigned_integer), cut(nom_char('*'))),
+                DeprecatedCommand::SelectAperture,
+            ),
+            value(DeprecatedCommand::UnitMode(UnitMode::Imperial), tag("G70*")),
+            value(DeprecatedCommand::UnitMode(UnitMode::Metric), tag("G71*")),
+            value(DeprecatedCommand::SingleQuadrantMode, tag("G74*")),
+            value(DeprecatedCommand::OptionalStop, tag("M01*")),
+        )),
+        GerberCommand::Deprecated,
+    )(input)
+}
+
 // Extended commands.
 
 fn parse_extended_command(input: Span) -> IResult<Span, GerberCommand> {
@@ -541,6 +657,7 @@ fn parse_extended_command(input: Span) -> IResult<Span, GerberCommand> {
             parse_load_rotation,
             parse_load_scaling,
             parse_aperture_block,
+            parse_deprecated_extended_command,
             parse_delete_attribute,
             parse_attribute,
         ))),
@@ -591,7 +708,15 @@ fn parse_attribute(input: Span) -> IResult<Span, GerberCommand> {
 
 fn parse_delete_attribute(input: Span) -> IResult<Span, GerberCommand> {
     map(
-        delimited(tag("TD"), opt(parse_field), cut(nom_char('*'))),
+        // Names are stored without the leading dot of standard attributes, the same as when they are set.
+        delimited(
+            tag("TD"),
+            opt(preceded(
+                opt(nom_char('.')),
+                take_while1(|c| !matches!(c, '*' | '%' | ',')),
+            )),
+            cut(nom_char('*')),
+        ),
         |name| GerberCommand::Attribute(Attribute::Delete { name }),
     )(input)
 }
@@ -1021,6 +1146,92 @@ fn parse_aperture_macro(input: Span) -> IResult<Span, GerberCommand> {
     )(input)
 }
 
+// Section 8.1
+fn parse_deprecated_extended_command(input: Span) -> IResult<Span, GerberCommand> {
+    /// Values for the A and B axes, either of which can be left out.
+    fn parse_axes(default: f64, input: Span) -> IResult<Span, Vector2<f64>> {
+        let (input, (a, b)) = pair(
+            opt(preceded(nom_char('A'), parse_decimal)),
+            opt(preceded(nom_char('B'), parse_decimal)),
+        )(input)?;
+
+        Ok((
+            input,
+            Vector2::new(a.unwrap_or(default), b.unwrap_or(default)),
+        ))
+    }
+
+    let parse_image_polarity = delimited(
+        tag("IP"),
+        cut(alt((
+            value(
+                DeprecatedCommand::ImagePolarity { negative: false },
+                tag("POS"),
+            ),
+            value(
+                DeprecatedCommand::ImagePolarity { negative: true },
+                tag("NEG"),
+            ),
+        ))),
+        cut(nom_char('*')),
+    );
+
+    let parse_mirror_image = map(
+        delimited(
+            tag("MI"),
+            cut(pair(
+                opt(preceded(nom_char('A'), one_of("01"))),
+                opt(preceded(nom_char('B'), one_of("01"))),
+            )),
+            cut(nom_char('*')),
+        ),
+        |(a, b)| DeprecatedCommand::MirrorImage {
+            a: a == Some('1'),
+            b: b == Some('1'),
+        },
+    );
+
+    let parse_offset = map(
+        delimited(
+            tag("OF"),
+            cut(|input| parse_axes(0.0, input)),
+            cut(nom_char('*')),
+        ),
+        DeprecatedCommand::Offset,
+    );
+
+    let parse_scale_factor = map(
+        delimited(
+            tag("SF"),
+            cut(|input| parse_axes(1.0, input)),
+            cut(nom_char('*')),
+        ),
+        DeprecatedCommand::ScaleFactor,
+    );
+
+    let parse_image_name = map(
+        delimited(tag("IN"), parse_string, cut(nom_char('*'))),
+        DeprecatedCommand::ImageName,
+    );
+
+    let parse_load_name = map(
+        delimited(tag("LN"), parse_string, cut(nom_char('*'))),
+        DeprecatedCommand::LoadName,
+    );
+
+    map(
+        alt((
+            parse_image_polarity,
+            parse_mirror_image,
+            parse_offset,
+            parse_scale_factor,
+            parse_image_name,
+            parse_load_name,
+        )),
+        GerberCommand::Deprecated,
+    )(input)
+}
+
 fn parse_load_polarity(input: Span) -> IResult<Span, GerberCommand> {
     map(
         terminated(
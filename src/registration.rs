@@ -0,0 +1,43 @@
+//! Boards are flipped over to work on their back side, which loses track of where they are. Registration pins are
+//! placed on the axis the board is flipped over, so that the flipped board lands back on the same pins.
+
+use anyhow::{bail, Result};
+use nalgebra::Vector2;
+use uom::si::length::millimeter;
+
+use crate::{drill_file::DrillFile, forge_file::Registration, panel::BoardBounds};
+
+/// The X position of the axis the board is flipped over, in millimeters.
+pub fn pin_axis(registration: &Registration, bounds: &BoardBounds) -> Result<f64> {
+    match registration.axis {
+        Some(axis) => Ok(axis.get::<millimeter>()),
+        None if bounds.is_empty() => {
+            bail!("The board doesn't have any gerber files, so its registration pins can't be placed.")
+        }
+        None => Ok((bounds.min.x + bounds.max.x) / 2.0),
+    }
+}
+
+/// The holes of the pins, one below the board and one above it.
+pub fn pin_holes(registration: &Registration, bounds: &BoardBounds) -> Result<DrillFile> {
+    if bounds.is_empty() {
+        bail!("The board doesn't have any gerber files, so its registration pins can't be placed.");
+    }
+
+    let axis = pin_axis(registration, bounds)?;
+    let diameter = registration.diameter.get::<millimeter>();
+    if diameter <= 0.0 {
+        bail!("Registration pins must have a diameter larger than zero.");
+    }
+
+    // Pins are kept clear of the board, measured from the edges of their holes.
+    let distance = registration.clearance.get::<millimeter>() + diameter / 2.0;
+
+    Ok(DrillFile::with_holes(
+        [
+            Vector2::new(axis, bounds.min.y - distance),
+            Vector2::new(axis, bounds.max.y + distance),
+        ],
+        diameter,
+    ))
+}
@@ -0,0 +1,522 @@
+//! Renders the toolpaths of a GCode file so that jobs can be reviewed without loading them into an external viewer.
+//! Cuts are drawn with the diameter of the tool, with each pass on its own layer. Rapid moves are drawn as thin lines
+//! and plunges as dots. Holding tabs are drawn over the cuts in their own color.
+
+use std::fmt::Write;
+
+use anyhow::{Context, Result};
+use nalgebra::Vector2;
+use uom::si::length::{millimeter, Length};
+
+use crate::{
+    gcode_generation::{BoardSide, GCodeFile, GCommand, MovementType, Tool},
+    geometry::arc_sweep,
+};
+
+/// Colors for the cuts of each pass. Passes past the end of the list reuse its colors.
+const PASS_COLORS: [(u8, u8, u8); 6] = [
+    (220, 40, 40),
+    (230, 140, 20),
+    (200, 40, 160),
+    (140, 70, 20),
+    (40, 160, 160),
+    (120, 60, 200),
+];
+const RAPID_COLOR: (u8, u8, u8) = (40, 90, 220);
+const PLUNGE_COLOR: (u8, u8, u8) = (30, 160, 60);
+const TAB_COLOR: (u8, u8, u8) = (20, 20, 20);
+
+/// Cuts are drawn partially transparent so that overlapping passes can be seen.
+const CUT_OPACITY: f32 = 0.6;
+
+/// Arcs are drawn as straight lines, each covering at most this many radians.
+const ARC_STEP: f64 = std::f64::consts::PI / 36.0;
+
+/// The largest a PNG preview can be along either axis, in pixels.
+const MAX_PNG_SIZE: f64 = 4096.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum StrokeKind {
+    Cut { pass_index: usize },
+    Tab,
+    Rapid,
+}
+
+/// A continuous movement of the tool. Positions are in millimeters.
+#[derive(Debug)]
+struct Stroke {
+    kind: StrokeKind,
+    width: f64,
+    points: Vec<Vector2<f64>>,
+}
+
+/// A place where the tool went straight down into the board.
+#[derive(Debug)]
+struct Plunge {
+    position: Vector2<f64>,
+    diameter: f64,
+}
+
+/// The movements of a GCode file as the machine will make them, after the back side of the board has been mirrored.
+#[derive(Debug, Default)]
+pub struct ToolpathPreview {
+    strokes: Vec<Stroke>,
+    plunges: Vec<Plunge>,
+}
+
+impl ToolpathPreview {
+    pub fn new(gcode_file: &GCodeFile, x_offset: Length<uom::si::SI<f64>, f64>) -> Self {
+        let mut preview = Self::default();
+
+        let x_offset = x_offset.get::<millimeter>();
+        let mut board_side = BoardSide::Front;
+        let mut tool = Tool::None;
+        let mut tool_is_ready_to_cut = false;
+        let mut over_holding_tab = false;
+        let mut position = Vector2::new(0.0, 0.0);
+
+        let to_vector = |x: &Length<uom::si::SI<f64>, f64>, y: &Length<uom::si::SI<f64>, f64>| {
+            Vector2::new(x.get::<millimeter>(), y.get::<millimeter>())
+        };
+
+        for command in gcode_file.commands() {
+            // Points are mirrored as they are added so that arcs are traced in the board's own coordinates.
+            let to_machine = |point: Vector2<f64>| match board_side {
+                BoardSide::Front => point,
+                BoardSide::Back => Vector2::new(x_offset - point.x, point.y),
+            };
+
+            match command {
+                GCommand::EquipTool(new_tool) => {
+                    tool = *new_tool;
+                    tool_is_ready_to_cut = false;
+                }
+                GCommand::SetPower(_) | GCommand::SetSpindleSpeed(_) => {
+                    tool_is_ready_to_cut = false;
+                }
+                GCommand::ToolChange {
+                    tool_number: _,
+                    diameter: new_diameter,
+                    message: _,
+                    rezero: _,
+                } => {
+                    if let Tool::Spindle {
+                        max_spindle_speed: _,
+                        diameter,
+                        plunge_speed: _,
+                        travel_height: _,
+                        cut_depth: _,
+                        pass_depth: _,
+                    } = &mut tool
+                    {
+                        *diameter = *new_diameter;
+                    }
+                    tool_is_ready_to_cut = false;
+                }
+                GCommand::MoveTo { target: (x, y) } => {
+                    let target = to_vector(x, y);
+
+                    if target != position {
+                        preview.add_points(
+                            StrokeKind::Rapid,
+                            0.0,
+                            [to_machine(position), to_machine(target)],
+                        );
+                        position = target;
+                    }
+
+                    tool_is_ready_to_cut = false;
+                }
+                GCommand::Cut {
+                    pass_index,
+                    movement,
+                    target: (x, y),
+                } => {
+                    let target = to_vector(x, y);
+                    let diameter = tool_diameter(&tool);
+
+                    if !tool_is_ready_to_cut {
+                        if let Tool::Spindle { .. } = tool {
+                            preview.plunges.push(Plunge {
+                                position: to_machine(position),
+                                diameter,
+                            });
+                        }
+                        tool_is_ready_to_cut = true;
+                    }
+
+                    let points = match movement {
+                        MovementType::Linear => vec![position, target],
+                        MovementType::ClockwiseCurve {
+                            center: (center_x, center_y),
+                        } => trace_arc(position, to_vector(center_x, center_y), target, true),
+                        MovementType::CounterClockwiseCurve {
+                            center: (center_x, center_y),
+                        } => trace_arc(position, to_vector(center_x, center_y), target, false),
+                    };
+
+                    let kind = if over_holding_tab {
+                        StrokeKind::Tab
+                    } else {
+                        StrokeKind::Cut {
+                            pass_index: *pass_index,
+                        }
+                    };
+
+                    preview.add_points(kind, diameter, points.into_iter().map(to_machine));
+                    position = target;
+                }
+                GCommand::Drill {
+                    target: (x, y),
+                    peck_depth: _,
+                    retract_height: _,
+                } => {
+                    let target = to_vector(x, y);
+
+                    if target != position {
+                        preview.add_points(
+                            StrokeKind::Rapid,
+                            0.0,
+                            [to_machine(position), to_machine(target)],
+                        );
+                        position = target;
+                    }
+
+                    preview.plunges.push(Plunge {
+                        position: to_machine(target),
+                        diameter: tool_diameter(&tool),
+                    });
+                    tool_is_ready_to_cut = false;
+                }
+                GCommand::Probe {
+                    target: (x, y),
+                    search_depth: _,
+                    speed: _,
+                } => {
+                    let target = to_vector(x, y);
+
+                    if target != position {
+                        preview.add_points(
+                            StrokeKind::Rapid,
+                            0.0,
+                            [to_machine(position), to_machine(target)],
+                        );
+                        position = target;
+                    }
+
+                    preview.plunges.push(Plunge {
+                        position: to_machine(target),
+                        diameter: tool_diameter(&tool),
+                    });
+                    tool_is_ready_to_cut = false;
+                }
+                GCommand::SetHoldingTab(holding_tab) => over_holding_tab = holding_tab.is_some(),
+                GCommand::SetSide(new_side) => board_side = *new_side,
+                _ => {}
+            }
+        }
+
+        preview
+    }
+
+    /// Adds points to the last stroke if it's of the same kind and ends where the points start, otherwise starts a
+    /// new stroke.
+    fn add_points(
+        &mut self,
+        kind: StrokeKind,
+        width: f64,
+        points: impl IntoIterator<Item = Vector2<f64>>,
+    ) {
+        let mut points = points.into_iter().peekable();
+
+        match (self.strokes.last_mut(), points.peek()) {
+            (Some(stroke), Some(first_point))
+                if stroke.kind == kind
+                    && stroke.width == width
+                    && stroke.points.last() == Some(first_point) =>
+            {
+                stroke.points.extend(points.skip(1));
+            }
+            _ => self.strokes.push(Stroke {
+                kind,
+                width,
+                points: points.collect(),
+            }),
+        }
+    }
+
+    /// The area covered by the preview as (min x, min y, max x, max y).
+    fn bounds(&self) -> (f64, f64, f64, f64) {
+        let mut bounds = (f64::INFINITY, f64::INFINITY, -f64::INFINITY, -f64::INFINITY);
+
+        let mut include = |point: Vector2<f64>, radius: f64| {
+            bounds.0 = bounds.0.min(point.x - radius);
+            bounds.1 = bounds.1.min(point.y - radius);
+            bounds.2 = bounds.2.max(point.x + radius);
+            bounds.3 = bounds.3.max(point.y + radius);
+        };
+
+        for stroke in self.strokes.iter() {
+            for point in stroke.points.iter() {
+                include(*point, stroke.width / 2.0);
+            }
+        }
+
+        for plunge in self.plunges.iter() {
+            include(plunge.position, plunge.diameter / 2.0);
+        }
+
+        if bounds.0 <= bounds.2 {
+            bounds
+        } else {
+            (0.0, 0.0, 1.0, 1.0)
+        }
+    }
+
+    /// Rapid moves are not drawn to scale, so they are given a width relative to the size of the preview.
+    fn rapid_width(&self) -> f64 {
+        let (min_x, min_y, max_x, max_y) = self.bounds();
+        (max_x - min_x).max(max_y - min_y) / 1000.0
+    }
+
+    /// The order strokes are drawn in. Later passes are drawn over earlier ones, and rapids over all of them.
+    fn layers(&self) -> Vec<(StrokeKind, Vec<&Stroke>)> {
+        let max_pass = self
+            .strokes
+            .iter()
+            .filter_map(|stroke| match stroke.kind {
+                StrokeKind::Cut { pass_index } => Some(pass_index),
+                StrokeKind::Tab | StrokeKind::Rapid => None,
+            })
+            .max();
+
+        max_pass
+            .map_or(0..0, |max_pass| 0..max_pass + 1)
+            .map(|pass_index| StrokeKind::Cut { pass_index })
+            .chain([StrokeKind::Tab, StrokeKind::Rapid])
+            .map(|kind| {
+                (
+                    kind,
+                    self.strokes
+                        .iter()
+                        .filter(|stroke| stroke.kind == kind)
+                        .collect(),
+                )
+            })
+            .collect()
+    }
+
+    pub fn render_svg(&self) -> Result<String> {
+        let (min_x, min_y, max_x, max_y) = self.bounds();
+        let rapid_width = self.rapid_width();
+
+        let mut svg = String::new();
+
+        // The Y axis is flipped so that the preview is seen from above the machine.
+        writeln!(
+            svg,
+            r#"<svg xmlns="http://www.w3.org/2000/svg" viewBox="{} {} {} {}">"#,
+            min_x,
+            -max_y,
+            max_x - min_x,
+            max_y - min_y
+        )?;
+        writeln!(svg, r#"<g transform="scale(1 -1)">"#)?;
+
+        for (kind, strokes) in self.layers() {
+            let (name, (red, green, blue), opacity) = match kind {
+                StrokeKind::Cut { pass_index } => (
+                    format!("pass{}", pass_index),
+                    pass_color(pass_index),
+                    CUT_OPACITY,
+                ),
+                StrokeKind::Tab => (String::from("tabs"), TAB_COLOR, 1.0),
+                StrokeKind::Rapid => (String::from("rapids"), RAPID_COLOR, 1.0),
+            };
+
+            writeln!(
+                svg,
+                r#"<g id="{}" fill="none" stroke="rgb({},{},{})" stroke-opacity="{}" stroke-linecap="round" stroke-linejoin="round">"#,
+                name, red, green, blue, opacity
+            )?;
+
+            for stroke in strokes {
+                let width = match stroke.kind {
+                    StrokeKind::Cut { pass_index: _ } | StrokeKind::Tab => stroke.width,
+                    StrokeKind::Rapid => rapid_width,
+                };
+
+                let points = stroke
+                    .points
+                    .iter()
+                    .map(|point| format!("{},{}", point.x, point.y))
+                    .collect::<Vec<_>>()
+                    .join(" ");
+
+                writeln!(
+                    svg,
+                    r#"<polyline stroke-width="{}" points="{}"/>"#,
+                    width, points
+                )?;
+            }
+
+            writeln!(svg, "</g>")?;
+        }
+
+        let (red, green, blue) = PLUNGE_COLOR;
+        writeln!(
+            svg,
+            r#"<g id="plunges" fill="rgb({},{},{})">"#,
+            red, green, blue
+        )?;
+        for plunge in self.plunges.iter() {
+            writeln!(
+                svg,
+                r#"<circle cx="{}" cy="{}" r="{}"/>"#,
+                plunge.position.x,
+                plunge.position.y,
+                (plunge.diameter / 2.0).max(rapid_width)
+            )?;
+        }
+        writeln!(svg, "</g>")?;
+
+        writeln!(svg, "</g>")?;
+        writeln!(svg, "</svg>")?;
+
+        Ok(svg)
+    }
+
+    pub fn render_png(&self, pixels_per_millimeter: f64) -> Result<Vec<u8>> {
+        let (min_x, min_y, max_x, max_y) = self.bounds();
+        let rapid_width = self.rapid_width();
+
+        let scale = pixels_per_millimeter.min(MAX_PNG_SIZE / (max_x - min_x).max(max_y - min_y));
+        let width = ((max_x - min_x) * scale).ceil().max(1.0) as u32;
+        let height = ((max_y - min_y) * scale).ceil().max(1.0) as u32;
+
+        let mut pixmap =
+            tiny_skia::Pixmap::new(width, height).context("Failed to allocate preview image.")?;
+        pixmap.fill(tiny_skia::Color::WHITE);
+
+        // The Y axis is flipped so that the preview is seen from above the machine.
+        let transform = tiny_skia::Transform::from_row(
+            scale as f32,
+            0.0,
+            0.0,
+            -scale as f32,
+            (-min_x * scale) as f32,
+            (max_y * scale) as f32,
+        );
+
+        for (kind, strokes) in self.layers() {
+            let mut paint = tiny_skia::Paint::default();
+            let (red, green, blue) = match kind {
+                StrokeKind::Cut { pass_index } => pass_color(pass_index),
+                StrokeKind::Tab => TAB_COLOR,
+                StrokeKind::Rapid => RAPID_COLOR,
+            };
+            let opacity = match kind {
+                StrokeKind::Cut { pass_index: _ } => CUT_OPACITY,
+                StrokeKind::Tab | StrokeKind::Rapid => 1.0,
+            };
+            paint.set_color_rgba8(red, green, blue, (opacity * 255.0) as u8);
+
+            for stroke in strokes {
+                let mut path = tiny_skia::PathBuilder::new();
+                let mut points = stroke.points.iter();
+
+                if let Some(first_point) = points.next() {
+                    path.move_to(first_point.x as f32, first_point.y as f32);
+                }
+                for point in points {
+                    path.line_to(point.x as f32, point.y as f32);
+                }
+
+                if let Some(path) = path.finish() {
+                    let width = match stroke.kind {
+                        StrokeKind::Cut { pass_index: _ } | StrokeKind::Tab => stroke.width,
+                        StrokeKind::Rapid => rapid_width,
+                    };
+
+                    let stroke_style = tiny_skia::Stroke {
+                        width: width as f32,
+                        line_cap: tiny_skia::LineCap::Round,
+                        line_join: tiny_skia::LineJoin::Round,
+                        ..Default::default()
+                    };
+
+                    pixmap.stroke_path(&path, &paint, &stroke_style, transform, None);
+                }
+            }
+        }
+
+        let mut paint = tiny_skia::Paint::default();
+        let (red, green, blue) = PLUNGE_COLOR;
+        paint.set_color_rgba8(red, green, blue, 255);
+
+        for plunge in self.plunges.iter() {
+            if let Some(circle) = tiny_skia::PathBuilder::from_circle(
+                plunge.position.x as f32,
+                plunge.position.y as f32,
+                (plunge.diameter / 2.0).max(rapid_width) as f32,
+            ) {
+                pixmap.fill_path(
+                    &circle,
+                    &paint,
+                    tiny_skia::FillRule::Winding,
+                    transform,
+                    None,
+                );
+            }
+        }
+
+        pixmap
+            .encode_png()
+            .context("Failed to encode preview image.")
+    }
+}
+
+fn tool_diameter(tool: &Tool) -> f64 {
+    match tool {
+        Tool::None => 0.0,
+        Tool::Laser {
+            max_power: _,
+            diameter,
+        } => diameter.get::<millimeter>(),
+        Tool::Spindle {
+            max_spindle_speed: _,
+            diameter,
+            plunge_speed: _,
+            travel_height: _,
+            cut_depth: _,
+            pass_depth: _,
+        } => diameter.get::<millimeter>(),
+    }
+}
+
+fn pass_color(pass_index: usize) -> (u8, u8, u8) {
+    PASS_COLORS[pass_index % PASS_COLORS.len()]
+}
+
+/// Breaks an arc into points along it, starting and ending with the ends of the arc.
+fn trace_arc(
+    start: Vector2<f64>,
+    center: Vector2<f64>,
+    end: Vector2<f64>,
+    clockwise: bool,
+) -> Vec<Vector2<f64>> {
+    let radius = (start - center).norm();
+    let start_angle = (start.y - center.y).atan2(start.x - center.x);
+    let sweep = arc_sweep(start, center, end, clockwise);
+
+    let steps = (sweep.abs() / ARC_STEP).ceil().max(1.0) as usize;
+
+    let mut points = vec![start];
+    points.extend((1..steps).map(|step| {
+        let angle = start_angle + sweep * step as f64 / steps as f64;
+        center + Vector2::new(angle.cos(), angle.sin()) * radius
+    }));
+    points.push(end);
+
+    points
+}